@@ -0,0 +1,42 @@
+//! Which AWS account the function reports on, so reports from several accounts can be told
+//! apart.
+
+use aws_sdk_iam::error::DisplayErrorContext;
+use aws_sdk_iam::Client as IamClient;
+use aws_sdk_sts::Client as StsClient;
+use tracing::{info, warn};
+
+/// The account id and alias, where they could be looked up.
+#[derive(Clone, Debug, Default)]
+pub struct AccountIdentity {
+    pub id: Option<String>,
+    pub alias: Option<String>,
+}
+
+/// Looks up the account with `sts:GetCallerIdentity` and its alias with
+/// `iam:ListAccountAliases`. Either failing, e.g. for lack of the IAM permission, only leaves
+/// that part unknown, since the report doesn't need it.
+pub async fn resolve(sts: &StsClient, iam: &IamClient) -> AccountIdentity {
+    let id = match sts.get_caller_identity().send().await {
+        Ok(output) => output.account().map(str::to_string),
+        Err(err) => {
+            warn!(
+                "looking up the account id failed: {}",
+                DisplayErrorContext(&err)
+            );
+            None
+        }
+    };
+    let alias = match iam.list_account_aliases().send().await {
+        Ok(output) => output.account_aliases().first().cloned(),
+        Err(err) => {
+            warn!(
+                "looking up the account alias failed, showing the id only: {}",
+                DisplayErrorContext(&err)
+            );
+            None
+        }
+    };
+    info!(account_id = ?id, account_alias = ?alias, "resolved the account");
+    AccountIdentity { id, alias }
+}
@@ -0,0 +1,1275 @@
+//! The report model and the computations on it that don't need AWS.
+//!
+//! Money is a `Decimal` so sums and threshold comparisons are exact; the `f64`s CloudWatch
+//! returns are converted where they come in.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Datelike, Duration, Months, NaiveDate, Utc};
+use chrono_tz::Tz;
+use rust_decimal::Decimal;
+
+use crate::config::{BillingStatistic, DigestPeriod, Language, MinChange, ServiceRollup};
+use crate::notify;
+
+pub struct Billing {
+    pub generated_at: DateTime<Utc>,
+    /// when the billing data behind `total` was recorded
+    pub as_of: Option<DateTime<Utc>>,
+    pub total: Decimal,
+    pub currency: String,
+    pub services: Vec<ServiceBilling>,
+    pub warnings: Vec<String>,
+    /// the parts of the report left out or built from less data than asked for, by
+    /// [`Degraded::note`], shown in the footer
+    pub degraded: Vec<String>,
+    pub digest: Option<Digest>,
+    /// by service name, with `SHOW_CHANGES=true`
+    pub changes: BTreeMap<String, CostChange>,
+    /// how each service moved in the ranking since the previous report, with
+    /// `SHOW_RANK_CHANGES=true`
+    pub rank_changes: BTreeMap<String, RankChange>,
+    /// the services that grew the most, with `SHOW_CHANGES=true`
+    pub increases: Vec<Increase>,
+    /// cost by the end of the month by service name, with `SHOW_FORECAST=true`
+    pub forecasts: BTreeMap<String, Decimal>,
+    /// with `MONTHLY_BUDGET` set
+    pub budget: Option<Budget>,
+    /// the Lambda request that produced the report, with `SHOW_REQUEST_ID=true`
+    pub request_id: Option<String>,
+    /// the days `total` covers
+    pub period: Period,
+    /// the account billed, where known
+    pub account_id: Option<String>,
+    /// its alias, or `ACCOUNT_NAME`
+    pub account_alias: Option<String>,
+    /// `TIMEZONE`, which times in the report are shown in
+    pub timezone: Tz,
+    /// `LANGUAGE`, which the report is written in
+    pub language: Language,
+    /// by linked account, with `GROUP_BY=account,service`; `services` then sums each service
+    /// over them
+    pub accounts: Vec<AccountBilling>,
+    /// what was spent each of the last days, oldest first, with `SHOW_SPARKLINE=true`
+    pub daily_spend: Vec<Decimal>,
+    /// the chart of the daily spend, with `ATTACH_CHART=true`
+    pub chart_url: Option<String>,
+    /// the Slack message text from `MESSAGE_TEMPLATE_PARAMETER`, replacing the built-in one
+    pub message_text: Option<String>,
+    /// the Slack attachment text from `ATTACHMENT_TEMPLATE_PARAMETER`, replacing the breakdown
+    pub attachment_text: Option<String>,
+    /// services in the total but left out of the breakdown by `HIDE_FROM_BREAKDOWN`
+    pub hidden: Vec<String>,
+    /// the total in `CONVERT_TO_CURRENCY`, where a rate was at hand
+    pub conversion: Option<Conversion>,
+    /// the statistics of the total listed in `BILLING_STATISTIC`, when more than the maximum
+    pub statistics: Vec<(BillingStatistic, Decimal)>,
+}
+
+/// The total in another currency.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Conversion {
+    pub currency: String,
+    pub total: Decimal,
+}
+
+/// Currencies without minor units, whose amounts are whole.
+const WHOLE_CURRENCIES: &[&str] = &["JPY", "KRW", "VND", "CLP", "ISK", "HUF", "TWD"];
+
+/// `amount` in `currency` at `rate` units of it per unit of the billing currency, rounded to
+/// the currency's minor unit: to the yen for JPY, to the cent for most.
+pub fn convert(amount: Decimal, rate: Decimal, currency: &str) -> Conversion {
+    Conversion {
+        currency: currency.to_string(),
+        total: (amount * rate).round_dp(minor_units(currency)),
+    }
+}
+
+/// The costs of one linked account of an organization.
+pub struct AccountBilling {
+    pub id: String,
+    /// most expensive first
+    pub services: Vec<ServiceBilling>,
+}
+
+impl AccountBilling {
+    pub fn total(&self) -> Decimal {
+        self.services.iter().map(|service| service.cost).sum()
+    }
+}
+
+/// Groups the `(account id, service)` costs by account, the most expensive account and service
+/// first, and sums each service over the accounts for the flat breakdown.
+pub fn group_by_account(
+    costs: Vec<(String, ServiceBilling)>,
+) -> (Vec<AccountBilling>, Vec<ServiceBilling>) {
+    let mut by_service: BTreeMap<String, Decimal> = BTreeMap::new();
+    let mut accounts: Vec<AccountBilling> = vec![];
+    for (account_id, service) in costs {
+        *by_service.entry(service.name.clone()).or_default() += service.cost;
+        match accounts.iter_mut().find(|account| account.id == account_id) {
+            Some(account) => account.services.push(service),
+            None => accounts.push(AccountBilling {
+                id: account_id,
+                services: vec![service],
+            }),
+        }
+    }
+    for account in &mut accounts {
+        account.services.sort_by(by_cost_descending);
+    }
+    accounts.sort_by(|a, b| b.total().cmp(&a.total()).then_with(|| a.id.cmp(&b.id)));
+    let services = by_service
+        .into_iter()
+        .map(|(name, cost)| ServiceBilling {
+            name,
+            cost,
+            usage: None,
+        })
+        .collect();
+    (accounts, services)
+}
+
+/// The month-to-date a report covers. Dates are in UTC, as AWS bills by the UTC month.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Period {
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+}
+
+impl Period {
+    /// From the first of the month of `as_of` to its day.
+    pub fn month_to_date(as_of: DateTime<Utc>) -> Self {
+        let end = as_of.date_naive();
+        Period {
+            start: end.with_day(1).unwrap_or(end),
+            end,
+        }
+    }
+}
+
+/// The monthly budget and how far into the month the report is, to tell whether spend is
+/// ahead of pace.
+pub struct Budget {
+    pub amount: Decimal,
+    /// share of the month elapsed, in percent
+    pub month_elapsed: Decimal,
+}
+
+impl Billing {
+    /// Orders the services by cost, most expensive first and ties by name, so every channel
+    /// shows the expensive ones first and in the same order from run to run.
+    pub fn sort_services(&mut self) {
+        self.services.sort_by(by_cost_descending);
+    }
+
+    /// Names of the services in the order they're shown, for [`rank_changes`].
+    pub fn ranking(&self) -> Vec<&str> {
+        self.services
+            .iter()
+            .map(|service| service.name.as_str())
+            .collect()
+    }
+
+    /// "2024-06-01 → 2024-06-14 UTC | 2024-06-14 18:00 JST 時点 | アカウント mycompany-prod
+    /// (123456789012)" (or "... | as of 2024-06-14 18:00 JST | account mycompany-prod
+    /// (123456789012)"), the time the data is as of and the account only where known.
+    ///
+    /// Services `HIDE_FROM_BREAKDOWN` left out of the breakdown are noted at the end, like
+    /// "(Tax含む)".
+    pub fn context_line(&self) -> String {
+        let mut parts = vec![format!(
+            "{} → {} UTC",
+            self.period.start.format("%Y-%m-%d"),
+            self.period.end.format("%Y-%m-%d")
+        )];
+        if let Some(as_of) = self.as_of {
+            let as_of = as_of
+                .with_timezone(&self.timezone)
+                .format("%Y-%m-%d %H:%M %Z");
+            parts.push(match self.language {
+                Language::Ja => format!("{} 時点", as_of),
+                Language::En => format!("as of {}", as_of),
+            });
+        }
+        let account = match (&self.account_alias, &self.account_id) {
+            (Some(alias), Some(id)) => Some(format!("{} ({})", alias, id)),
+            (Some(name), None) | (None, Some(name)) => Some(name.clone()),
+            (None, None) => None,
+        };
+        if let Some(account) = account {
+            parts.push(format!(
+                "{} {}",
+                self.language.pick("アカウント", "account"),
+                account
+            ));
+        }
+        if !self.hidden.is_empty() {
+            let hidden = self.hidden.join(", ");
+            parts.push(match self.language {
+                Language::Ja => format!("({}含む)", hidden),
+                Language::En => format!("(includes {})", hidden),
+            });
+        }
+        parts.join(" | ")
+    }
+
+    /// Sum of the credits and refunds in the breakdown, i.e. the services with a negative
+    /// cost, or `None` when there are none.
+    pub fn credits(&self) -> Option<Decimal> {
+        let credits: Vec<Decimal> = self
+            .services
+            .iter()
+            .map(|service| service.cost)
+            .filter(|cost| *cost < Decimal::ZERO)
+            .collect();
+        (!credits.is_empty()).then(|| credits.into_iter().sum())
+    }
+}
+
+/// Sum of the positive service costs, i.e. what was charged before credits. Shares are taken
+/// of this, so a credit can't push them past 100%.
+pub fn charges(services: &[ServiceBilling]) -> Decimal {
+    services
+        .iter()
+        .map(|service| service.cost)
+        .filter(|cost| *cost > Decimal::ZERO)
+        .sum()
+}
+
+/// How the cost of a service moved since the previous day.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CostChange {
+    /// the service cost nothing the day before
+    New,
+    /// by this much, up or down
+    Changed(Decimal),
+}
+
+/// How a service moved in the ranking by cost since the previous report.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RankChange {
+    /// the service wasn't ranked the last time
+    New,
+    /// by this many places, up when positive
+    Moved(i64),
+}
+
+/// The rank of each of `current` compared to `previous`, both most expensive first. Nothing on
+/// the first run, when there's no previous ranking.
+pub fn rank_changes(current: &[&str], previous: Option<&[String]>) -> BTreeMap<String, RankChange> {
+    let previous = match previous {
+        Some(previous) => previous,
+        None => return BTreeMap::new(),
+    };
+    current
+        .iter()
+        .enumerate()
+        .map(|(rank, name)| {
+            let change = match previous.iter().position(|before| before == name) {
+                Some(before) => RankChange::Moved(before as i64 - rank as i64),
+                None => RankChange::New,
+            };
+            (name.to_string(), change)
+        })
+        .collect()
+}
+
+/// A service whose cost grew since the previous day.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Increase {
+    pub name: String,
+    /// by how much it grew
+    pub delta: Decimal,
+    /// what it costs now
+    pub cost: Decimal,
+}
+
+/// Most services listed as the top increases.
+pub const TOP_INCREASES: usize = 3;
+
+/// The [`TOP_INCREASES`] of `services` whose cost grew the most per `changes`, largest first and
+/// by name on ties. A new service grew by its whole cost; services that shrank are left out.
+/// Nothing when even the largest increase isn't over `floor`, or without previous costs.
+pub fn top_increases(
+    services: &[ServiceBilling],
+    changes: &BTreeMap<String, CostChange>,
+    floor: Decimal,
+) -> Vec<Increase> {
+    let mut increases: Vec<Increase> = services
+        .iter()
+        .filter_map(|service| {
+            let delta = match changes.get(&service.name)? {
+                CostChange::New => service.cost,
+                CostChange::Changed(delta) => *delta,
+            };
+            (delta > Decimal::ZERO).then(|| Increase {
+                name: service.name.clone(),
+                delta,
+                cost: service.cost,
+            })
+        })
+        .collect();
+    increases.sort_by(|a, b| b.delta.cmp(&a.delta).then_with(|| a.name.cmp(&b.name)));
+    increases.truncate(TOP_INCREASES);
+    match increases.first() {
+        Some(largest) if largest.delta > floor => increases,
+        _ => vec![],
+    }
+}
+
+/// How alarming the total is against `BILLING_WARNING_THRESHOLD` and `BILLING_ALERT_THRESHOLD`,
+/// for anything that reacts to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Normal,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    /// `None` when neither threshold is set, so there is nothing to measure against.
+    pub fn of(total: Decimal, warning: Option<Decimal>, critical: Option<Decimal>) -> Option<Self> {
+        if warning.is_none() && critical.is_none() {
+            return None;
+        }
+        let severity = if critical.is_some_and(|critical| total > critical) {
+            Severity::Critical
+        } else if warning.is_some_and(|warning| total > warning) {
+            Severity::Warning
+        } else {
+            Severity::Normal
+        };
+        Some(severity)
+    }
+}
+
+/// The decimals amounts in `currency` are written with: 0 for JPY, 2 for most.
+pub fn minor_units(currency: &str) -> u32 {
+    if WHOLE_CURRENCIES.contains(&currency) {
+        0
+    } else {
+        2
+    }
+}
+
+/// What was spent over a `DIGEST_PERIOD` window.
+pub struct Digest {
+    pub period: DigestPeriod,
+    pub spend: Decimal,
+}
+
+pub struct ServiceBilling {
+    pub name: String,
+    pub cost: Decimal,
+    /// only with `SHOW_USAGE=true`, and only when the usage is in a single unit
+    pub usage: Option<Usage>,
+}
+
+pub struct Usage {
+    pub quantity: f64,
+    /// as Cost Explorer reports it, e.g. "GB-Mo" or "Hrs"
+    pub unit: String,
+}
+
+/// Keeps the `n` most expensive services.
+pub fn keep_most_expensive(services: &mut Vec<ServiceBilling>, n: usize) {
+    services.sort_by(by_cost_descending);
+    services.truncate(n);
+}
+
+/// Folds the services costing less than `min_share` percent of the [`charges`] into one
+/// "その他 (N サービス)" ("Other (N services)") entry at the end, keeping the order of the
+/// rest. Credits are never folded, a lone small service is kept as it is, and nothing is
+/// folded before anything is charged.
+pub fn fold_long_tail(
+    services: Vec<ServiceBilling>,
+    min_share: Decimal,
+    language: Language,
+) -> Vec<ServiceBilling> {
+    let total = charges(&services);
+    if total <= Decimal::ZERO {
+        return services;
+    }
+    let (kept, small): (Vec<_>, Vec<_>) = services.into_iter().partition(|service| {
+        service.cost < Decimal::ZERO || service.cost * Decimal::ONE_HUNDRED >= min_share * total
+    });
+    if small.len() < 2 {
+        return kept.into_iter().chain(small).collect();
+    }
+    let other = ServiceBilling {
+        name: match language {
+            Language::Ja => format!("その他 ({} サービス)", small.len()),
+            Language::En => format!("Other ({} services)", small.len()),
+        },
+        cost: small.iter().map(|service| service.cost).sum(),
+        usage: None,
+    };
+    kept.into_iter().chain(std::iter::once(other)).collect()
+}
+
+/// Costs are `Decimal`, so unlike `f64` they are totally ordered and need no NaN handling.
+fn by_cost_descending(a: &ServiceBilling, b: &ServiceBilling) -> Ordering {
+    b.cost.cmp(&a.cost).then_with(|| a.name.cmp(&b.name))
+}
+
+/// Sums the services matching a rollup into one service named after its label, placed where
+/// the first of them was. Unmatched services pass through, so the total stays the same. Usage
+/// is kept while every rolled up service has it in the same unit.
+pub fn roll_up(services: Vec<ServiceBilling>, rollups: &[ServiceRollup]) -> Vec<ServiceBilling> {
+    if rollups.is_empty() {
+        return services;
+    }
+    let mut rolled_up: Vec<ServiceBilling> = Vec::with_capacity(services.len());
+    for service in services {
+        let label = match rollups
+            .iter()
+            .find(|rollup| rollup.pattern.is_match(&service.name))
+        {
+            Some(rollup) => &rollup.label,
+            None => {
+                rolled_up.push(service);
+                continue;
+            }
+        };
+        match rolled_up.iter_mut().find(|bucket| &bucket.name == label) {
+            Some(bucket) => {
+                bucket.cost += service.cost;
+                bucket.usage = match (bucket.usage.take(), service.usage) {
+                    (Some(a), Some(b)) if a.unit == b.unit => Some(Usage {
+                        quantity: a.quantity + b.quantity,
+                        unit: a.unit,
+                    }),
+                    _ => None,
+                };
+            }
+            None => rolled_up.push(ServiceBilling {
+                name: label.clone(),
+                ..service
+            }),
+        }
+    }
+    rolled_up
+}
+
+/// Share of the month elapsed at `now` in `tz`, in percent.
+pub fn month_elapsed_percent(now: DateTime<Utc>, tz: Tz) -> Decimal {
+    let now = now.with_timezone(&tz).naive_local();
+    let first = NaiveDate::from_ymd_opt(now.year(), now.month(), 1).unwrap_or(now.date());
+    let next = first.checked_add_months(Months::new(1)).unwrap_or(first);
+    let start = first.and_hms_opt(0, 0, 0).unwrap_or(now);
+    let month = (next - first).num_seconds();
+    if month <= 0 {
+        return Decimal::ZERO;
+    }
+    Decimal::from((now - start).num_seconds()) * Decimal::ONE_HUNDRED / Decimal::from(month)
+}
+
+/// Changes smaller than this aren't shown.
+const CHANGE_EPSILON: Decimal = Decimal::from_parts(1, 0, 0, false, 2);
+
+/// Compares each of `current` with the service of the same name in `previous`. Services new
+/// since then are `New`, and unchanged ones, like those no longer billed, are left out.
+pub fn cost_changes(
+    current: &[ServiceBilling],
+    previous: &[ServiceBilling],
+) -> BTreeMap<String, CostChange> {
+    current
+        .iter()
+        .filter_map(|service| {
+            let before = previous
+                .iter()
+                .find(|before| before.name == service.name)
+                .map(|before| before.cost)
+                .filter(|cost| !cost.is_zero());
+            let change = match before {
+                None if service.cost.is_zero() => return None,
+                None => CostChange::New,
+                Some(before) if (service.cost - before).abs() < CHANGE_EPSILON => return None,
+                Some(before) => CostChange::Changed(service.cost - before),
+            };
+            Some((service.name.clone(), change))
+        })
+        .collect()
+}
+
+/// Returns a warning when `currency` has no `EstimatedCharges` metrics, in which case every
+/// figure in the report would silently be zero.
+pub fn currency_mismatch_warning(
+    currency: &str,
+    available: &[String],
+    language: Language,
+) -> Option<String> {
+    if available.iter().any(|c| c == currency) {
+        return None;
+    }
+    let available = if available.is_empty() {
+        language.pick("なし", "none").to_string()
+    } else {
+        available.join(", ")
+    };
+    Some(match language {
+        Language::Ja => format!(
+            "⚠ 通貨 {} の請求メトリクスがありません (利用可能: {})",
+            currency, available
+        ),
+        Language::En => format!(
+            "⚠ No billing metrics in {} (available currencies: {})",
+            currency, available
+        ),
+    })
+}
+
+/// A part of the report that had to do with less, which the report still went out without.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Degraded {
+    /// listing the services failed part way
+    ServiceList,
+    /// the forecasts of this many services failed
+    Forecasts(usize),
+    Sparkline,
+    RankChanges,
+    Chart,
+    Conversion,
+}
+
+impl Degraded {
+    /// "予測なし (2 サービス)", or "no forecast for 2 services" in English.
+    pub fn note(self, language: Language) -> String {
+        match (self, language) {
+            (Degraded::ServiceList, Language::Ja) => "サービス一覧が不完全".to_string(),
+            (Degraded::ServiceList, Language::En) => "incomplete service list".to_string(),
+            (Degraded::Forecasts(count), Language::Ja) => {
+                format!("予測なし ({} サービス)", count)
+            }
+            (Degraded::Forecasts(1), Language::En) => "no forecast for 1 service".to_string(),
+            (Degraded::Forecasts(count), Language::En) => {
+                format!("no forecast for {} services", count)
+            }
+            (Degraded::Sparkline, _) => language.pick("推移なし", "no sparkline").to_string(),
+            (Degraded::RankChanges, _) => language
+                .pick("順位の変動なし", "no rank changes")
+                .to_string(),
+            (Degraded::Chart, _) => language.pick("グラフなし", "no chart").to_string(),
+            (Degraded::Conversion, _) => language
+                .pick("換算額なし", "no converted total")
+                .to_string(),
+        }
+    }
+}
+
+/// Warns that some services may be missing from the breakdown, as listing them failed part way.
+pub fn incomplete_services_warning(language: Language) -> String {
+    language
+        .pick(
+            "⚠ サービス一覧の取得が途中で失敗したため、内訳に漏れがある可能性があります",
+            "⚠ Listing the services failed part way, so some may be missing from the breakdown",
+        )
+        .to_string()
+}
+
+/// Returns a warning when the latest datapoint is more than `threshold` older than `now`.
+pub fn staleness_warning(
+    as_of: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+    threshold: Duration,
+    language: Language,
+) -> Option<String> {
+    let age = now - as_of?;
+    if age <= threshold {
+        return None;
+    }
+    Some(match language {
+        Language::Ja => format!("⚠ データが古い可能性 ({}時間前)", age.num_hours()),
+        Language::En => format!("⚠ The data may be stale ({} hours old)", age.num_hours()),
+    })
+}
+
+/// Returns a warning when `services` add up to more than `tolerance` away from `total`, e.g.
+/// when per-service datapoints lag behind the total or some lack the currency dimension. The
+/// difference is in `currency`, rounded to the cent: "⚠ 内訳未取得: $1.5".
+pub fn discrepancy_warning(
+    total: Decimal,
+    services: &[ServiceBilling],
+    tolerance: MinChange,
+    currency: &str,
+    language: Language,
+) -> Option<String> {
+    let unattributed = total - services.iter().map(|service| service.cost).sum::<Decimal>();
+    let difference = unattributed.abs();
+    let within = match tolerance {
+        MinChange::Absolute(max) => difference <= max,
+        MinChange::Percent(_) if total.is_zero() => difference.is_zero(),
+        MinChange::Percent(percent) => difference * Decimal::ONE_HUNDRED <= percent * total.abs(),
+    };
+    if within {
+        return None;
+    }
+    Some(format!(
+        "⚠ {}: {}",
+        language.pick("内訳未取得", "Not in the breakdown"),
+        notify::format_money(unattributed.round_dp(2), currency, language)
+    ))
+}
+
+/// The day-over-day increases of the cumulative month-to-date totals in `daily_totals` (oldest
+/// first), i.e. what was spent each day but the first. A decrease means the billing month reset
+/// in between, so that day's total is what was spent since the reset.
+pub fn daily_deltas(daily_totals: &[Decimal]) -> Vec<Decimal> {
+    daily_totals
+        .windows(2)
+        .map(|pair| {
+            let (previous, current) = (pair[0], pair[1]);
+            if current >= previous {
+                current - previous
+            } else {
+                current
+            }
+        })
+        .collect()
+}
+
+/// Sums the [`daily_deltas`] of `daily_totals`.
+pub fn sum_daily_deltas(daily_totals: &[Decimal]) -> Decimal {
+    daily_deltas(daily_totals).into_iter().sum()
+}
+
+/// A compact identity of what `billing` reports: the total in cents and a hash of the
+/// breakdown, e.g. `123456:9f86d081884c7d65`. Reports with the same figures get the same one.
+pub fn fingerprint(billing: &Billing) -> String {
+    // FNV-1a, which unlike the std hasher stays the same across Rust releases
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for service in &billing.services {
+        let line = format!(
+            "{}={}\n",
+            service.name,
+            service.cost.round_dp(2).normalize()
+        );
+        for byte in line.bytes() {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    let cents = (billing.total * Decimal::ONE_HUNDRED).round();
+    format!("{}:{:016x}", cents, hash)
+}
+
+/// Whether the report with `fingerprint` would repeat the one sent last, `previous` with the
+/// time it was sent, and should be skipped. It isn't once `max_age` has passed since then.
+pub fn is_unchanged(
+    previous: Option<(&str, DateTime<Utc>)>,
+    fingerprint: &str,
+    now: DateTime<Utc>,
+    max_age: Option<Duration>,
+) -> bool {
+    match previous {
+        Some((previous, sent_at)) => {
+            previous == fingerprint && max_age.is_none_or(|max_age| now - sent_at < max_age)
+        }
+        None => false,
+    }
+}
+
+/// Whether `current` moved far enough from the `previous` total to be reported. Without a
+/// previous total (the first run) it always is.
+pub fn has_changed(previous: Option<Decimal>, current: Decimal, min_change: MinChange) -> bool {
+    let previous = match previous {
+        Some(previous) => previous,
+        None => return true,
+    };
+    let change = (current - previous).abs();
+    match min_change {
+        MinChange::Absolute(min) => change >= min,
+        // any change from nothing is a change
+        MinChange::Percent(_) if previous.is_zero() => !change.is_zero(),
+        MinChange::Percent(percent) => change * Decimal::ONE_HUNDRED >= percent * previous.abs(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::testing;
+
+    fn service(name: &str, cost: &str) -> ServiceBilling {
+        ServiceBilling {
+            name: name.to_string(),
+            cost: cost.parse().unwrap(),
+            usage: None,
+        }
+    }
+
+    fn names(services: &[ServiceBilling]) -> Vec<&str> {
+        services
+            .iter()
+            .map(|service| service.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn keeps_the_most_expensive() {
+        let mut services = vec![
+            service("AWSLambda", "1"),
+            service("AmazonS3", "5"),
+            service("AmazonEC2", "10"),
+            service("AmazonSNS", "5"),
+        ];
+        keep_most_expensive(&mut services, 3);
+        assert_eq!(names(&services), ["AmazonEC2", "AmazonS3", "AmazonSNS"]);
+        keep_most_expensive(&mut services, 10);
+        assert_eq!(services.len(), 3);
+    }
+
+    #[test]
+    fn currency_with_metrics_is_not_warned_about() {
+        let available = ["JPY".to_string(), "USD".to_string()];
+        assert_eq!(
+            currency_mismatch_warning("USD", &available, Language::Ja),
+            None
+        );
+    }
+
+    #[test]
+    fn currency_without_metrics_lists_the_available_ones() {
+        let available = ["JPY".to_string(), "USD".to_string()];
+        assert_eq!(
+            currency_mismatch_warning("EUR", &available, Language::Ja).unwrap(),
+            "⚠ 通貨 EUR の請求メトリクスがありません (利用可能: JPY, USD)"
+        );
+        assert_eq!(
+            currency_mismatch_warning("EUR", &available, Language::En).unwrap(),
+            "⚠ No billing metrics in EUR (available currencies: JPY, USD)"
+        );
+    }
+
+    #[test]
+    fn no_metrics_at_all() {
+        assert_eq!(
+            currency_mismatch_warning("USD", &[], Language::Ja).unwrap(),
+            "⚠ 通貨 USD の請求メトリクスがありません (利用可能: なし)"
+        );
+        assert_eq!(
+            currency_mismatch_warning("USD", &[], Language::En).unwrap(),
+            "⚠ No billing metrics in USD (available currencies: none)"
+        );
+    }
+
+    #[test]
+    fn staleness_warning_past_the_threshold() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 14, 9, 0, 0).unwrap();
+        let threshold = Duration::hours(24);
+        assert_eq!(
+            staleness_warning(
+                Some(now - Duration::hours(30)),
+                now,
+                threshold,
+                Language::Ja
+            ),
+            Some("⚠ データが古い可能性 (30時間前)".to_string())
+        );
+        assert_eq!(
+            staleness_warning(
+                Some(now - Duration::hours(30)),
+                now,
+                threshold,
+                Language::En
+            ),
+            Some("⚠ The data may be stale (30 hours old)".to_string())
+        );
+        assert_eq!(
+            staleness_warning(Some(now - threshold), now, threshold, Language::En),
+            None
+        );
+        assert_eq!(staleness_warning(None, now, threshold, Language::En), None);
+    }
+
+    fn decimals(values: &[i64]) -> Vec<Decimal> {
+        values.iter().map(|&value| Decimal::from(value)).collect()
+    }
+
+    #[test]
+    fn daily_deltas_are_summed_across_a_month_reset() {
+        assert_eq!(sum_daily_deltas(&[]), Decimal::ZERO);
+        assert_eq!(sum_daily_deltas(&decimals(&[10])), Decimal::ZERO);
+        // +2, +3, reset to 2, +3
+        assert_eq!(
+            sum_daily_deltas(&decimals(&[10, 12, 15, 2, 5])),
+            Decimal::from(10)
+        );
+    }
+
+    #[test]
+    fn cent_amounts_sum_exactly() {
+        // (0.2 - 0.1) + (0.3 - 0.2) is 0.19999999999999998 in f64
+        assert_ne!((0.2 - 0.1) + (0.3 - 0.2), 0.2);
+        let totals = [Decimal::new(1, 1), Decimal::new(2, 1), Decimal::new(3, 1)];
+        assert_eq!(sum_daily_deltas(&totals), Decimal::new(2, 1));
+        assert_eq!(sum_daily_deltas(&totals).to_string(), "0.2");
+    }
+
+    #[test]
+    fn has_changed_by_amount_or_percent() {
+        let total = Decimal::from(100);
+        let cent = MinChange::Absolute(Decimal::new(1, 2));
+        assert!(has_changed(None, total, cent));
+        assert!(!has_changed(Some(total), total, cent));
+        assert!(has_changed(Some(total), Decimal::new(10001, 2), cent));
+        assert!(has_changed(Some(total), Decimal::new(9999, 2), cent));
+
+        let five_percent = MinChange::Percent(Decimal::from(5));
+        assert!(!has_changed(
+            Some(total),
+            Decimal::new(1049, 1),
+            five_percent
+        ));
+        assert!(has_changed(Some(total), Decimal::from(105), five_percent));
+        assert!(has_changed(Some(total), Decimal::from(95), five_percent));
+        assert!(!has_changed(
+            Some(Decimal::ZERO),
+            Decimal::ZERO,
+            five_percent
+        ));
+        assert!(has_changed(
+            Some(Decimal::ZERO),
+            Decimal::new(1, 2),
+            five_percent
+        ));
+    }
+
+    fn with_usage(name: &str, cost: &str, quantity: f64, unit: &str) -> ServiceBilling {
+        ServiceBilling {
+            usage: Some(Usage {
+                quantity,
+                unit: unit.to_string(),
+            }),
+            ..service(name, cost)
+        }
+    }
+
+    #[test]
+    fn rollup_sums_matching_services_where_the_first_was() {
+        let rollups = ServiceRollup::parse_map(Some(r#"{"EC2": "EC2"}"#)).unwrap();
+        let services = vec![
+            service("AmazonS3", "3"),
+            with_usage("AmazonEC2", "10", 5.0, "Hrs"),
+            service("AWSLambda", "1"),
+            with_usage("EC2-Instances", "2.5", 2.0, "Hrs"),
+        ];
+        let rolled_up = roll_up(services, &rollups);
+        assert_eq!(names(&rolled_up), ["AmazonS3", "EC2", "AWSLambda"]);
+        assert_eq!(rolled_up[1].cost, "12.5".parse::<Decimal>().unwrap());
+        let usage = rolled_up[1].usage.as_ref().unwrap();
+        assert_eq!((usage.quantity, usage.unit.as_str()), (7.0, "Hrs"));
+    }
+
+    #[test]
+    fn rollup_drops_usage_in_different_units() {
+        let rollups = ServiceRollup::parse_map(Some(r#"{"^Amazon": "AWS"}"#)).unwrap();
+        let services = vec![
+            with_usage("AmazonEC2", "10", 5.0, "Hrs"),
+            with_usage("AmazonS3", "3", 100.0, "GB-Mo"),
+        ];
+        let rolled_up = roll_up(services, &rollups);
+        assert_eq!(names(&rolled_up), ["AWS"]);
+        assert!(rolled_up[0].usage.is_none());
+    }
+
+    #[test]
+    fn services_sort_by_cost_then_name() {
+        let mut services = vec![
+            service("AmazonSNS", "5"),
+            service("AWSLambda", "1"),
+            service("AmazonEC2", "10"),
+            service("AmazonS3", "5"),
+        ];
+        services.sort_by(by_cost_descending);
+        assert_eq!(
+            names(&services),
+            ["AmazonEC2", "AmazonS3", "AmazonSNS", "AWSLambda"]
+        );
+    }
+
+    #[test]
+    fn changes_since_the_previous_day() {
+        let current = [
+            service("AmazonEC2", "12.5"),
+            service("AmazonS3", "3.004"),
+            service("AWSLambda", "1"),
+            service("AmazonSNS", "0"),
+            service("AmazonSQS", "2"),
+        ];
+        let previous = [
+            service("AmazonEC2", "10"),
+            service("AmazonS3", "3"),
+            service("AWSLambda", "0"),
+            service("AmazonSQS", "2.5"),
+        ];
+        let changes = cost_changes(&current, &previous);
+        let expected: BTreeMap<String, CostChange> = [
+            ("AmazonEC2", CostChange::Changed("2.5".parse().unwrap())),
+            ("AWSLambda", CostChange::New),
+            ("AmazonSQS", CostChange::Changed("-0.5".parse().unwrap())),
+        ]
+        .iter()
+        .map(|(name, change)| (name.to_string(), *change))
+        .collect();
+        assert_eq!(changes, expected);
+    }
+
+    #[test]
+    fn severity_of_the_total() {
+        let severity = |total: i64| {
+            Severity::of(
+                Decimal::from(total),
+                Some(Decimal::from(100)),
+                Some(Decimal::from(200)),
+            )
+        };
+        assert_eq!(severity(100), Some(Severity::Normal));
+        assert_eq!(severity(101), Some(Severity::Warning));
+        assert_eq!(severity(201), Some(Severity::Critical));
+        let critical_only = Severity::of(Decimal::from(150), None, Some(Decimal::from(100)));
+        assert_eq!(critical_only, Some(Severity::Critical));
+        assert_eq!(Severity::of(Decimal::from(150), None, None), None);
+    }
+
+    #[test]
+    fn month_elapsed_in_the_timezone() {
+        // midnight of the 16th of a 30-day month
+        let now = Utc.with_ymd_and_hms(2024, 6, 15, 15, 0, 0).unwrap();
+        assert_eq!(
+            month_elapsed_percent(now, Tz::Asia__Tokyo),
+            Decimal::from(50)
+        );
+        // still the last day of May in New York
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 3, 0, 0).unwrap();
+        assert!(month_elapsed_percent(now, Tz::America__New_York) > Decimal::from(99));
+        assert_eq!(
+            month_elapsed_percent(now, Tz::UTC).round_dp(1),
+            "0.4".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn breakdown_short_of_the_total_is_warned_about() {
+        let services = [service("AmazonEC2", "10"), service("AmazonS3", "5")];
+        let warning = |total: &str, tolerance: MinChange| {
+            discrepancy_warning(
+                total.parse().unwrap(),
+                &services,
+                tolerance,
+                "USD",
+                Language::Ja,
+            )
+        };
+        let one_dollar = MinChange::Absolute(Decimal::ONE);
+        assert_eq!(warning("16", one_dollar), None);
+        assert_eq!(
+            warning("17.5", one_dollar).as_deref(),
+            Some("⚠ 内訳未取得: $2.5")
+        );
+        assert_eq!(
+            warning("13", one_dollar).as_deref(),
+            Some("⚠ 内訳未取得: -$2")
+        );
+        assert_eq!(
+            warning("17.126", one_dollar).as_deref(),
+            Some("⚠ 内訳未取得: $2.13")
+        );
+        let ten_percent = MinChange::Percent(Decimal::TEN);
+        assert_eq!(warning("16.5", ten_percent), None);
+        assert!(warning("17", ten_percent).is_some());
+        // nothing is within a percentage of nothing
+        assert!(warning("0", ten_percent).is_some());
+        assert_eq!(
+            discrepancy_warning(
+                "17.5".parse().unwrap(),
+                &services,
+                one_dollar,
+                "EUR",
+                Language::En
+            )
+            .as_deref(),
+            Some("⚠ Not in the breakdown: €2.50")
+        );
+    }
+
+    #[test]
+    fn long_tail_is_folded_last() {
+        let services = vec![
+            service("AmazonEC2", "95"),
+            service("AmazonS3", "2"),
+            service("AWSLambda", "0.5"),
+            service("AmazonSNS", "0.4"),
+            service("Credits", "-3"),
+        ];
+        let folded = fold_long_tail(services, Decimal::ONE, Language::Ja);
+        assert_eq!(
+            names(&folded),
+            ["AmazonEC2", "AmazonS3", "Credits", "その他 (2 サービス)"]
+        );
+        assert_eq!(folded[3].cost, "0.9".parse::<Decimal>().unwrap());
+
+        // a lone small service isn't worth an entry of its own
+        let services = vec![service("AmazonEC2", "99.5"), service("AmazonS3", "0.5")];
+        let folded = fold_long_tail(services, Decimal::ONE, Language::En);
+        assert_eq!(names(&folded), ["AmazonEC2", "AmazonS3"]);
+        // nor is anything folded before anything is charged
+        let services = vec![service("AmazonEC2", "0"), service("AmazonS3", "0")];
+        assert_eq!(
+            fold_long_tail(services, Decimal::ONE, Language::En).len(),
+            2
+        );
+    }
+
+    #[test]
+    fn credits_are_kept_out_of_the_charges() {
+        let services = [
+            service("AmazonEC2", "10"),
+            service("Credits", "-3"),
+            service("Refund", "-1.5"),
+        ];
+        assert_eq!(charges(&services), Decimal::TEN);
+        assert_eq!(charges(&[]), Decimal::ZERO);
+    }
+
+    #[test]
+    fn context_line_has_the_period_data_time_and_account() {
+        let mut report = testing::billing(&[("AmazonEC2", "10")]);
+        report.timezone = Tz::Asia__Tokyo;
+        report.account_id = Some("123456789012".to_string());
+        assert_eq!(
+            report.context_line(),
+            "2024-06-01 → 2024-06-14 UTC | 2024-06-14 18:00 JST 時点 | アカウント 123456789012"
+        );
+        report.as_of = None;
+        report.account_id = None;
+        assert_eq!(report.context_line(), "2024-06-01 → 2024-06-14 UTC");
+    }
+
+    #[test]
+    fn period_is_the_utc_month_to_date() {
+        let period = Period::month_to_date(Utc.with_ymd_and_hms(2024, 6, 30, 23, 0, 0).unwrap());
+        assert_eq!(period.start, NaiveDate::from_ymd_opt(2024, 6, 1).unwrap());
+        assert_eq!(period.end, NaiveDate::from_ymd_opt(2024, 6, 30).unwrap());
+    }
+
+    #[test]
+    fn costs_are_grouped_by_account() {
+        let (accounts, services) = group_by_account(vec![
+            ("111111111111".to_string(), service("Amazon S3", "1")),
+            ("222222222222".to_string(), service("Amazon S3", "4")),
+            ("111111111111".to_string(), service("Amazon EC2", "2")),
+            ("222222222222".to_string(), service("AWS Lambda", "0.5")),
+        ]);
+        let accounts: Vec<_> = accounts
+            .iter()
+            .map(|account| {
+                (
+                    account.id.as_str(),
+                    names(&account.services),
+                    account.total(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            accounts,
+            [
+                (
+                    "222222222222",
+                    vec!["Amazon S3", "AWS Lambda"],
+                    Decimal::new(45, 1)
+                ),
+                (
+                    "111111111111",
+                    vec!["Amazon EC2", "Amazon S3"],
+                    Decimal::new(3, 0)
+                ),
+            ]
+        );
+        // the services of every account together
+        let services: Vec<_> = services
+            .iter()
+            .map(|service| (service.name.as_str(), service.cost))
+            .collect();
+        assert_eq!(
+            services,
+            [
+                ("AWS Lambda", Decimal::new(5, 1)),
+                ("Amazon EC2", Decimal::new(2, 0)),
+                ("Amazon S3", Decimal::new(5, 0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn context_line_notes_the_hidden_services() {
+        let mut report = testing::billing(&[("AmazonEC2", "10")]);
+        report.as_of = None;
+        report.hidden = vec!["Tax".to_string(), "Support".to_string()];
+        assert_eq!(
+            report.context_line(),
+            "2024-06-01 → 2024-06-14 UTC | (Tax, Support含む)"
+        );
+        report.language = Language::En;
+        assert!(report
+            .context_line()
+            .ends_with(" | (includes Tax, Support)"));
+    }
+
+    #[test]
+    fn daily_deltas_start_again_on_a_new_month() {
+        let totals = [
+            Decimal::new(10, 0),
+            Decimal::new(15, 0),
+            Decimal::new(15, 0),
+            Decimal::new(2, 0),
+            Decimal::new(5, 0),
+        ];
+        assert_eq!(
+            daily_deltas(&totals),
+            [
+                Decimal::new(5, 0),
+                Decimal::ZERO,
+                Decimal::new(2, 0),
+                Decimal::new(3, 0),
+            ]
+        );
+        assert_eq!(sum_daily_deltas(&totals), Decimal::new(10, 0));
+        assert!(daily_deltas(&totals[..1]).is_empty());
+    }
+
+    #[test]
+    fn top_increases_are_the_largest_over_the_floor() {
+        let services = [
+            service("AmazonEC2", "12"),
+            service("AmazonS3", "3"),
+            service("AWSLambda", "2"),
+            service("AmazonRDS", "20"),
+            service("AmazonSNS", "1"),
+        ];
+        let changes = BTreeMap::from([
+            (
+                "AmazonEC2".to_string(),
+                CostChange::Changed(Decimal::new(4, 0)),
+            ),
+            (
+                "AmazonS3".to_string(),
+                CostChange::Changed(Decimal::new(1, 0)),
+            ),
+            ("AWSLambda".to_string(), CostChange::New),
+            (
+                "AmazonRDS".to_string(),
+                CostChange::Changed(Decimal::new(-5, 0)),
+            ),
+            (
+                "AmazonSNS".to_string(),
+                CostChange::Changed(Decimal::new(1, 0)),
+            ),
+        ]);
+        let increases = top_increases(&services, &changes, Decimal::ONE);
+        let increases: Vec<_> = increases
+            .iter()
+            .map(|increase| (increase.name.as_str(), increase.delta))
+            .collect();
+        // a new service grew by its whole cost, ties go by name
+        assert_eq!(
+            increases,
+            [
+                ("AmazonEC2", Decimal::new(4, 0)),
+                ("AWSLambda", Decimal::new(2, 0)),
+                ("AmazonS3", Decimal::new(1, 0)),
+            ]
+        );
+        assert!(top_increases(&services, &changes, Decimal::new(4, 0)).is_empty());
+        assert!(top_increases(&services, &BTreeMap::new(), Decimal::ZERO).is_empty());
+    }
+
+    #[test]
+    fn rank_changes_since_the_previous_report() {
+        let previous = [
+            "AmazonS3".to_string(),
+            "AmazonEC2".to_string(),
+            "AWSLambda".to_string(),
+        ];
+        let changes = rank_changes(&["AmazonEC2", "AmazonS3", "AmazonRDS"], Some(&previous));
+        assert_eq!(
+            changes,
+            BTreeMap::from([
+                ("AmazonEC2".to_string(), RankChange::Moved(1)),
+                ("AmazonS3".to_string(), RankChange::Moved(-1)),
+                ("AmazonRDS".to_string(), RankChange::New),
+            ])
+        );
+        assert!(rank_changes(&["AmazonEC2"], None).is_empty());
+    }
+
+    #[test]
+    fn fingerprint_changes_with_the_figures_only() {
+        let report = testing::billing(&[("AmazonEC2", "10.5"), ("AmazonS3", "1")]);
+        let fingerprint = fingerprint(&report);
+        assert!(fingerprint.starts_with("1150:"), "{}", fingerprint);
+        assert_eq!(fingerprint.len(), "1150:".len() + 16);
+
+        // trailing zeros and sub-cent noise don't matter, another breakdown does
+        let same = testing::billing(&[("AmazonEC2", "10.500"), ("AmazonS3", "1.001")]);
+        assert_eq!(super::fingerprint(&same), fingerprint);
+        let moved = testing::billing(&[("AmazonEC2", "10.5"), ("AWSLambda", "1")]);
+        assert_ne!(super::fingerprint(&moved), fingerprint);
+    }
+
+    #[test]
+    fn unchanged_until_the_max_age() {
+        let sent_at = Utc.with_ymd_and_hms(2024, 6, 14, 0, 0, 0).unwrap();
+        let now = sent_at + Duration::hours(6);
+        let previous = Some(("1150:00", sent_at));
+        assert!(is_unchanged(previous, "1150:00", now, None));
+        assert!(!is_unchanged(previous, "1151:00", now, None));
+        assert!(!is_unchanged(None, "1150:00", now, None));
+        assert!(is_unchanged(
+            previous,
+            "1150:00",
+            now,
+            Some(Duration::hours(7))
+        ));
+        assert!(!is_unchanged(
+            previous,
+            "1150:00",
+            now,
+            Some(Duration::hours(6))
+        ));
+    }
+
+    #[test]
+    fn conversion_rounds_to_the_minor_unit() {
+        let converted = convert(Decimal::new(123456, 2), Decimal::new(1542, 1), "JPY");
+        assert_eq!(converted.currency, "JPY");
+        assert_eq!(converted.total, Decimal::new(190369, 0));
+        let converted = convert(Decimal::new(1000, 2), Decimal::new(9234, 4), "EUR");
+        assert_eq!(converted.total, Decimal::new(923, 2));
+    }
+
+    #[test]
+    fn degraded_notes() {
+        assert_eq!(
+            Degraded::Forecasts(2).note(Language::Ja),
+            "予測なし (2 サービス)"
+        );
+        assert_eq!(
+            Degraded::Forecasts(1).note(Language::En),
+            "no forecast for 1 service"
+        );
+        assert_eq!(
+            Degraded::Forecasts(3).note(Language::En),
+            "no forecast for 3 services"
+        );
+        assert_eq!(Degraded::Chart.note(Language::Ja), "グラフなし");
+        assert_eq!(
+            Degraded::ServiceList.note(Language::En),
+            "incomplete service list"
+        );
+    }
+}
@@ -0,0 +1,160 @@
+//! Runs the report from a shell instead of Lambda, against the credentials of the default
+//! provider chain (`AWS_PROFILE` etc.), to preview the message before deploying.
+//!
+//! ```text
+//! billing-report report [--report-type TYPE] [--send] [--webhook-url URL]
+//! ```
+//!
+//! Without `--send` the Slack payload is printed to stdout and nothing is posted.
+
+use std::env;
+use std::process;
+
+use aws_billing_notification::config::{Config, ReportType};
+use aws_billing_notification::handler::{self, Clients};
+use aws_billing_notification::metrics::InvocationMetrics;
+use aws_billing_notification::notify::{self, SlackLimits, SlackOptions};
+use aws_billing_notification::retry::RetryPolicy;
+use aws_billing_notification::secret::Secret;
+use aws_billing_notification::xray::Tracer;
+use aws_billing_notification::AppError;
+
+const USAGE: &str =
+    "usage: billing-report report [--report-type TYPE] [--send] [--webhook-url URL]";
+
+/// Exit codes, so scripts can tell a broken setup from a failing AWS or webhook call.
+const EXIT_USAGE: i32 = 2;
+const EXIT_CONFIG: i32 = 3;
+const EXIT_AWS: i32 = 4;
+const EXIT_NOTIFY: i32 = 5;
+
+struct Args {
+    report_type: Option<String>,
+    send: bool,
+    webhook_url: Option<String>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    match args.next().as_deref() {
+        Some("report") => {}
+        Some(other) => return Err(format!("unknown command: {}", other)),
+        None => return Err("missing command".to_string()),
+    }
+    let mut parsed = Args {
+        report_type: None,
+        send: false,
+        webhook_url: None,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--send" => parsed.send = true,
+            "--report-type" => {
+                parsed.report_type = Some(args.next().ok_or("--report-type needs a value")?)
+            }
+            "--webhook-url" => {
+                parsed.webhook_url = Some(args.next().ok_or("--webhook-url needs a value")?)
+            }
+            other => return Err(format!("unknown option: {}", other)),
+        }
+    }
+    Ok(parsed)
+}
+
+fn exit_code(err: &AppError) -> i32 {
+    match err {
+        AppError::Config(_) | AppError::InvalidWebhookUrl { .. } => EXIT_CONFIG,
+        AppError::Notify(_) => EXIT_NOTIFY,
+        _ => EXIT_AWS,
+    }
+}
+
+async fn run(args: Args) -> Result<(), AppError> {
+    let config = Config::from_env()?;
+    let report_type = ReportType::from_event(args.report_type.as_deref());
+    let preset = report_type.preset();
+    let clients = Clients::from_env().await?;
+    let mut retry = RetryPolicy::new(None);
+    retry.attempt_timeout = config.aws_timeout;
+    let tracer = Tracer::disabled();
+    let mut metrics = InvocationMetrics::new(report_type);
+
+    let mut billing =
+        handler::collect(&config, &preset, &clients, retry, &tracer, &mut metrics).await?;
+    billing.account_id = clients.account.id.clone();
+    billing.account_alias = config
+        .account_name
+        .clone()
+        .or_else(|| clients.account.alias.clone());
+    if !args.send {
+        let options = SlackOptions::from_config(&config, &preset);
+        let limits = SlackLimits::webhook(&options);
+        for payload in notify::build_slack_payloads(&billing, &options, limits) {
+            println!("{:#}", payload);
+        }
+        return Ok(());
+    }
+
+    let webhook_url = match args.webhook_url {
+        Some(url) => {
+            notify::validate_webhook_url("--webhook-url", &url, config.slack_webhook_host())?;
+            Some(Secret::new(url))
+        }
+        None if config.channels.iter().any(|channel| channel.uses_webhook()) => Some(
+            notify::get_webhook_url(
+                &clients.ssm,
+                &retry,
+                &tracer,
+                config.webhook_url.as_ref(),
+                config.slack_webhook_host(),
+            )
+            .await?,
+        ),
+        None => None,
+    };
+    let bot_token = if config.uses_slack_bot() {
+        Some(notify::get_bot_token(&clients.ssm, &retry, &tracer).await?)
+    } else {
+        None
+    };
+    let notifiers = notify::build_notifiers(
+        &config,
+        &preset,
+        webhook_url.as_ref(),
+        bot_token.as_ref(),
+        &clients,
+        None,
+    );
+    let receipts = notify::dispatch(
+        &notifiers,
+        &billing,
+        &tracer,
+        config.delivery,
+        config.notify_timeout,
+        None,
+    )
+    .await?;
+    for receipt in receipts {
+        eprintln!(
+            "delivered via {} ({})",
+            receipt.channel,
+            receipt.response.as_deref().unwrap_or("no response")
+        );
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    aws_billing_notification::init();
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            process::exit(EXIT_USAGE);
+        }
+    };
+    if let Err(err) = run(args).await {
+        eprintln!("{} error: {}", err.kind(), err);
+        process::exit(exit_code(&err));
+    }
+}
@@ -0,0 +1,122 @@
+//! A bar chart of the month's daily spend, for `ATTACH_CHART`.
+//!
+//! Drawn straight into a pixel buffer and encoded with the pure-Rust `png` crate, so it needs
+//! no fonts or system libraries on the Lambda runtime. It has no labels; the message carries
+//! the figures.
+
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+
+pub const CHART_WIDTH: u32 = 400;
+pub const CHART_HEIGHT: u32 = 120;
+
+/// A month has at most 31 days, which also caps the image's size.
+pub const MAX_BARS: usize = 31;
+
+/// Fewer days than this make no trend, so the chart is left out.
+pub const MIN_BARS: usize = 2;
+
+const BACKGROUND: [u8; 3] = [0xff, 0xff, 0xff];
+const BAR: [u8; 3] = [0x36, 0x7f, 0xc4];
+const BASELINE: [u8; 3] = [0xc0, 0xc0, 0xc0];
+
+/// Pixels between bars and around the plot.
+const GAP: u32 = 2;
+
+/// `daily_spend` (oldest first) as a PNG: a bar per day scaled to the largest, the latest
+/// [`MAX_BARS`] only. Days with negative spend (credits) are drawn as empty.
+pub fn render_bar_chart(daily_spend: &[Decimal]) -> Result<Vec<u8>, png::EncodingError> {
+    let days = &daily_spend[daily_spend.len().saturating_sub(MAX_BARS)..];
+    let mut pixels = vec![0; (CHART_WIDTH * CHART_HEIGHT * 3) as usize];
+    for pixel in pixels.chunks_mut(3) {
+        pixel.copy_from_slice(&BACKGROUND);
+    }
+    let baseline = CHART_HEIGHT - 1 - GAP;
+    fill(
+        &mut pixels,
+        0,
+        CHART_WIDTH,
+        baseline,
+        baseline + 1,
+        BASELINE,
+    );
+
+    let max = days.iter().copied().max().unwrap_or_default();
+    if !days.is_empty() && max > Decimal::ZERO {
+        let slot = (CHART_WIDTH - GAP) / days.len() as u32;
+        let plot_height = f64::from(baseline - GAP);
+        for (i, spend) in days.iter().enumerate() {
+            let ratio = (*spend / max).to_f64().unwrap_or_default().clamp(0.0, 1.0);
+            let height = (ratio * plot_height).round() as u32;
+            let left = GAP + i as u32 * slot;
+            let right = left + slot.saturating_sub(GAP).max(1);
+            fill(&mut pixels, left, right, baseline - height, baseline, BAR);
+        }
+    }
+
+    let mut encoded = vec![];
+    let mut encoder = png::Encoder::new(&mut encoded, CHART_WIDTH, CHART_HEIGHT);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels)?;
+    writer.finish()?;
+    Ok(encoded)
+}
+
+/// Paints the rows `top..bottom` of the columns `left..right`.
+fn fill(pixels: &mut [u8], left: u32, right: u32, top: u32, bottom: u32, color: [u8; 3]) {
+    for y in top..bottom.min(CHART_HEIGHT) {
+        for x in left..right.min(CHART_WIDTH) {
+            let offset = ((y * CHART_WIDTH + x) * 3) as usize;
+            pixels[offset..offset + 3].copy_from_slice(&color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(encoded: &[u8]) -> (png::OutputInfo, Vec<u8>) {
+        let mut reader = png::Decoder::new(encoded).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        (info, pixels)
+    }
+
+    fn pixel(pixels: &[u8], x: u32, y: u32) -> [u8; 3] {
+        let offset = ((y * CHART_WIDTH + x) * 3) as usize;
+        [pixels[offset], pixels[offset + 1], pixels[offset + 2]]
+    }
+
+    #[test]
+    fn bars_are_scaled_to_the_largest() {
+        let days = [Decimal::new(10, 0), Decimal::new(5, 0), Decimal::new(-1, 0)];
+        let (info, pixels) = decode(&render_bar_chart(&days).unwrap());
+        assert_eq!((info.width, info.height), (CHART_WIDTH, CHART_HEIGHT));
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+
+        let slot = (CHART_WIDTH - GAP) / 3;
+        let column = |day: u32| GAP + day * slot + 1;
+        let baseline = CHART_HEIGHT - 1 - GAP;
+        // the largest reaches the top of the plot, half as much half as high
+        assert_eq!(pixel(&pixels, column(0), GAP), BAR);
+        assert_eq!(pixel(&pixels, column(1), GAP), BACKGROUND);
+        assert_eq!(pixel(&pixels, column(1), baseline - 1), BAR);
+        assert_eq!(pixel(&pixels, column(1), baseline - 60), BACKGROUND);
+        // credits draw nothing
+        assert_eq!(pixel(&pixels, column(2), baseline - 1), BACKGROUND);
+        assert_eq!(pixel(&pixels, column(2), baseline), BASELINE);
+    }
+
+    #[test]
+    fn only_the_latest_days_are_drawn() {
+        let mut days = vec![Decimal::ONE; MAX_BARS + 5];
+        days[..5].fill(Decimal::new(100, 0));
+        let (_, pixels) = decode(&render_bar_chart(&days).unwrap());
+        // the days left out don't dwarf the rest
+        assert_eq!(pixel(&pixels, GAP + 1, GAP), BAR);
+        assert!(render_bar_chart(&[]).is_ok());
+    }
+}
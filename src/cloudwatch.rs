@@ -0,0 +1,1040 @@
+//! `AWS/Billing` metrics from CloudWatch.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+
+use aws_sdk_cloudwatch::error::ProvideErrorMetadata;
+use aws_sdk_cloudwatch::primitives::DateTime;
+use aws_sdk_cloudwatch::types::{Dimension, DimensionFilter, Statistic};
+use aws_sdk_cloudwatch::Client as CloudWatchClient;
+use chrono::{Datelike, Duration, SecondsFormat, TimeZone, Utc};
+use chrono_tz::Tz;
+use rust_decimal::prelude::FromPrimitive;
+use rust_decimal::Decimal;
+use serde_derive::Serialize;
+use tracing::warn;
+
+use crate::billing::ServiceBilling;
+use crate::config::BillingStatistic;
+use crate::limiter::Limiter;
+use crate::retry::{self, RetryPolicy};
+use crate::AppError;
+
+/// `GetMetricStatistics` request for `statistics` of `EstimatedCharges`.
+#[derive(Clone)]
+pub struct MetricStatisticsRequest {
+    pub namespace: String,
+    pub statistics: Vec<BillingStatistic>,
+    pub dimensions: Vec<(String, String)>,
+    pub start_time: chrono::DateTime<Utc>,
+    pub end_time: chrono::DateTime<Utc>,
+    pub period: Duration,
+}
+
+#[derive(Clone)]
+pub struct Datapoint {
+    /// start of the period the datapoint aggregates
+    pub timestamp: Option<chrono::DateTime<Utc>>,
+    pub maximum: Option<f64>,
+    /// only when requested, like `minimum`
+    pub average: Option<f64>,
+    pub minimum: Option<f64>,
+}
+
+/// Datapoints CloudWatch returned for a metric in one namespace, as they came, for
+/// `mode: "explain"`.
+#[derive(Serialize, Clone)]
+pub struct RawSeries {
+    pub namespace: String,
+    pub datapoints: Vec<RawDatapoint>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct RawDatapoint {
+    /// RFC 3339 start of the period
+    pub timestamp: Option<String>,
+    pub maximum: Option<f64>,
+}
+
+/// Aggregated value of a metric over the time window.
+pub struct Measurement {
+    pub value: Decimal,
+    /// when the latest datapoint was recorded, `None` without data
+    pub as_of: Option<chrono::DateTime<Utc>>,
+    /// how many datapoints `value` is made of; 0 means it is a stand-in
+    pub datapoints: usize,
+    /// each statistic the facade was asked for, in that order, `value` being the maximum
+    pub statistics: Vec<(BillingStatistic, Decimal)>,
+}
+
+/// `ListMetrics` request for metrics carrying the `dimension_name` dimension.
+#[derive(Clone)]
+pub struct ListMetricsRequest {
+    pub namespace: String,
+    pub metric_name: Option<String>,
+    pub dimension_name: String,
+    pub next_token: Option<String>,
+}
+
+/// Dimension values listed with `ListMetrics`.
+pub struct Listed {
+    pub values: Vec<String>,
+    /// a page after the first still failed after the retries, so the values are only those of
+    /// the pages before it
+    pub incomplete: bool,
+}
+
+pub struct ListMetricsPage {
+    /// dimensions of each metric in the page
+    pub metrics: Vec<Vec<(String, String)>>,
+    pub next_token: Option<String>,
+}
+
+/// The CloudWatch calls `CloudWatchFacade` is built on, so the aggregation logic can run
+/// against an in-memory source.
+pub trait BillingMetricsSource {
+    fn list_metrics(
+        &self,
+        request: ListMetricsRequest,
+    ) -> impl Future<Output = Result<ListMetricsPage, AppError>> + Send;
+
+    fn get_metric_statistics(
+        &self,
+        request: MetricStatisticsRequest,
+    ) -> impl Future<Output = Result<Vec<Datapoint>, AppError>> + Send;
+}
+
+/// A borrowed source, so a caller can still look at it after the facade is done, like a test
+/// at what a fake was asked for.
+impl<S: BillingMetricsSource + Sync> BillingMetricsSource for &S {
+    fn list_metrics(
+        &self,
+        request: ListMetricsRequest,
+    ) -> impl Future<Output = Result<ListMetricsPage, AppError>> + Send {
+        (**self).list_metrics(request)
+    }
+
+    fn get_metric_statistics(
+        &self,
+        request: MetricStatisticsRequest,
+    ) -> impl Future<Output = Result<Vec<Datapoint>, AppError>> + Send {
+        (**self).get_metric_statistics(request)
+    }
+}
+
+impl BillingMetricsSource for CloudWatchClient {
+    async fn list_metrics(&self, request: ListMetricsRequest) -> Result<ListMetricsPage, AppError> {
+        let output = self
+            .list_metrics()
+            .namespace(request.namespace)
+            .set_metric_name(request.metric_name)
+            .dimensions(
+                DimensionFilter::builder()
+                    .name(request.dimension_name)
+                    .build(),
+            )
+            .set_next_token(request.next_token)
+            .send();
+
+        match output.await {
+            Err(err) => Err(AppError::CloudWatch {
+                operation: "ListMetrics",
+                source: err.map_service_error(Into::into),
+            }),
+            Ok(output) => Ok(ListMetricsPage {
+                metrics: output
+                    .metrics()
+                    .iter()
+                    .map(|m| {
+                        m.dimensions()
+                            .iter()
+                            .filter_map(|d| Some((d.name()?.to_string(), d.value()?.to_string())))
+                            .collect()
+                    })
+                    .collect(),
+                next_token: output.next_token().map(str::to_string),
+            }),
+        }
+    }
+
+    async fn get_metric_statistics(
+        &self,
+        request: MetricStatisticsRequest,
+    ) -> Result<Vec<Datapoint>, AppError> {
+        let dimensions = request
+            .dimensions
+            .into_iter()
+            .map(|(name, value)| Dimension::builder().name(name).value(value).build())
+            .collect();
+        let metric = self
+            .get_metric_statistics()
+            .set_dimensions(Some(dimensions))
+            .metric_name("EstimatedCharges")
+            .namespace(request.namespace)
+            .set_statistics(Some(
+                request
+                    .statistics
+                    .iter()
+                    .map(|statistic| match statistic {
+                        BillingStatistic::Maximum => Statistic::Maximum,
+                        BillingStatistic::Average => Statistic::Average,
+                        BillingStatistic::Minimum => Statistic::Minimum,
+                    })
+                    .collect(),
+            ))
+            .start_time(DateTime::from_secs(request.start_time.timestamp()))
+            .end_time(DateTime::from_secs(request.end_time.timestamp()))
+            .period(request.period.num_seconds() as i32)
+            .send();
+
+        match metric.await {
+            Err(err) => Err(AppError::CloudWatch {
+                operation: "GetMetricStatistics",
+                source: err.map_service_error(Into::into),
+            }),
+            Ok(metric) => Ok(metric
+                .datapoints()
+                .iter()
+                .map(|dp| Datapoint {
+                    timestamp: dp
+                        .timestamp()
+                        .and_then(|t| chrono::DateTime::from_timestamp(t.secs(), t.subsec_nanos())),
+                    maximum: dp.maximum(),
+                    average: dp.average(),
+                    minimum: dp.minimum(),
+                })
+                .collect()),
+        }
+    }
+}
+
+/// Time range the metrics are aggregated over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeWindow {
+    /// the last `Duration` up to now
+    Rolling(Duration),
+    /// from midnight in `tz`, `days - 1` days ago, up to now: the current day so far for
+    /// `days == 1`
+    CalendarDays { days: i64, tz: Tz },
+    /// from midnight in `tz` on the first of the month up to now, the span of the month-to-date
+    /// figure in the billing console
+    MonthToDate { tz: Tz },
+}
+
+impl TimeWindow {
+    pub fn range(
+        &self,
+        now: chrono::DateTime<Utc>,
+    ) -> (chrono::DateTime<Utc>, chrono::DateTime<Utc>) {
+        match *self {
+            TimeWindow::Rolling(lookback) => (now - lookback, now),
+            TimeWindow::CalendarDays { days, tz } => {
+                let first_day = now.with_timezone(&tz).date_naive() - Duration::days(days - 1);
+                // midnight can be skipped by a DST change, in which case fall back to rolling
+                let start = midnight(first_day, tz).unwrap_or(now - Duration::days(days));
+                (start, now)
+            }
+            TimeWindow::MonthToDate { tz } => {
+                let today = now.with_timezone(&tz).date_naive();
+                let first_day = today.with_day(1).expect("every month has a first day");
+                let start =
+                    midnight(first_day, tz).unwrap_or(now - Duration::days(i64::from(today.day())));
+                (start, now)
+            }
+        }
+    }
+}
+
+/// The start of `day` in `tz`, unless a DST change skips it.
+fn midnight(day: chrono::NaiveDate, tz: Tz) -> Option<chrono::DateTime<Utc>> {
+    day.and_hms_opt(0, 0, 0)
+        .and_then(|midnight| tz.from_local_datetime(&midnight).earliest())
+        .map(|start| start.with_timezone(&Utc))
+}
+
+/// Resolution of the metric queries. The maximum over the window doesn't depend on it, but the
+/// timestamp of the latest datapoint is only as accurate as this.
+const PERIOD_HOURS: i64 = 1;
+
+/// Namespace the billing metrics are published to by AWS.
+pub const BILLING_NAMESPACE: &str = "AWS/Billing";
+
+/// Codes `GetMetricStatistics` answers a period or range it can't serve with.
+const INVALID_PARAMETER_CODES: &[&str] = &["InvalidParameterValue", "InvalidParameterCombination"];
+
+/// Most datapoints a single `GetMetricStatistics` call returns.
+const MAX_DATAPOINTS: i64 = 1440;
+
+/// The shortest period CloudWatch accepts for `range`: a multiple of 60 seconds yielding at
+/// most `MAX_DATAPOINTS`, and no finer than the resolution kept for data that old (1 minute
+/// for 15 days, 5 minutes for 63 days, an hour beyond).
+pub fn suggested_period(range: Duration) -> Duration {
+    let seconds = range.num_seconds().max(1);
+    let minutes = (seconds + MAX_DATAPOINTS * 60 - 1) / (MAX_DATAPOINTS * 60);
+    let period = Duration::minutes(minutes.max(1));
+    let retention_floor = if range > Duration::days(63) {
+        Duration::hours(1)
+    } else if range > Duration::days(15) {
+        Duration::minutes(5)
+    } else {
+        Duration::minutes(1)
+    };
+    period.max(retention_floor)
+}
+
+/// Queries `EstimatedCharges` in each of `namespaces` and combines them: services are unioned
+/// by name and costs with matching dimensions are summed.
+pub struct CloudWatchFacade<S> {
+    source: S,
+    namespaces: Vec<String>,
+    window: TimeWindow,
+    /// `None` leaves the `Currency` dimension out of the queries
+    currency: Option<String>,
+    /// always with the maximum
+    statistics: Vec<BillingStatistic>,
+    retry: RetryPolicy,
+    limiter: Limiter,
+}
+
+impl<S: BillingMetricsSource> CloudWatchFacade<S> {
+    /// Measurements carry `statistics` besides the maximum they are based on. Without a
+    /// `currency` the metrics are queried by namespace and service only, for custom namespaces
+    /// whose metrics have no `Currency` dimension.
+    pub fn new(
+        source: S,
+        namespaces: Vec<String>,
+        window: TimeWindow,
+        currency: Option<String>,
+        mut statistics: Vec<BillingStatistic>,
+        retry: RetryPolicy,
+        limiter: Limiter,
+    ) -> Self {
+        if !statistics.contains(&BillingStatistic::Maximum) {
+            statistics.insert(0, BillingStatistic::Maximum);
+        }
+        CloudWatchFacade {
+            source,
+            namespaces,
+            window,
+            currency,
+            statistics,
+            retry,
+            limiter,
+        }
+    }
+
+    /// Follows `next_token` until every page of every namespace is read and returns the
+    /// distinct values of `dimension_name`, sorted.
+    async fn list_dimension_values(
+        &self,
+        metric_name: Option<&str>,
+        dimension_name: &str,
+    ) -> Result<Listed, AppError> {
+        let mut listed = Listed {
+            values: vec![],
+            incomplete: false,
+        };
+        for namespace in &self.namespaces {
+            let namespace_listed = self
+                .list_namespace_dimension_values(namespace, metric_name, dimension_name)
+                .await?;
+            listed.values.extend(namespace_listed.values);
+            listed.incomplete |= namespace_listed.incomplete;
+        }
+        listed.values.sort_unstable();
+        listed.values.dedup();
+        Ok(listed)
+    }
+
+    /// A page that still fails after the retries, typically for throttling, ends the listing
+    /// with the values of the pages before it rather than losing them, unless it is the first.
+    async fn list_namespace_dimension_values(
+        &self,
+        namespace: &str,
+        metric_name: Option<&str>,
+        dimension_name: &str,
+    ) -> Result<Listed, AppError> {
+        let mut values = vec![];
+        let mut next_token = None;
+        let mut page_number = 1;
+        loop {
+            let request = ListMetricsRequest {
+                namespace: namespace.to_string(),
+                metric_name: metric_name.map(str::to_string),
+                dimension_name: dimension_name.to_string(),
+                next_token,
+            };
+            let page = match retry::with_retry(&self.retry, "ListMetrics", || async {
+                let _permit = self.limiter.acquire("ListMetrics").await;
+                self.source.list_metrics(request.clone()).await
+            })
+            .await
+            {
+                Ok(page) => page,
+                Err(err) if page_number > 1 => {
+                    warn!(
+                        namespace,
+                        page_number,
+                        listed = values.len(),
+                        error_kind = err.kind(),
+                        "listing metrics failed, going on with the pages before: {}",
+                        err
+                    );
+                    return Ok(Listed {
+                        values,
+                        incomplete: true,
+                    });
+                }
+                Err(err) => return Err(err),
+            };
+            values.extend(
+                page.metrics
+                    .into_iter()
+                    .flatten()
+                    .filter(|(name, _)| name == dimension_name)
+                    .map(|(_, value)| value),
+            );
+            next_token = page.next_token;
+            if next_token.is_none() {
+                return Ok(Listed {
+                    values,
+                    incomplete: false,
+                });
+            }
+            page_number += 1;
+        }
+    }
+
+    /// Datapoints of `namespace` within `window` up to `now`, `period` apart.
+    async fn get_datapoints(
+        &self,
+        namespace: &str,
+        dimensions: Vec<(String, String)>,
+        window: TimeWindow,
+        now: chrono::DateTime<Utc>,
+        period: Duration,
+    ) -> Result<Vec<Datapoint>, AppError> {
+        let (start_time, end_time) = window.range(now);
+        let request = MetricStatisticsRequest {
+            namespace: namespace.to_string(),
+            statistics: self.statistics.clone(),
+            dimensions,
+            start_time,
+            end_time,
+            period,
+        };
+        retry::with_retry(&self.retry, "GetMetricStatistics", || async {
+            let _permit = self.limiter.acquire("GetMetricStatistics").await;
+            self.source.get_metric_statistics(request.clone()).await
+        })
+        .await
+        .map_err(|err| match err {
+            AppError::CloudWatch { source, .. }
+                if source
+                    .as_service_error()
+                    .and_then(|err| err.code())
+                    .is_some_and(|code| INVALID_PARAMETER_CODES.contains(&code)) =>
+            {
+                let range = end_time - start_time;
+                AppError::MetricQuery {
+                    period_secs: period.num_seconds(),
+                    range_secs: range.num_seconds(),
+                    suggested_period_secs: suggested_period(range).num_seconds(),
+                    source,
+                }
+            }
+            err => err,
+        })
+    }
+
+    /// Largest `Maximum` across the window up to `now`, summed over the namespaces, or zero when
+    /// there is no data. `as_of` is the oldest of the namespaces' latest datapoints, so a
+    /// namespace that stopped reporting still shows up as stale. The other statistics are
+    /// taken across the window the same way: the mean of the averages, the smallest minimum.
+    async fn get_maximum(
+        &self,
+        dimensions: Vec<(String, String)>,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<Measurement, AppError> {
+        let mut value = Decimal::ZERO;
+        let mut as_of = None;
+        let mut count = 0;
+        let mut statistics: Vec<(BillingStatistic, Decimal)> = self
+            .statistics
+            .iter()
+            .map(|statistic| (*statistic, Decimal::ZERO))
+            .collect();
+        for namespace in &self.namespaces {
+            let datapoints = self
+                .get_datapoints(
+                    namespace,
+                    dimensions.clone(),
+                    self.window,
+                    now,
+                    Duration::hours(PERIOD_HOURS),
+                )
+                .await?;
+            count += datapoints.iter().filter(|dp| dp.maximum.is_some()).count();
+            let maximum = datapoints
+                .iter()
+                .filter_map(|dp| dp.maximum)
+                .reduce(f64::max)
+                .and_then(Decimal::from_f64)
+                .unwrap_or_default();
+            value += maximum;
+            for (statistic, sum) in &mut statistics {
+                *sum += match statistic {
+                    BillingStatistic::Maximum => maximum,
+                    BillingStatistic::Average => {
+                        let averages: Vec<f64> =
+                            datapoints.iter().filter_map(|dp| dp.average).collect();
+                        (!averages.is_empty())
+                            .then(|| averages.iter().sum::<f64>() / averages.len() as f64)
+                            .and_then(Decimal::from_f64)
+                            .unwrap_or_default()
+                    }
+                    BillingStatistic::Minimum => datapoints
+                        .iter()
+                        .filter_map(|dp| dp.minimum)
+                        .reduce(f64::min)
+                        .and_then(Decimal::from_f64)
+                        .unwrap_or_default(),
+                };
+            }
+            if let Some(latest) = datapoints.iter().filter_map(|dp| dp.timestamp).max() {
+                as_of =
+                    Some(as_of.map_or(latest, |as_of: chrono::DateTime<Utc>| as_of.min(latest)));
+            }
+        }
+        Ok(Measurement {
+            value,
+            as_of,
+            datapoints: count,
+            statistics,
+        })
+    }
+
+    pub async fn get_available_currencies(&self) -> Result<Listed, AppError> {
+        self.list_dimension_values(Some("EstimatedCharges"), "Currency")
+            .await
+    }
+
+    pub async fn get_total_cost(&self) -> Result<Measurement, AppError> {
+        self.get_maximum(self.dimensions(None), Utc::now()).await
+    }
+
+    /// The dimensions of the total, or of `service`.
+    fn dimensions(&self, service: Option<&str>) -> Vec<(String, String)> {
+        self.currency
+            .iter()
+            .map(|currency| ("Currency".to_string(), currency.clone()))
+            .chain(service.map(|service| ("ServiceName".to_string(), service.to_string())))
+            .collect()
+    }
+
+    /// Daily `Maximum` of the total over `window` plus the day before it, summed over the
+    /// namespaces and oldest first, for summing day-over-day deltas. Days without data are
+    /// skipped.
+    pub async fn get_daily_totals(&self, window: Duration) -> Result<Vec<Decimal>, AppError> {
+        let mut daily = BTreeMap::new();
+        for namespace in &self.namespaces {
+            let datapoints = self
+                .get_datapoints(
+                    namespace,
+                    self.dimensions(None),
+                    TimeWindow::Rolling(window + Duration::days(1)),
+                    Utc::now(),
+                    Duration::days(1),
+                )
+                .await?;
+            for dp in datapoints {
+                if let Some(maximum) = dp.maximum.and_then(Decimal::from_f64) {
+                    *daily.entry(dp.timestamp).or_insert(Decimal::ZERO) += maximum;
+                }
+            }
+        }
+        Ok(daily.into_values().collect())
+    }
+
+    /// The datapoints behind [`Self::get_total_cost`], or behind [`Self::get_cost`] of
+    /// `service`, per namespace.
+    pub async fn get_raw_series(&self, service: Option<&str>) -> Result<Vec<RawSeries>, AppError> {
+        let dimensions = self.dimensions(service);
+        let now = Utc::now();
+        let mut series = Vec::with_capacity(self.namespaces.len());
+        for namespace in &self.namespaces {
+            let datapoints = self
+                .get_datapoints(
+                    namespace,
+                    dimensions.clone(),
+                    self.window,
+                    now,
+                    Duration::hours(PERIOD_HOURS),
+                )
+                .await?;
+            series.push(RawSeries {
+                namespace: namespace.clone(),
+                datapoints: datapoints
+                    .into_iter()
+                    .map(|dp| RawDatapoint {
+                        timestamp: dp
+                            .timestamp
+                            .map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true)),
+                        maximum: dp.maximum,
+                    })
+                    .collect(),
+            });
+        }
+        Ok(series)
+    }
+
+    pub async fn get_services_in_billing_namespace(&self) -> Result<Listed, AppError> {
+        self.list_dimension_values(None, "ServiceName").await
+    }
+
+    pub async fn get_cost(&self, service: &str) -> Result<ServiceBilling, AppError> {
+        self.get_cost_as_of(service, Utc::now()).await
+    }
+
+    /// The cost of `service` as it was `ago` ago, over the same window ending then.
+    pub async fn get_previous_cost(
+        &self,
+        service: &str,
+        ago: Duration,
+    ) -> Result<ServiceBilling, AppError> {
+        self.get_cost_as_of(service, Utc::now() - ago).await
+    }
+
+    async fn get_cost_as_of(
+        &self,
+        service: &str,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<ServiceBilling, AppError> {
+        let cost = self
+            .get_maximum(self.dimensions(Some(service)), now)
+            .await?
+            .value;
+        Ok(ServiceBilling {
+            name: service.to_string(),
+            cost,
+            usage: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakeMetricsSource;
+
+    fn facade<'a>(
+        source: &'a FakeMetricsSource,
+        namespaces: &[&str],
+    ) -> CloudWatchFacade<&'a FakeMetricsSource> {
+        CloudWatchFacade::new(
+            source,
+            namespaces
+                .iter()
+                .map(|namespace| namespace.to_string())
+                .collect(),
+            TimeWindow::Rolling(Duration::days(1)),
+            Some("USD".to_string()),
+            vec![BillingStatistic::Maximum],
+            RetryPolicy::new(None),
+            Limiter::new(5, 0.0),
+        )
+    }
+
+    #[tokio::test]
+    async fn lists_services_over_every_page() {
+        let source = FakeMetricsSource::default()
+            .with_page(vec![
+                vec![("Currency", "USD"), ("ServiceName", "AmazonS3")],
+                vec![("Currency", "USD"), ("ServiceName", "AmazonEC2")],
+            ])
+            .with_page(vec![
+                vec![("Currency", "USD"), ("ServiceName", "AWSLambda")],
+                vec![("Currency", "USD"), ("ServiceName", "AmazonEC2")],
+            ]);
+        let listed = facade(&source, &[BILLING_NAMESPACE])
+            .get_services_in_billing_namespace()
+            .await
+            .unwrap();
+        assert_eq!(listed.values, ["AWSLambda", "AmazonEC2", "AmazonS3"]);
+        assert!(!listed.incomplete);
+        let tokens: Vec<_> = source
+            .list_requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|request| request.next_token.clone())
+            .collect();
+        assert_eq!(tokens, [None, Some("1".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn missing_datapoints_cost_nothing() {
+        let source = FakeMetricsSource::default()
+            .with_datapoints(vec![("Currency", "USD")], vec![None, Some(12.5), None]);
+        let facade = facade(&source, &[BILLING_NAMESPACE]);
+
+        let total = facade.get_total_cost().await.unwrap();
+        assert_eq!(total.value, Decimal::new(125, 1));
+        assert_eq!(total.datapoints, 1);
+
+        let cost = facade.get_cost("AmazonEC2").await.unwrap();
+        assert_eq!(cost.name, "AmazonEC2");
+        assert_eq!(cost.cost, Decimal::ZERO);
+        let requests = source.statistics_requests.lock().unwrap();
+        assert_eq!(
+            requests[1].dimensions,
+            [
+                ("Currency".to_string(), "USD".to_string()),
+                ("ServiceName".to_string(), "AmazonEC2".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn available_currencies_are_sorted_and_deduplicated() {
+        let source = FakeMetricsSource::default().with_page(vec![
+            vec![("Currency", "USD")],
+            vec![("Currency", "JPY")],
+            vec![("Currency", "USD"), ("ServiceName", "AmazonEC2")],
+        ]);
+        let facade = facade(&source, &[BILLING_NAMESPACE]);
+        assert_eq!(
+            facade.get_available_currencies().await.unwrap().values,
+            ["JPY", "USD"]
+        );
+    }
+
+    #[tokio::test]
+    async fn total_is_the_largest_datapoint() {
+        let source = FakeMetricsSource::default().with_datapoints(
+            vec![("Currency", "USD")],
+            vec![Some(3.0), Some(12.5), Some(7.0)],
+        );
+        let facade = facade(&source, &[BILLING_NAMESPACE]);
+        assert_eq!(
+            facade.get_total_cost().await.unwrap().value,
+            Decimal::new(125, 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn total_without_data_is_zero_and_undated() {
+        let source = FakeMetricsSource::default();
+        let total = facade(&source, &[BILLING_NAMESPACE])
+            .get_total_cost()
+            .await
+            .unwrap();
+        assert_eq!(total.value, Decimal::ZERO);
+        assert_eq!(total.datapoints, 0);
+        assert!(total.as_of.is_none());
+    }
+
+    #[tokio::test]
+    async fn namespaces_are_combined() {
+        let source = FakeMetricsSource::default()
+            .with_page(vec![vec![("ServiceName", "AmazonEC2")]])
+            .with_datapoints(vec![("Currency", "USD")], vec![Some(10.0), Some(30.0)])
+            .with_datapoints(
+                vec![("Currency", "USD"), ("ServiceName", "AmazonEC2")],
+                vec![Some(30.0)],
+            )
+            .in_namespace("Custom/Costs")
+            .with_page(vec![
+                vec![("ServiceName", "Datadog")],
+                vec![("ServiceName", "AmazonEC2")],
+            ])
+            .with_datapoints(vec![("Currency", "USD")], vec![Some(5.0)])
+            .with_datapoints(
+                vec![("Currency", "USD"), ("ServiceName", "AmazonEC2")],
+                vec![Some(2.0)],
+            );
+        let facade = facade(&source, &[BILLING_NAMESPACE, "Custom/Costs"]);
+
+        let listed = facade.get_services_in_billing_namespace().await.unwrap();
+        assert_eq!(listed.values, ["AmazonEC2", "Datadog"]);
+        assert_eq!(
+            facade.get_total_cost().await.unwrap().value,
+            Decimal::from(35)
+        );
+        assert_eq!(
+            facade.get_cost("AmazonEC2").await.unwrap().cost,
+            Decimal::from(32)
+        );
+        assert_eq!(
+            facade.get_cost("Datadog").await.unwrap().cost,
+            Decimal::ZERO
+        );
+    }
+
+    #[tokio::test]
+    async fn total_is_dated_by_its_latest_datapoint() {
+        let hour = |hour| Some(Utc.with_ymd_and_hms(2024, 6, 14, hour, 0, 0).unwrap());
+        let source = FakeMetricsSource::default().with_timed_datapoints(
+            vec![("Currency", "USD")],
+            vec![
+                (hour(3), Some(20.0)),
+                (hour(9), Some(25.0)),
+                (hour(6), Some(22.0)),
+                (None, Some(1.0)),
+            ],
+        );
+        let total = facade(&source, &[BILLING_NAMESPACE])
+            .get_total_cost()
+            .await
+            .unwrap();
+        assert_eq!(total.value, Decimal::from(25));
+        assert_eq!(total.as_of, hour(9));
+        let requests = source.statistics_requests.lock().unwrap();
+        assert_eq!(requests[0].period, Duration::hours(PERIOD_HOURS));
+    }
+
+    #[tokio::test]
+    async fn daily_totals_cover_the_day_before_the_window_oldest_first() {
+        let day = |day| Some(Utc.with_ymd_and_hms(2024, 6, day, 0, 0, 0).unwrap());
+        let source = FakeMetricsSource::default().with_timed_datapoints(
+            vec![("Currency", "USD")],
+            vec![
+                (day(9), Some(15.0)),
+                (day(7), Some(10.0)),
+                (day(8), None),
+                (day(10), Some(16.5)),
+            ],
+        );
+        let facade = facade(&source, &[BILLING_NAMESPACE]);
+        let totals = facade.get_daily_totals(Duration::days(7)).await.unwrap();
+        assert_eq!(
+            totals,
+            [Decimal::from(10), Decimal::from(15), Decimal::new(165, 1)]
+        );
+        let requests = facade.source.statistics_requests.lock().unwrap();
+        assert_eq!(requests[0].period, Duration::days(1));
+        assert_eq!(
+            requests[0].end_time - requests[0].start_time,
+            Duration::days(8)
+        );
+    }
+
+    #[test]
+    fn calendar_days_start_at_midnight_in_the_timezone() {
+        // 12:00 in Tokyo
+        let now = Utc.with_ymd_and_hms(2024, 6, 14, 3, 0, 0).unwrap();
+        let today = TimeWindow::CalendarDays {
+            days: 1,
+            tz: Tz::Asia__Tokyo,
+        };
+        assert_eq!(
+            today.range(now),
+            (Utc.with_ymd_and_hms(2024, 6, 13, 15, 0, 0).unwrap(), now)
+        );
+        let week = TimeWindow::CalendarDays {
+            days: 7,
+            tz: Tz::Asia__Tokyo,
+        };
+        assert_eq!(
+            week.range(now).0,
+            Utc.with_ymd_and_hms(2024, 6, 7, 15, 0, 0).unwrap()
+        );
+        assert_eq!(
+            TimeWindow::Rolling(Duration::days(1)).range(now),
+            (now - Duration::days(1), now)
+        );
+    }
+
+    #[test]
+    fn calendar_day_without_a_midnight_falls_back_to_rolling() {
+        // Havana springs forward from 00:00 to 01:00 on 2024-03-10
+        let now = Utc.with_ymd_and_hms(2024, 3, 10, 12, 0, 0).unwrap();
+        let window = TimeWindow::CalendarDays {
+            days: 1,
+            tz: Tz::America__Havana,
+        };
+        assert_eq!(window.range(now), (now - Duration::days(1), now));
+    }
+
+    #[tokio::test]
+    async fn combined_total_is_as_old_as_its_stalest_namespace() {
+        let hour = |hour| Some(Utc.with_ymd_and_hms(2024, 6, 14, hour, 0, 0).unwrap());
+        let source = FakeMetricsSource::default()
+            .with_timed_datapoints(vec![("Currency", "USD")], vec![(hour(9), Some(30.0))])
+            .in_namespace("Custom/Costs")
+            .with_timed_datapoints(
+                vec![("Currency", "USD")],
+                vec![(hour(1), Some(4.0)), (hour(2), Some(5.0))],
+            )
+            .in_namespace("Custom/Empty");
+        let total = facade(
+            &source,
+            &[BILLING_NAMESPACE, "Custom/Costs", "Custom/Empty"],
+        )
+        .get_total_cost()
+        .await
+        .unwrap();
+        assert_eq!(total.value, Decimal::from(35));
+        assert_eq!(total.as_of, hour(2));
+    }
+
+    #[test]
+    fn suggested_period_fits_the_datapoint_limit() {
+        assert_eq!(suggested_period(Duration::seconds(0)), Duration::minutes(1));
+        assert_eq!(suggested_period(Duration::hours(1)), Duration::minutes(1));
+        assert_eq!(suggested_period(Duration::days(1)), Duration::minutes(1));
+        assert_eq!(
+            suggested_period(Duration::days(1) + Duration::seconds(1)),
+            Duration::minutes(2)
+        );
+        assert_eq!(suggested_period(Duration::days(7)), Duration::minutes(7));
+        assert_eq!(suggested_period(Duration::days(90)), Duration::minutes(90));
+        for days in [1, 7, 31, 90, 400] {
+            let range = Duration::days(days);
+            let period = suggested_period(range);
+            assert_eq!(period.num_seconds() % 60, 0);
+            assert!(range.num_seconds() / period.num_seconds() <= MAX_DATAPOINTS);
+        }
+    }
+
+    #[tokio::test]
+    async fn raw_series_are_returned_as_they_came() {
+        let hour = |hour| Some(Utc.with_ymd_and_hms(2024, 6, 14, hour, 0, 0).unwrap());
+        let source = FakeMetricsSource::default()
+            .with_timed_datapoints(
+                vec![("Currency", "USD"), ("ServiceName", "AmazonEC2")],
+                vec![(hour(9), Some(25.0)), (hour(3), None)],
+            )
+            .in_namespace("Custom/Costs");
+        let series = facade(&source, &[BILLING_NAMESPACE, "Custom/Costs"])
+            .get_raw_series(Some("AmazonEC2"))
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(&series).unwrap(),
+            serde_json::json!([
+                {
+                    "namespace": "AWS/Billing",
+                    "datapoints": [
+                        {"timestamp": "2024-06-14T09:00:00Z", "maximum": 25.0},
+                        {"timestamp": "2024-06-14T03:00:00Z", "maximum": null},
+                    ],
+                },
+                {"namespace": "Custom/Costs", "datapoints": []},
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn total_carries_every_statistic_asked_for() {
+        let mut source = FakeMetricsSource::default();
+        let datapoint = |maximum, average, minimum| Datapoint {
+            timestamp: None,
+            maximum: Some(maximum),
+            average: Some(average),
+            minimum: Some(minimum),
+        };
+        source.datapoints.insert(
+            (
+                BILLING_NAMESPACE.to_string(),
+                vec![("Currency".to_string(), "USD".to_string())],
+            ),
+            vec![datapoint(12.0, 9.0, 8.0), datapoint(10.0, 7.0, 6.0)],
+        );
+        let facade = CloudWatchFacade::new(
+            &source,
+            vec![BILLING_NAMESPACE.to_string()],
+            TimeWindow::Rolling(Duration::days(1)),
+            Some("USD".to_string()),
+            vec![BillingStatistic::Average, BillingStatistic::Minimum],
+            RetryPolicy::new(None),
+            Limiter::new(5, 0.0),
+        );
+
+        let total = facade.get_total_cost().await.unwrap();
+        assert_eq!(total.value, Decimal::new(12, 0));
+        // the maximum is always asked for, first
+        assert_eq!(
+            total.statistics,
+            [
+                (BillingStatistic::Maximum, Decimal::new(12, 0)),
+                (BillingStatistic::Average, Decimal::new(8, 0)),
+                (BillingStatistic::Minimum, Decimal::new(6, 0)),
+            ]
+        );
+        let requests = source.statistics_requests.lock().unwrap();
+        assert_eq!(
+            requests[0].statistics,
+            [
+                BillingStatistic::Maximum,
+                BillingStatistic::Average,
+                BillingStatistic::Minimum,
+            ]
+        );
+    }
+
+    #[test]
+    fn month_to_date_starts_on_the_first_in_the_timezone() {
+        // 2024-07-01 08:00 in Tokyo, still June in UTC
+        let now = Utc.with_ymd_and_hms(2024, 6, 30, 23, 0, 0).unwrap();
+        let window = TimeWindow::MonthToDate {
+            tz: Tz::Asia__Tokyo,
+        };
+        assert_eq!(
+            window.range(now),
+            (Utc.with_ymd_and_hms(2024, 6, 30, 15, 0, 0).unwrap(), now)
+        );
+        let window = TimeWindow::MonthToDate { tz: Tz::UTC };
+        assert_eq!(
+            window.range(now).0,
+            Utc.with_ymd_and_hms(2024, 6, 1, 0, 0, 0).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_page_keeps_the_pages_before_it() {
+        let source = FakeMetricsSource::default()
+            .with_page(vec![vec![("Currency", "USD"), ("ServiceName", "AmazonS3")]])
+            .with_page(vec![vec![
+                ("Currency", "USD"),
+                ("ServiceName", "AmazonEC2"),
+            ]])
+            .with_page(vec![vec![
+                ("Currency", "USD"),
+                ("ServiceName", "AWSLambda"),
+            ]])
+            .failing_page(1);
+        let listed = facade(&source, &[BILLING_NAMESPACE])
+            .get_services_in_billing_namespace()
+            .await
+            .unwrap();
+        assert_eq!(listed.values, ["AmazonS3"]);
+        assert!(listed.incomplete);
+        // the listing ends there
+        let tokens: Vec<_> = source
+            .list_requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|request| request.next_token.clone())
+            .collect();
+        assert!(tokens.iter().all(|token| token.as_deref() != Some("2")));
+    }
+
+    #[tokio::test]
+    async fn failed_first_page_fails_the_listing() {
+        let source = FakeMetricsSource::default()
+            .with_page(vec![vec![("Currency", "USD"), ("ServiceName", "AmazonS3")]])
+            .failing_page(0);
+        let result = facade(&source, &[BILLING_NAMESPACE])
+            .get_services_in_billing_namespace()
+            .await;
+        assert!(matches!(
+            result,
+            Err(AppError::CloudWatch {
+                operation: "ListMetrics",
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,2089 @@
+//! Settings read from the event and from environment variables.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::time::Duration as StdDuration;
+
+use chrono::{Duration, NaiveTime};
+use chrono_tz::Tz;
+use regex::Regex;
+use rust_decimal::{Decimal, RoundingStrategy};
+
+use crate::cloudwatch::BILLING_NAMESPACE;
+use crate::handler;
+use crate::notify;
+use crate::secret::Secret;
+use crate::AppError;
+
+/// Slack's limit on the size of a message.
+const SLACK_MAX_BYTES: usize = 40_000;
+
+/// Report "flavor" selected by the `report_type` field of the scheduled event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportType {
+    Daily,
+    Weekly,
+    Detailed,
+}
+
+/// How the per-service breakdown is laid out in the Slack attachment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// two fields per row
+    Compact,
+    /// one field per row
+    Full,
+}
+
+pub struct ReportPreset {
+    pub lookback: Duration,
+    pub top_n: Option<usize>,
+    pub format: ReportFormat,
+}
+
+impl ReportType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ReportType::Daily => "daily",
+            ReportType::Weekly => "weekly",
+            ReportType::Detailed => "detailed",
+        }
+    }
+
+    /// Unknown or empty values fall back to `Daily`.
+    pub fn from_event(report_type: Option<&str>) -> Self {
+        match report_type.map(str::trim) {
+            Some("weekly") => ReportType::Weekly,
+            Some("detailed") => ReportType::Detailed,
+            _ => ReportType::Daily,
+        }
+    }
+
+    /// Presets for each report type:
+    ///
+    /// | type       | lookback | top-N | format  |
+    /// |------------|----------|-------|---------|
+    /// | `daily`    | 1 day    | all   | compact |
+    /// | `weekly`   | 7 days   | 10    | compact |
+    /// | `detailed` | 1 day    | all   | full    |
+    pub fn preset(self) -> ReportPreset {
+        match self {
+            ReportType::Daily => ReportPreset {
+                lookback: Duration::days(1),
+                top_n: None,
+                format: ReportFormat::Compact,
+            },
+            ReportType::Weekly => ReportPreset {
+                lookback: Duration::days(7),
+                top_n: Some(10),
+                format: ReportFormat::Compact,
+            },
+            ReportType::Detailed => ReportPreset {
+                lookback: Duration::days(1),
+                top_n: None,
+                format: ReportFormat::Full,
+            },
+        }
+    }
+}
+
+/// How the breakdown is rendered in Slack, selected by `MESSAGE_FORMAT` (or `MESSAGE_STYLE`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageFormat {
+    /// `fields`: a field per service in an attachment
+    Fields,
+    /// `table`: a fixed-width table in a code block, which doesn't wrap awkwardly
+    Table,
+    /// `markdown`: the report is also returned as a GitHub-flavored Markdown table in
+    /// `markdown` of the output, e.g. for a CI job to post on a pull request. Slack, which
+    /// doesn't render Markdown tables, gets the `table` layout
+    Markdown,
+}
+
+impl MessageFormat {
+    pub fn parse(format: Option<&str>) -> Result<Self, AppError> {
+        match format.map(str::trim) {
+            None | Some("") | Some("fields") => Ok(MessageFormat::Fields),
+            Some("table") => Ok(MessageFormat::Table),
+            Some("markdown") => Ok(MessageFormat::Markdown),
+            Some(other) => Err(AppError::Config(format!(
+                "invalid MESSAGE_FORMAT: {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// Smallest change of the total worth a report with `NOTIFY_ONLY_ON_CHANGE=true`, from
+/// `MIN_CHANGE`: `0.5` for an amount, `5%` for a share of the previous total. Also the
+/// `DISCREPANCY_TOLERANCE` between the total and the breakdown, in the same notation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MinChange {
+    Absolute(Decimal),
+    Percent(Decimal),
+}
+
+impl MinChange {
+    pub fn parse(min_change: Option<&str>) -> Result<Self, AppError> {
+        Self::parse_var(
+            "MIN_CHANGE",
+            min_change,
+            MinChange::Absolute(Decimal::new(1, 2)),
+        )
+    }
+
+    /// Parses the value of the environment variable `name`, `default` when unset.
+    pub fn parse_var(name: &str, value: Option<&str>, default: Self) -> Result<Self, AppError> {
+        let min_change = match value.map(str::trim) {
+            None | Some("") => return Ok(default),
+            Some(min_change) => min_change,
+        };
+        let invalid = |err: rust_decimal::Error| {
+            AppError::Config(format!("invalid {}: {}: {}", name, min_change, err))
+        };
+        match min_change.strip_suffix('%') {
+            Some(percent) => Ok(MinChange::Percent(
+                percent.trim().parse::<Decimal>().map_err(invalid)?,
+            )),
+            None => Ok(MinChange::Absolute(
+                min_change.parse::<Decimal>().map_err(invalid)?,
+            )),
+        }
+    }
+}
+
+/// Tiers of the total, from `SPEND_BUCKETS`: ascending thresholds such as `100,500`, each
+/// starting a tier. The lowest tier is 🟢, the highest 🔴, and the ones between 🟡 and then 🟠,
+/// so there can be up to 3 thresholds.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpendBuckets {
+    thresholds: Vec<Decimal>,
+}
+
+/// Slack attachment color and emoji of each tier, by the number of thresholds.
+const SPEND_BUCKET_STYLES: [&[(&str, &str)]; 3] = [
+    &[("good", "🟢"), ("danger", "🔴")],
+    &[("good", "🟢"), ("warning", "🟡"), ("danger", "🔴")],
+    &[
+        ("good", "🟢"),
+        ("warning", "🟡"),
+        ("#ff8c00", "🟠"),
+        ("danger", "🔴"),
+    ],
+];
+
+impl SpendBuckets {
+    pub fn parse(buckets: &str) -> Result<Self, AppError> {
+        let invalid = |reason: &str| {
+            AppError::Config(format!("invalid SPEND_BUCKETS: {}: {}", buckets, reason))
+        };
+        let thresholds = buckets
+            .split(',')
+            .map(str::trim)
+            .filter(|threshold| !threshold.is_empty())
+            .map(|threshold| {
+                threshold
+                    .parse::<Decimal>()
+                    .map_err(|_| invalid("thresholds must be amounts"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if thresholds.is_empty() || thresholds.len() > SPEND_BUCKET_STYLES.len() {
+            return Err(invalid("expected 1 to 3 thresholds"));
+        }
+        if thresholds.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(invalid("thresholds must be ascending"));
+        }
+        Ok(SpendBuckets { thresholds })
+    }
+
+    /// The color and emoji of the tier `total` is in: the one of the highest threshold it
+    /// reaches, or the lowest below all of them.
+    pub fn style(&self, total: Decimal) -> (&'static str, &'static str) {
+        let tier = self
+            .thresholds
+            .iter()
+            .filter(|threshold| total >= **threshold)
+            .count();
+        SPEND_BUCKET_STYLES[self.thresholds.len() - 1][tier]
+    }
+}
+
+/// Services summed under one label, from `SERVICE_ROLLUP`: a JSON object of regular
+/// expressions to labels, e.g. `{"EC2": "EC2"}` puts `AmazonEC2`, `AmazonEC2-Other` and
+/// `EC2-Instances` under `EC2`. A service matching several patterns goes to the first in
+/// alphabetical order of the patterns.
+#[derive(Clone, Debug)]
+pub struct ServiceRollup {
+    pub pattern: Regex,
+    pub label: String,
+}
+
+impl ServiceRollup {
+    pub fn parse_map(rollup: Option<&str>) -> Result<Vec<Self>, AppError> {
+        let rollup = match rollup.map(str::trim) {
+            None | Some("") => return Ok(vec![]),
+            Some(rollup) => rollup,
+        };
+        let map: serde_json::Map<String, serde_json::Value> = serde_json::from_str(rollup)
+            .map_err(|err| {
+                AppError::Config(format!(
+                    "invalid SERVICE_ROLLUP: expected a JSON object of patterns to labels: {}",
+                    err
+                ))
+            })?;
+        map.into_iter()
+            .map(|(pattern, label)| {
+                let label = match label {
+                    serde_json::Value::String(label) if !label.trim().is_empty() => label,
+                    _ => {
+                        return Err(AppError::Config(format!(
+                            "invalid SERVICE_ROLLUP: the label of {} must be a non-empty string",
+                            pattern
+                        )))
+                    }
+                };
+                let pattern = Regex::new(&pattern).map_err(|err| {
+                    AppError::Config(format!(
+                        "invalid SERVICE_ROLLUP pattern {}: {}",
+                        pattern, err
+                    ))
+                })?;
+                Ok(ServiceRollup { pattern, label })
+            })
+            .collect()
+    }
+}
+
+/// How the displayed total is rounded, from `ROUND_TOTAL` (`nearest`, `up` or `down`) to
+/// `ROUND_TOTAL_PRECISION` decimal places, e.g. `ROUND_TOTAL=up ROUND_TOTAL_PRECISION=0` for a
+/// conservative whole-dollar figure. The breakdown is never rounded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TotalRounding {
+    pub mode: RoundingMode,
+    pub precision: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoundingMode {
+    Nearest,
+    Up,
+    Down,
+}
+
+impl Default for TotalRounding {
+    fn default() -> Self {
+        TotalRounding {
+            mode: RoundingMode::Nearest,
+            precision: 2,
+        }
+    }
+}
+
+impl TotalRounding {
+    pub fn parse(mode: Option<&str>, precision: Option<&str>) -> Result<Self, AppError> {
+        let mode = match mode.map(str::trim) {
+            None | Some("") | Some("nearest") => RoundingMode::Nearest,
+            Some("up") => RoundingMode::Up,
+            Some("down") => RoundingMode::Down,
+            Some(other) => {
+                return Err(AppError::Config(format!("invalid ROUND_TOTAL: {}", other)));
+            }
+        };
+        let precision = match precision.map(str::trim) {
+            None | Some("") => TotalRounding::default().precision,
+            Some(precision) => precision
+                .parse::<u32>()
+                .ok()
+                .filter(|precision| *precision <= 10)
+                .ok_or_else(|| {
+                    AppError::Config(format!("invalid ROUND_TOTAL_PRECISION: {}", precision))
+                })?,
+        };
+        Ok(TotalRounding { mode, precision })
+    }
+
+    pub fn apply(self, total: Decimal) -> Decimal {
+        let strategy = match self.mode {
+            RoundingMode::Nearest => RoundingStrategy::MidpointAwayFromZero,
+            RoundingMode::Up => RoundingStrategy::ToPositiveInfinity,
+            RoundingMode::Down => RoundingStrategy::ToNegativeInfinity,
+        };
+        total.round_dp_with_strategy(self.precision, strategy)
+    }
+}
+
+/// Breakdown dimensions selected by `GROUP_BY`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GroupBy {
+    /// `service`: per service, from CloudWatch
+    Service,
+    /// `service,region`: per service and region, from Cost Explorer since `AWS/Billing`
+    /// metrics carry no region
+    ServiceAndRegion,
+    /// `region`: per region, from Cost Explorer, for totals by region
+    Region,
+    /// `account,service`: per service of each linked account of an organization, from Cost
+    /// Explorer. Slack gets a section per account
+    AccountAndService,
+}
+
+impl GroupBy {
+    pub fn parse(group_by: Option<&str>) -> Result<Self, AppError> {
+        let keys: Vec<&str> = group_by
+            .unwrap_or("service")
+            .split(',')
+            .map(str::trim)
+            .collect();
+        match keys.as_slice() {
+            ["service"] => Ok(GroupBy::Service),
+            ["service", "region"] => Ok(GroupBy::ServiceAndRegion),
+            ["region"] => Ok(GroupBy::Region),
+            ["account", "service"] => Ok(GroupBy::AccountAndService),
+            _ => Err(AppError::Config(format!(
+                "invalid GROUP_BY: {}",
+                group_by.unwrap_or_default()
+            ))),
+        }
+    }
+}
+
+/// Language of the report text, selected by `LANGUAGE`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Language {
+    /// `ja`
+    #[default]
+    Ja,
+    /// `en`, which also writes amounts to the cent with thousands separators: "$1,234.50"
+    En,
+}
+
+impl Language {
+    pub fn parse(language: Option<&str>) -> Result<Self, AppError> {
+        match language.map(str::trim) {
+            None | Some("") | Some("ja") => Ok(Language::Ja),
+            Some("en") => Ok(Language::En),
+            Some(other) => Err(AppError::Config(format!("invalid LANGUAGE: {}", other))),
+        }
+    }
+
+    /// `ja` or `en`, whichever is in this language.
+    pub fn pick<'a>(self, ja: &'a str, en: &'a str) -> &'a str {
+        match self {
+            Language::Ja => ja,
+            Language::En => en,
+        }
+    }
+}
+
+/// A statistic of `EstimatedCharges` over the lookback, listed in `BILLING_STATISTIC`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BillingStatistic {
+    /// `maximum`: the month-to-date charges, which the total always is
+    Maximum,
+    /// `average`
+    Average,
+    /// `minimum`
+    Minimum,
+}
+
+impl BillingStatistic {
+    /// Comma separated, e.g. `maximum,average`, kept in the order given.
+    pub fn parse_list(list: Option<&str>) -> Result<Vec<Self>, AppError> {
+        let mut statistics = vec![];
+        for name in list.unwrap_or("maximum").split(',').map(str::trim) {
+            let statistic = match name {
+                "maximum" => BillingStatistic::Maximum,
+                "average" => BillingStatistic::Average,
+                "minimum" => BillingStatistic::Minimum,
+                other => {
+                    return Err(AppError::Config(format!(
+                        "invalid BILLING_STATISTIC: {}",
+                        other
+                    )))
+                }
+            };
+            if !statistics.contains(&statistic) {
+                statistics.push(statistic);
+            }
+        }
+        Ok(statistics)
+    }
+}
+
+/// Spending summary over a longer window, selected by `DIGEST_PERIOD`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DigestPeriod {
+    /// `week`: the last 7 days
+    Week,
+    /// `month`: the last 30 days
+    Month,
+}
+
+impl DigestPeriod {
+    pub fn parse(period: Option<&str>) -> Result<Option<Self>, AppError> {
+        match period.map(str::trim) {
+            None | Some("") => Ok(None),
+            Some("week") => Ok(Some(DigestPeriod::Week)),
+            Some("month") => Ok(Some(DigestPeriod::Month)),
+            Some(other) => Err(AppError::Config(format!(
+                "invalid DIGEST_PERIOD: {}",
+                other
+            ))),
+        }
+    }
+
+    pub fn window(self) -> Duration {
+        match self {
+            DigestPeriod::Week => Duration::days(7),
+            DigestPeriod::Month => Duration::days(30),
+        }
+    }
+}
+
+/// Where the report can be delivered, as listed in `NOTIFY_CHANNELS`. Apart from
+/// `generic-webhook`, each needs the cargo feature of the same name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Channel {
+    /// `slack`: the Slack webhook in SSM
+    #[cfg(feature = "slack")]
+    Slack,
+    /// `slack-bot`: `chat.postMessage` to `SLACK_CHANNEL` with the bot token in SSM, which can
+    /// also schedule the message for `SLACK_POST_AT`
+    #[cfg(feature = "slack")]
+    SlackBot,
+    /// `generic-webhook`: plain JSON to the webhook in SSM
+    GenericWebhook,
+    /// `sns`: a plain-text message to `SNS_TOPIC_ARN`
+    #[cfg(feature = "sns")]
+    Sns,
+    /// `ses`: a plain-text email from `SES_FROM` to `SES_TO`
+    #[cfg(feature = "ses")]
+    Ses,
+    /// `teams`: a message card to the Microsoft Teams webhook in SSM
+    #[cfg(feature = "teams")]
+    Teams,
+}
+
+/// Channels behind a cargo feature named like them.
+const FEATURE_CHANNELS: &[&str] = &["slack", "sns", "ses", "teams"];
+
+impl Channel {
+    pub fn parse_list(channels: &str) -> Result<Vec<Self>, AppError> {
+        channels
+            .split(',')
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .map(|c| match c {
+                #[cfg(feature = "slack")]
+                "slack" => Ok(Channel::Slack),
+                #[cfg(feature = "slack")]
+                "slack-bot" => Ok(Channel::SlackBot),
+                "generic-webhook" => Ok(Channel::GenericWebhook),
+                #[cfg(feature = "sns")]
+                "sns" => Ok(Channel::Sns),
+                #[cfg(feature = "ses")]
+                "ses" => Ok(Channel::Ses),
+                #[cfg(feature = "teams")]
+                "teams" => Ok(Channel::Teams),
+                other if FEATURE_CHANNELS.contains(&other) => Err(AppError::Config(format!(
+                    "NOTIFY_CHANNELS entry {} needs the {} feature, which this build lacks",
+                    other, other
+                ))),
+                other => Err(AppError::Config(format!(
+                    "invalid NOTIFY_CHANNELS entry: {}",
+                    other
+                ))),
+            })
+            .collect()
+    }
+
+    /// Whether the channel posts to the webhook URL stored in SSM.
+    pub fn uses_webhook(self) -> bool {
+        match self {
+            #[cfg(feature = "slack")]
+            Channel::Slack => true,
+            #[cfg(feature = "slack")]
+            Channel::SlackBot => false,
+            Channel::GenericWebhook => true,
+            #[cfg(feature = "sns")]
+            Channel::Sns => false,
+            #[cfg(feature = "ses")]
+            Channel::Ses => false,
+            #[cfg(feature = "teams")]
+            Channel::Teams => true,
+        }
+    }
+}
+
+/// How the channels in `NOTIFY_CHANNELS` are used, selected by `NOTIFY_MODE`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    /// `all`: every channel gets the report
+    All,
+    /// `failover`: channels are tried in order until one delivers
+    Failover,
+}
+
+/// Deployment settings, from environment variables:
+///
+/// * `WEBHOOK_URL`: the webhook to post to, instead of the one in SSM Parameter Store
+/// * `AWS_REGION`: where the function runs, set by Lambda, for console links of the right
+///   partition (default none)
+/// * `BILLING_CURRENCY`: currency of the `EstimatedCharges` metrics to report (default `USD`)
+/// * `INCLUDE_CURRENCY_DIMENSION`: `false` queries the metrics without the `Currency`
+///   dimension, for custom namespaces whose metrics lack it; `BILLING_CURRENCY` then only labels
+///   the figures (default `true`)
+/// * `CONVERT_TO_CURRENCY`: currency code, e.g. `JPY`, the Slack message also shows the total
+///   in, like "($1234 / ¥190,000)" (default none)
+/// * `EXCHANGE_RATE`: units of `CONVERT_TO_CURRENCY` per unit of `BILLING_CURRENCY`, e.g. `154.2`
+/// * `EXCHANGE_RATE_URL`: endpoint to fetch the rate from each run instead, with `{from}` and
+///   `{to}` replaced by the currency codes; see [`crate::exchange::fetch_rate`]. When it fails
+///   the conversion is left out
+/// * `GROUP_BY`: see [`GroupBy`] (default `service`)
+/// * `PER_SERVICE_ALERT_THRESHOLD`: highlight services costing more than this (default off)
+/// * `SHOW_TIMESTAMP`: `false` hides the generated-at footer (default on)
+/// * `SHOW_REQUEST_ID`: `true` adds the Lambda request id to the footer, to find the logs of the
+///   run behind a message (default off)
+/// * `GENERIC_WEBHOOK`: `true` is a shorthand for `NOTIFY_CHANNELS=generic-webhook`
+/// * `STALENESS_WARN_HOURS`: warn when the billing data is older than this (default 24)
+/// * `SLACK_PAGINATE`: `true` splits a long breakdown over several Slack messages, each within
+///   50 services and `SLACK_MAX_BYTES` (default off)
+/// * `SLACK_MAX_BYTES`: largest Slack message, as serialized JSON; the cheapest services are
+///   left out of a larger one that isn't split, noting how many (default `40000`)
+/// * `SLACK_COMPATIBLE_WEBHOOK`: `true` accepts any https webhook for the `slack` channel, for
+///   Slack-compatible endpoints like Mattermost (default off:
+///   `https://hooks.slack.com/services/...`)
+/// * `SLACK_WEBHOOK_HOST`: host of the Slack incoming webhooks, e.g. `hooks.slack-gov.com` for
+///   GovSlack (default `hooks.slack.com`)
+/// * `MESSAGE_FORMAT`: see [`MessageFormat`] (default `fields`). `MESSAGE_STYLE` is read when it
+///   isn't set
+/// * `MARKDOWN_ONLY`: `true` with `MESSAGE_FORMAT=markdown` only returns the Markdown, without
+///   notifying any channel (default off)
+/// * `LANGUAGE`: see [`Language`] (default `ja`)
+/// * `ATTACHMENT_TITLE`: title of the Slack attachment holding the breakdown, empty for none
+///   (default `サービス別内訳`, or `Breakdown by service` in English)
+/// * `MESSAGE_TEMPLATE_PARAMETER`: SSM parameter holding a Tera template of the Slack message
+///   text, e.g. `/billing-notification/message-template`, replacing the built-in wording; see
+///   `templates/` for examples (default none)
+/// * `ATTACHMENT_TEMPLATE_PARAMETER`: likewise for the text of the attachment, which then
+///   replaces the breakdown fields and isn't paginated (default none)
+/// * `CONSOLE_LINKS`: `true` links each service's cost to it in Cost Explorer, where the
+///   service is known (default off)
+/// * `CLOUDWATCH_MAX_CONCURRENCY`: most CloudWatch calls in flight at once (default 5)
+/// * `CLOUDWATCH_MAX_RPS`: most CloudWatch calls started per second, `0` for no limit
+///   (default 10)
+/// * `REQUIRE_DATA`: `true` fails the run when CloudWatch has no datapoints for the total,
+///   instead of reporting 0 (default off)
+/// * `SKIP_SERVICES_WHEN_ZERO`: `true` skips the per-service CloudWatch queries when the total
+///   is zero, since every service would be too, and reports just the total (default off)
+/// * `HANDLER_RETRY`: `true` runs a failed stage of the collection (the total, the service list,
+///   the per-service costs) once more, keeping what was already fetched (default off)
+/// * `AWS_TIMEOUT_SECS`: longest a single AWS call may take (default 10)
+/// * `NOTIFY_TIMEOUT_SECS`: longest a notification channel may take, or a single attempt of a
+///   Slack message, which is retried (default 5)
+/// * `NOTIFY_ONLY_ON_CHANGE`: `true` skips the report when the total moved less than
+///   `MIN_CHANGE` since the last one sent (default off)
+/// * `MIN_CHANGE`: see [`MinChange`] (default `0.01`)
+/// * `NOTIFY_MIN_TOTAL`: total below which the report is held back, e.g. `10` for a sandbox
+///   account (default none)
+/// * `NOTIFY_FORCE_DAY`: day of the month, in `BILLING_TIMEZONE`, the report goes out even below
+///   `NOTIFY_MIN_TOTAL`, e.g. `1`, so a quiet account still shows the notifier works (default
+///   none)
+/// * `SKIP_UNCHANGED`: `true` skips a report whose total and breakdown are the same as the
+///   last one sent, e.g. while the billing data is delayed (default off)
+/// * `SKIP_UNCHANGED_MAX_HOURS`: hours after which an unchanged report is sent anyway (default
+///   none, never)
+/// * `SHOW_RANK_CHANGES`: `true` marks each service with how many places it moved in the
+///   ranking since the last report sent, like "↑2", "↓1", "=" or "NEW" (default off)
+/// * `DISCREPANCY_TOLERANCE`: how far the breakdown may add up short of (or over) the total
+///   before the report notes it, as an amount or a percentage of the total (default `1`)
+/// * `SERVICE_ROLLUP`: see [`ServiceRollup`] (default none)
+/// * `ROUND_TOTAL`, `ROUND_TOTAL_PRECISION`: see [`TotalRounding`] (default `nearest`, `2`)
+/// * `SHOW_CHANGES`: `true` shows how each service's cost moved over the last 24 hours, at the
+///   cost of a second CloudWatch query per service; not with a Cost Explorer breakdown (default
+///   off)
+/// * `TOP_INCREASES_FLOOR`: with `SHOW_CHANGES=true`, the 3 services that grew the most are
+///   listed when the largest increase is over this amount (default `1`)
+/// * `HYBRID`: `true` takes the breakdown from Cost Explorer while the total still comes from
+///   CloudWatch; see `DISCREPANCY_TOLERANCE` for when they disagree (default off)
+/// * `SHOW_FORECAST`: `true` shows where the 3 most expensive services are heading by the end of
+///   the month, from Cost Explorer; each forecast is a billed API call (default off)
+/// * `SHOW_SPARKLINE`: `true` puts a sparkline of the last 7 days' spend, e.g. `▂▃▁▅▇▆▅`, after
+///   the total, at the cost of one more CloudWatch query (default off)
+/// * `HIDE_NEGATIVE`: `true` leaves credits (services with a negative cost) out of the
+///   breakdown; the total still includes them (default off)
+/// * `HIDE_FROM_BREAKDOWN`: comma separated service names, e.g. `Tax`, left out of the breakdown
+///   but not the total, which the footer then notes as "(Tax含む)" (default none)
+/// * `OTHER_THRESHOLD_PERCENT`: services below this share of the breakdown are summed up as
+///   one "その他" entry, e.g. `1` (default off)
+/// * `ACCOUNT_MIN_SHARE_PERCENT`: with `GROUP_BY=account,service`, accounts below this share of
+///   the total are combined into one section in Slack, e.g. `5` (default off)
+/// * `ACCOUNT_NAMES`: JSON object of account ids to the names their sections are titled with,
+///   e.g. `{"111111111111": "production"}`; other accounts show their id (default none)
+/// * `ACCOUNT_NAME`: name the account is shown by, for one without an alias or to override it
+///   (default the account alias, where the function may list it)
+/// * `FREE_TIER_HINTS`: JSON object of service names to a note shown by their cost once they
+///   cost anything, e.g. `{"AmazonEC2": "750時間/月まで無料"}` (default none)
+/// * `SHOW_USAGE`: `true` adds usage quantities to the per-service breakdown, from Cost Explorer
+///   (default off)
+/// * `DIGEST_PERIOD`: see [`DigestPeriod`] (default off)
+/// * `BILLING_STATISTIC`: comma separated [`BillingStatistic`]s of the total to show, e.g.
+///   `maximum,average` for "最大 $1234 / 平均 $900" under it. The total itself stays the maximum
+///   (default `maximum`)
+/// * `BILLING_ALERT_THRESHOLD`: total above which the message mentions `ALERT_MENTION`
+///   (default off)
+/// * `MONTHLY_BUDGET`: budget the month-to-date total is shown against as a progress bar
+///   (default none)
+/// * `BILLING_WARNING_THRESHOLD`: total above which the message is marked as a warning, below
+///   `BILLING_ALERT_THRESHOLD` (default off)
+/// * `SPEND_BUCKETS`: see [`SpendBuckets`]; marks the Slack message with the emoji and color of
+///   the tier of the total instead of the warning and alert thresholds (default none)
+/// * `ALERT_MENTION`: Slack mention such as `<!subteam^S123>` or `<@U123>` (default none)
+/// * `EXCLUDE_TYPES`: comma separated Cost Explorer record types, e.g. `Credit,Refund,Tax`, left
+///   out of the total and the breakdown (default none). Only applies when the breakdown comes
+///   from Cost Explorer (`GROUP_BY` other than `service`, or `SHOW_USAGE=true`); `EstimatedCharges`
+///   can't be filtered by record type.
+/// * `EMIT_METRICS`: `true` writes run metrics in CloudWatch Embedded Metric Format (default off)
+/// * `ALIGN_TO_CALENDAR_DAY`: `true` starts the lookback at midnight instead of 24 hours (or 7
+///   days) ago, so "today" matches the billing console (default off)
+/// * `MTD`: `true` queries the total from midnight on the first of the month instead of over
+///   the lookback, so it matches the month-to-date figure of the billing console; overrides
+///   `ALIGN_TO_CALENDAR_DAY` (default off)
+/// * `BILLING_TIMEZONE`: IANA name of the timezone midnight is in, e.g. `Asia/Tokyo` (default
+///   `UTC`)
+/// * `BILLING_NAMESPACE`: comma separated CloudWatch namespaces to combine, e.g.
+///   `AWS/Billing,Custom/Costs` for metrics streamed in alongside AWS's (default `AWS/Billing`)
+/// * `NOTIFY_CHANNELS`: comma separated [`Channel`]s in priority order (default `slack`, or
+///   `generic-webhook` with `GENERIC_WEBHOOK=true`)
+/// * `NOTIFY_MODE`: see [`Delivery`] (default `all`)
+/// * `SLACK_CHANNEL`: channel id (or name) the `slack-bot` channel posts to
+/// * `SLACK_POST_AT`: local time in `BILLING_TIMEZONE`, e.g. `09:00`, the `slack-bot` channel
+///   schedules the message for instead of posting it right away; a run at or after that time
+///   posts right away. Incoming webhooks can't schedule, so this needs `slack-bot` (default
+///   none)
+/// * `SNS_TOPIC_ARN`: topic of the `sns` channel
+/// * `SES_FROM`, `SES_TO`: sender and comma separated recipients of the `ses` channel
+/// * `HISTORY_TABLE`: DynamoDB table each report is also stored in (default none)
+/// * `S3_REPORT_BUCKET`: bucket to also put each report in, as `<prefix>YYYY/MM/DD.json` dated
+///   in `BILLING_TIMEZONE` (default none)
+/// * `S3_REPORT_PREFIX`: prefix of the report keys, e.g. `billing/` (default none)
+/// * `S3_REPORT_CSV`: `true` puts a `.csv` of the breakdown next to the JSON (default off)
+/// * `ATTACH_CHART`: `true` shows a bar chart of the month's daily spend in the Slack message,
+///   put in `S3_REPORT_BUCKET` as a `.png` next to the report (default off)
+/// * `CHART_DAYS`: days the chart covers instead of the month so far, from 2 to 31; the chart is
+///   left out while CloudWatch has fewer than 2 of them
+pub struct Config {
+    pub webhook_url: Option<Secret<String>>,
+    pub region: Option<String>,
+    pub currency: String,
+    pub include_currency_dimension: bool,
+    pub convert_to_currency: Option<String>,
+    pub exchange_rate: Option<Decimal>,
+    pub exchange_rate_url: Option<String>,
+    pub group_by: GroupBy,
+    pub service_threshold: Option<Decimal>,
+    pub show_timestamp: bool,
+    pub show_request_id: bool,
+    pub staleness_threshold: Duration,
+    pub slack_paginate: bool,
+    pub slack_max_bytes: usize,
+    pub slack_compatible_webhook: bool,
+    pub slack_webhook_host: String,
+    pub message_format: MessageFormat,
+    pub markdown_only: bool,
+    pub console_links: bool,
+    pub language: Language,
+    pub attachment_title: String,
+    pub message_template_parameter: Option<String>,
+    pub attachment_template_parameter: Option<String>,
+    pub cloudwatch_max_concurrency: usize,
+    pub cloudwatch_max_rps: f64,
+    pub require_data: bool,
+    pub skip_services_when_zero: bool,
+    pub aws_timeout: StdDuration,
+    pub handler_retry: bool,
+    pub notify_timeout: StdDuration,
+    pub notify_only_on_change: bool,
+    pub notify_min_total: Option<Decimal>,
+    pub skip_unchanged: bool,
+    pub skip_unchanged_max_age: Option<Duration>,
+    pub notify_force_day: Option<u32>,
+    pub show_rank_changes: bool,
+    pub min_change: MinChange,
+    pub discrepancy_tolerance: MinChange,
+    pub service_rollup: Vec<ServiceRollup>,
+    pub total_rounding: TotalRounding,
+    pub free_tier_hints: BTreeMap<String, String>,
+    pub account_names: BTreeMap<String, String>,
+    pub show_changes: bool,
+    pub increase_floor: Decimal,
+    pub show_forecast: bool,
+    pub show_sparkline: bool,
+    pub hybrid: bool,
+    pub hide_negative: bool,
+    pub hide_from_breakdown: Vec<String>,
+    pub other_threshold: Option<Decimal>,
+    pub account_min_share: Option<Decimal>,
+    pub account_name: Option<String>,
+    pub show_usage: bool,
+    pub digest_period: Option<DigestPeriod>,
+    pub billing_statistics: Vec<BillingStatistic>,
+    pub alert_threshold: Option<Decimal>,
+    pub warning_threshold: Option<Decimal>,
+    pub monthly_budget: Option<Decimal>,
+    pub alert_mention: Option<String>,
+    pub spend_buckets: Option<SpendBuckets>,
+    pub exclude_types: Vec<String>,
+    pub emit_metrics: bool,
+    pub align_to_calendar_day: bool,
+    pub month_to_date: bool,
+    pub timezone: Tz,
+    pub namespaces: Vec<String>,
+    pub channels: Vec<Channel>,
+    pub slack_channel: Option<String>,
+    pub slack_post_at: Option<NaiveTime>,
+    pub delivery: Delivery,
+    pub sns_topic_arn: Option<String>,
+    pub ses_from: Option<String>,
+    pub ses_to: Vec<String>,
+    pub history_table: Option<String>,
+    pub s3_report_bucket: Option<String>,
+    pub s3_report_prefix: String,
+    pub s3_report_csv: bool,
+    pub attach_chart: bool,
+    pub chart_days: Option<i64>,
+}
+
+impl Config {
+    /// Whether the breakdown comes from Cost Explorer rather than CloudWatch.
+    pub fn uses_cost_explorer(&self) -> bool {
+        self.hybrid || self.show_usage || self.group_by != GroupBy::Service
+    }
+
+    /// The host the webhook must be a Slack incoming webhook on, when it must be one.
+    pub fn slack_webhook_host(&self) -> Option<&str> {
+        #[cfg(feature = "slack")]
+        return (!self.slack_compatible_webhook && self.channels.contains(&Channel::Slack))
+            .then_some(self.slack_webhook_host.as_str());
+        #[cfg(not(feature = "slack"))]
+        return None;
+    }
+
+    /// Whether a channel posts with the Slack bot token in SSM.
+    pub fn uses_slack_bot(&self) -> bool {
+        #[cfg(feature = "slack")]
+        return self.channels.contains(&Channel::SlackBot);
+        #[cfg(not(feature = "slack"))]
+        return false;
+    }
+
+    /// Reads and validates every setting. All problems are reported in one error rather than
+    /// just the first, so a misconfigured deployment can be fixed in one go.
+    pub fn from_env() -> Result<Self, AppError> {
+        Self::from_lookup(|name| env::var(name).ok())
+    }
+
+    /// [`Self::from_env`], with the variables looked up in `lookup` instead, e.g. in a map of
+    /// them in tests.
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, AppError> {
+        let var = |name: &str| lookup(name).ok_or(env::VarError::NotPresent);
+        let mut problems = vec![];
+        let service_threshold = match var("PER_SERVICE_ALERT_THRESHOLD") {
+            Ok(value) => value
+                .parse::<Decimal>()
+                .map_err(|err| {
+                    problems.push(format!("invalid PER_SERVICE_ALERT_THRESHOLD: {}", err))
+                })
+                .ok(),
+            Err(_) => None,
+        };
+        let alert_threshold = match var("BILLING_ALERT_THRESHOLD") {
+            Ok(value) => value
+                .parse::<Decimal>()
+                .map_err(|err| problems.push(format!("invalid BILLING_ALERT_THRESHOLD: {}", err)))
+                .ok(),
+            Err(_) => None,
+        };
+        let other_threshold = match var("OTHER_THRESHOLD_PERCENT") {
+            Ok(value) => value
+                .parse::<Decimal>()
+                .ok()
+                .filter(|percent| *percent > Decimal::ZERO && *percent <= Decimal::ONE_HUNDRED)
+                .or_else(|| {
+                    problems.push(format!("invalid OTHER_THRESHOLD_PERCENT: {}", value));
+                    None
+                }),
+            Err(_) => None,
+        };
+        let account_min_share = match var("ACCOUNT_MIN_SHARE_PERCENT") {
+            Ok(value) => value
+                .parse::<Decimal>()
+                .ok()
+                .filter(|percent| *percent > Decimal::ZERO && *percent <= Decimal::ONE_HUNDRED)
+                .or_else(|| {
+                    problems.push(format!("invalid ACCOUNT_MIN_SHARE_PERCENT: {}", value));
+                    None
+                }),
+            Err(_) => None,
+        };
+        let increase_floor = match var("TOP_INCREASES_FLOOR") {
+            Ok(value) => value
+                .parse::<Decimal>()
+                .ok()
+                .filter(|floor| *floor >= Decimal::ZERO)
+                .unwrap_or_else(|| {
+                    problems.push(format!("invalid TOP_INCREASES_FLOOR: {}", value));
+                    Decimal::ONE
+                }),
+            Err(_) => Decimal::ONE,
+        };
+        let message_template_parameter = var("MESSAGE_TEMPLATE_PARAMETER")
+            .ok()
+            .filter(|v| !v.is_empty());
+        let attachment_template_parameter = var("ATTACHMENT_TEMPLATE_PARAMETER")
+            .ok()
+            .filter(|v| !v.is_empty());
+        if !cfg!(feature = "templates")
+            && (message_template_parameter.is_some() || attachment_template_parameter.is_some())
+        {
+            problems.push(
+                "MESSAGE_TEMPLATE_PARAMETER and ATTACHMENT_TEMPLATE_PARAMETER need the templates \
+                 feature, which this build lacks"
+                    .to_string(),
+            );
+        }
+        let notify_min_total = match var("NOTIFY_MIN_TOTAL") {
+            Ok(value) => value
+                .parse::<Decimal>()
+                .map_err(|err| problems.push(format!("invalid NOTIFY_MIN_TOTAL: {}", err)))
+                .ok(),
+            Err(_) => None,
+        };
+        let notify_force_day = match var("NOTIFY_FORCE_DAY") {
+            Ok(value) => value
+                .parse::<u32>()
+                .ok()
+                .filter(|day| (1..=31).contains(day))
+                .or_else(|| {
+                    problems.push(format!("invalid NOTIFY_FORCE_DAY: {}", value));
+                    None
+                }),
+            Err(_) => None,
+        };
+        let spend_buckets = match var("SPEND_BUCKETS") {
+            Ok(value) if !value.trim().is_empty() => SpendBuckets::parse(&value)
+                .map_err(|err| problems.push(problem(err)))
+                .ok(),
+            _ => None,
+        };
+        let skip_unchanged_max_age = match var("SKIP_UNCHANGED_MAX_HOURS") {
+            Ok(value) => value
+                .parse::<i64>()
+                .ok()
+                .filter(|hours| *hours > 0)
+                .map(Duration::hours)
+                .or_else(|| {
+                    problems.push(format!("invalid SKIP_UNCHANGED_MAX_HOURS: {}", value));
+                    None
+                }),
+            Err(_) => None,
+        };
+        let convert_to_currency = var("CONVERT_TO_CURRENCY")
+            .ok()
+            .map(|v| v.trim().to_ascii_uppercase())
+            .filter(|v| !v.is_empty());
+        let exchange_rate = match var("EXCHANGE_RATE") {
+            Ok(value) => value
+                .parse::<Decimal>()
+                .ok()
+                .filter(|rate| *rate > Decimal::ZERO)
+                .or_else(|| {
+                    problems.push(format!("invalid EXCHANGE_RATE: {}", value));
+                    None
+                }),
+            Err(_) => None,
+        };
+        let exchange_rate_url = var("EXCHANGE_RATE_URL").ok().filter(|v| !v.is_empty());
+        if convert_to_currency.is_some() && exchange_rate.is_none() && exchange_rate_url.is_none() {
+            problems
+                .push("CONVERT_TO_CURRENCY needs EXCHANGE_RATE or EXCHANGE_RATE_URL".to_string());
+        }
+        let monthly_budget = match var("MONTHLY_BUDGET") {
+            Ok(value) => value
+                .parse::<Decimal>()
+                .ok()
+                .filter(|budget| *budget > Decimal::ZERO)
+                .or_else(|| {
+                    problems.push(format!("invalid MONTHLY_BUDGET: {}", value));
+                    None
+                }),
+            Err(_) => None,
+        };
+        let warning_threshold = match var("BILLING_WARNING_THRESHOLD") {
+            Ok(value) => value
+                .parse::<Decimal>()
+                .map_err(|err| problems.push(format!("invalid BILLING_WARNING_THRESHOLD: {}", err)))
+                .ok(),
+            Err(_) => None,
+        };
+        let staleness_hours = match var("STALENESS_WARN_HOURS") {
+            Ok(value) => value
+                .parse::<i64>()
+                .map_err(|err| problems.push(format!("invalid STALENESS_WARN_HOURS: {}", err)))
+                .unwrap_or(24),
+            Err(_) => 24,
+        };
+        let slack_max_bytes = match var("SLACK_MAX_BYTES") {
+            Ok(value) => value
+                .parse::<usize>()
+                .ok()
+                .filter(|bytes| *bytes > 0)
+                .unwrap_or_else(|| {
+                    problems.push(format!("invalid SLACK_MAX_BYTES: {}", value));
+                    SLACK_MAX_BYTES
+                }),
+            Err(_) => SLACK_MAX_BYTES,
+        };
+        let timezone = match var("BILLING_TIMEZONE") {
+            Ok(value) => value
+                .parse::<Tz>()
+                .map_err(|err| problems.push(format!("invalid BILLING_TIMEZONE: {}", err)))
+                .unwrap_or(Tz::UTC),
+            Err(_) => Tz::UTC,
+        };
+        let group_by = GroupBy::parse(var("GROUP_BY").ok().as_deref())
+            .map_err(|err| problems.push(problem(err)))
+            .unwrap_or(GroupBy::Service);
+        let message_format = var("MESSAGE_FORMAT").or_else(|_| var("MESSAGE_STYLE")).ok();
+        let message_format = MessageFormat::parse(message_format.as_deref())
+            .map_err(|err| problems.push(problem(err)))
+            .unwrap_or(MessageFormat::Fields);
+        let slack_webhook_host = match var("SLACK_WEBHOOK_HOST") {
+            Ok(host) if host.trim().is_empty() => "hooks.slack.com".to_string(),
+            // a bare host, which the URL's is compared to
+            Ok(host) if host.contains(|c: char| c == '/' || c == ':' || c.is_whitespace()) => {
+                problems.push(format!("invalid SLACK_WEBHOOK_HOST: {}", host));
+                "hooks.slack.com".to_string()
+            }
+            Ok(host) => host.to_ascii_lowercase(),
+            Err(_) => "hooks.slack.com".to_string(),
+        };
+        let language = Language::parse(var("LANGUAGE").ok().as_deref())
+            .map_err(|err| problems.push(problem(err)))
+            .unwrap_or_default();
+        let cloudwatch_max_concurrency = match var("CLOUDWATCH_MAX_CONCURRENCY") {
+            Ok(value) => value
+                .parse::<usize>()
+                .ok()
+                .filter(|n| *n > 0)
+                .unwrap_or_else(|| {
+                    problems.push(format!("invalid CLOUDWATCH_MAX_CONCURRENCY: {}", value));
+                    5
+                }),
+            Err(_) => 5,
+        };
+        let cloudwatch_max_rps = match var("CLOUDWATCH_MAX_RPS") {
+            Ok(value) => value
+                .parse::<f64>()
+                .ok()
+                .filter(|rps| rps.is_finite() && *rps >= 0.0)
+                .unwrap_or_else(|| {
+                    problems.push(format!("invalid CLOUDWATCH_MAX_RPS: {}", value));
+                    10.0
+                }),
+            Err(_) => 10.0,
+        };
+        let aws_timeout = parse_timeout(&lookup, "AWS_TIMEOUT_SECS", 10, &mut problems);
+        let notify_timeout = parse_timeout(&lookup, "NOTIFY_TIMEOUT_SECS", 5, &mut problems);
+        let min_change = MinChange::parse(var("MIN_CHANGE").ok().as_deref())
+            .map_err(|err| problems.push(problem(err)))
+            .unwrap_or(MinChange::Absolute(Decimal::ZERO));
+        let service_rollup = ServiceRollup::parse_map(var("SERVICE_ROLLUP").ok().as_deref())
+            .map_err(|err| problems.push(problem(err)))
+            .unwrap_or_default();
+        let free_tier_hints = match var("FREE_TIER_HINTS") {
+            Ok(value) if !value.trim().is_empty() => {
+                serde_json::from_str(&value).unwrap_or_else(|err| {
+                    problems.push(format!(
+                        "invalid FREE_TIER_HINTS: expected a JSON object of service names to \
+                         notes: {}",
+                        err
+                    ));
+                    BTreeMap::new()
+                })
+            }
+            _ => BTreeMap::new(),
+        };
+        let account_names = match var("ACCOUNT_NAMES") {
+            Ok(value) if !value.trim().is_empty() => {
+                serde_json::from_str(&value).unwrap_or_else(|err| {
+                    problems.push(format!(
+                        "invalid ACCOUNT_NAMES: expected a JSON object of account ids to names: {}",
+                        err
+                    ));
+                    BTreeMap::new()
+                })
+            }
+            _ => BTreeMap::new(),
+        };
+        let discrepancy_tolerance = MinChange::parse_var(
+            "DISCREPANCY_TOLERANCE",
+            var("DISCREPANCY_TOLERANCE").ok().as_deref(),
+            MinChange::Absolute(Decimal::ONE),
+        )
+        .map_err(|err| problems.push(problem(err)))
+        .unwrap_or(MinChange::Absolute(Decimal::ONE));
+        let total_rounding = TotalRounding::parse(
+            var("ROUND_TOTAL").ok().as_deref(),
+            var("ROUND_TOTAL_PRECISION").ok().as_deref(),
+        )
+        .map_err(|err| problems.push(problem(err)))
+        .unwrap_or_default();
+        let digest_period = DigestPeriod::parse(var("DIGEST_PERIOD").ok().as_deref())
+            .map_err(|err| problems.push(problem(err)))
+            .unwrap_or(None);
+        let billing_statistics =
+            BillingStatistic::parse_list(var("BILLING_STATISTIC").ok().as_deref())
+                .map_err(|err| problems.push(problem(err)))
+                .unwrap_or_else(|_| vec![BillingStatistic::Maximum]);
+        let namespaces: Vec<String> = match var("BILLING_NAMESPACE") {
+            Ok(value) => value
+                .split(',')
+                .map(str::trim)
+                .filter(|namespace| !namespace.is_empty())
+                .map(str::to_string)
+                .collect(),
+            Err(_) => vec![BILLING_NAMESPACE.to_string()],
+        };
+        if namespaces.is_empty() {
+            problems.push("BILLING_NAMESPACE is empty".to_string());
+        }
+        let generic_webhook = var("GENERIC_WEBHOOK").is_ok_and(|v| v == "true");
+        let channels = match var("NOTIFY_CHANNELS") {
+            Ok(value) => match Channel::parse_list(&value) {
+                Ok(channels) if channels.is_empty() => {
+                    problems.push("NOTIFY_CHANNELS is empty".to_string());
+                    channels
+                }
+                Ok(channels) => channels,
+                Err(err) => {
+                    problems.push(problem(err));
+                    vec![]
+                }
+            },
+            Err(_) if generic_webhook => vec![Channel::GenericWebhook],
+            #[cfg(feature = "slack")]
+            Err(_) => vec![Channel::Slack],
+            #[cfg(not(feature = "slack"))]
+            Err(_) => {
+                problems.push("NOTIFY_CHANNELS is required without the slack feature".to_string());
+                vec![]
+            }
+        };
+        let delivery = match var("NOTIFY_MODE").as_deref() {
+            Ok("all") | Err(_) => Delivery::All,
+            Ok("failover") => Delivery::Failover,
+            Ok(other) => {
+                problems.push(format!("invalid NOTIFY_MODE: {}", other));
+                Delivery::All
+            }
+        };
+        let slack_channel = var("SLACK_CHANNEL").ok().filter(|v| !v.is_empty());
+        #[cfg(feature = "slack")]
+        let uses_slack_bot = channels.contains(&Channel::SlackBot);
+        #[cfg(not(feature = "slack"))]
+        let uses_slack_bot = false;
+        if uses_slack_bot && slack_channel.is_none() {
+            problems.push("the slack-bot channel needs SLACK_CHANNEL".to_string());
+        }
+        let slack_post_at = match var("SLACK_POST_AT") {
+            Ok(value) if !uses_slack_bot => {
+                problems.push(format!(
+                    "SLACK_POST_AT={} needs the slack-bot channel, since webhooks can't schedule \
+                     messages",
+                    value
+                ));
+                None
+            }
+            Ok(value) => NaiveTime::parse_from_str(&value, "%H:%M")
+                .map_err(|_| problems.push(format!("invalid SLACK_POST_AT: {}", value)))
+                .ok(),
+            Err(_) => None,
+        };
+        let sns_topic_arn = var("SNS_TOPIC_ARN").ok().filter(|v| !v.is_empty());
+        #[cfg(feature = "sns")]
+        if channels.contains(&Channel::Sns) && sns_topic_arn.is_none() {
+            problems.push("the sns channel needs SNS_TOPIC_ARN".to_string());
+        }
+        let ses_from = var("SES_FROM").ok().filter(|v| !v.is_empty());
+        let ses_to: Vec<String> = var("SES_TO")
+            .map(|v| {
+                v.split(',')
+                    .map(str::trim)
+                    .filter(|to| !to.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+        #[cfg(feature = "ses")]
+        if channels.contains(&Channel::Ses) && (ses_from.is_none() || ses_to.is_empty()) {
+            problems.push("the ses channel needs SES_FROM and SES_TO".to_string());
+        }
+        let history_table = var("HISTORY_TABLE").ok().filter(|v| !v.is_empty());
+        if !cfg!(feature = "history-dynamodb") && history_table.is_some() {
+            problems.push(
+                "HISTORY_TABLE needs the history-dynamodb feature, which this build lacks"
+                    .to_string(),
+            );
+        }
+        let s3_report_bucket = var("S3_REPORT_BUCKET").ok().filter(|v| !v.is_empty());
+        if !cfg!(feature = "s3") && s3_report_bucket.is_some() {
+            problems
+                .push("S3_REPORT_BUCKET needs the s3 feature, which this build lacks".to_string());
+        }
+        let attach_chart = var("ATTACH_CHART").is_ok_and(|v| v == "true");
+        if attach_chart && !cfg!(feature = "chart") {
+            problems
+                .push("ATTACH_CHART needs the chart feature, which this build lacks".to_string());
+        } else if attach_chart && s3_report_bucket.is_none() {
+            problems.push("ATTACH_CHART needs S3_REPORT_BUCKET to put the chart in".to_string());
+        }
+        let chart_days = match var("CHART_DAYS") {
+            Ok(value) => value
+                .parse::<i64>()
+                .ok()
+                .filter(|days| (2..=31).contains(days))
+                .or_else(|| {
+                    problems.push(format!("invalid CHART_DAYS: {}", value));
+                    None
+                }),
+            Err(_) => None,
+        };
+        let show_usage = var("SHOW_USAGE").is_ok_and(|v| v == "true");
+        let show_forecast = var("SHOW_FORECAST").is_ok_and(|v| v == "true");
+        let hybrid = var("HYBRID").is_ok_and(|v| v == "true");
+        let exclude_types: Vec<String> = var("EXCLUDE_TYPES")
+            .map(|v| {
+                v.split(',')
+                    .map(str::trim)
+                    .filter(|t| !t.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+        if !cfg!(feature = "cost-explorer")
+            && (group_by != GroupBy::Service
+                || show_usage
+                || show_forecast
+                || hybrid
+                || !exclude_types.is_empty())
+        {
+            problems.push(
+                "GROUP_BY other than service, SHOW_USAGE, SHOW_FORECAST, HYBRID and EXCLUDE_TYPES \
+                 need the cost-explorer feature, which this build lacks"
+                    .to_string(),
+            );
+        }
+        let region = match var("AWS_REGION") {
+            Ok(value) => handler::parse_region(&value)
+                .map(|region| region.to_string())
+                .map_err(|err| problems.push(problem(err)))
+                .ok(),
+            Err(_) => None,
+        };
+        let config = Config {
+            webhook_url: var("WEBHOOK_URL")
+                .ok()
+                .filter(|url| !url.is_empty())
+                .map(Secret::new),
+            region,
+            currency: var("BILLING_CURRENCY").unwrap_or_else(|_| "USD".to_string()),
+            include_currency_dimension: var("INCLUDE_CURRENCY_DIMENSION")
+                .map_or(true, |v| v != "false"),
+            convert_to_currency,
+            exchange_rate,
+            exchange_rate_url,
+            group_by,
+            service_threshold,
+            show_request_id: var("SHOW_REQUEST_ID").is_ok_and(|v| v == "true"),
+            show_timestamp: var("SHOW_TIMESTAMP").map_or(true, |v| v != "false"),
+            staleness_threshold: Duration::hours(staleness_hours),
+            slack_paginate: var("SLACK_PAGINATE").is_ok_and(|v| v == "true"),
+            slack_max_bytes,
+            slack_compatible_webhook: var("SLACK_COMPATIBLE_WEBHOOK").is_ok_and(|v| v == "true"),
+            slack_webhook_host,
+            message_format,
+            markdown_only: var("MARKDOWN_ONLY").is_ok_and(|v| v == "true"),
+            console_links: var("CONSOLE_LINKS").is_ok_and(|v| v == "true"),
+            language,
+            attachment_title: var("ATTACHMENT_TITLE").unwrap_or_else(|_| {
+                language
+                    .pick("サービス別内訳", "Breakdown by service")
+                    .to_string()
+            }),
+            message_template_parameter,
+            attachment_template_parameter,
+            cloudwatch_max_concurrency,
+            cloudwatch_max_rps,
+            require_data: var("REQUIRE_DATA").is_ok_and(|v| v == "true"),
+            skip_services_when_zero: var("SKIP_SERVICES_WHEN_ZERO").is_ok_and(|v| v == "true"),
+            aws_timeout,
+            handler_retry: var("HANDLER_RETRY").is_ok_and(|v| v == "true"),
+            notify_timeout,
+            notify_only_on_change: var("NOTIFY_ONLY_ON_CHANGE").is_ok_and(|v| v == "true"),
+            notify_min_total,
+            skip_unchanged: var("SKIP_UNCHANGED").is_ok_and(|v| v == "true"),
+            skip_unchanged_max_age,
+            notify_force_day,
+            show_rank_changes: var("SHOW_RANK_CHANGES").is_ok_and(|v| v == "true"),
+            min_change,
+            discrepancy_tolerance,
+            service_rollup,
+            total_rounding,
+            free_tier_hints,
+            account_names,
+            show_changes: var("SHOW_CHANGES").is_ok_and(|v| v == "true"),
+            increase_floor,
+            show_forecast,
+            show_sparkline: var("SHOW_SPARKLINE").is_ok_and(|v| v == "true"),
+            hybrid,
+            hide_negative: var("HIDE_NEGATIVE").is_ok_and(|v| v == "true"),
+            hide_from_breakdown: var("HIDE_FROM_BREAKDOWN")
+                .map(|v| {
+                    v.split(',')
+                        .map(str::trim)
+                        .filter(|name| !name.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            other_threshold,
+            account_min_share,
+            account_name: var("ACCOUNT_NAME").ok().filter(|v| !v.is_empty()),
+            show_usage,
+            digest_period,
+            billing_statistics,
+            alert_threshold,
+            warning_threshold,
+            monthly_budget,
+            alert_mention: var("ALERT_MENTION").ok().filter(|v| !v.is_empty()),
+            spend_buckets,
+            exclude_types,
+            emit_metrics: var("EMIT_METRICS").is_ok_and(|v| v == "true"),
+            align_to_calendar_day: var("ALIGN_TO_CALENDAR_DAY").is_ok_and(|v| v == "true"),
+            month_to_date: var("MTD").is_ok_and(|v| v == "true"),
+            timezone,
+            namespaces,
+            channels,
+            slack_channel,
+            slack_post_at,
+            delivery,
+            sns_topic_arn,
+            ses_from,
+            ses_to,
+            history_table,
+            s3_report_bucket,
+            s3_report_prefix: var("S3_REPORT_PREFIX").unwrap_or_default(),
+            s3_report_csv: var("S3_REPORT_CSV").is_ok_and(|v| v == "true"),
+            attach_chart,
+            chart_days,
+        };
+        // against the channels above
+        if let Some(ref url) = config.webhook_url {
+            if let Err(err) = notify::validate_webhook_url(
+                "WEBHOOK_URL",
+                url.expose(),
+                config.slack_webhook_host(),
+            ) {
+                problems.push(err.to_string());
+            }
+        }
+        if !problems.is_empty() {
+            return Err(AppError::Config(problems.join("; ")));
+        }
+        Ok(config)
+    }
+}
+
+/// Reads a positive number of seconds from `name`, noting a problem if it isn't one.
+fn parse_timeout(
+    lookup: &impl Fn(&str) -> Option<String>,
+    name: &str,
+    default_secs: u64,
+    problems: &mut Vec<String>,
+) -> StdDuration {
+    match lookup(name) {
+        Some(value) => match value.parse::<u64>() {
+            Ok(secs) if secs > 0 => StdDuration::from_secs(secs),
+            _ => {
+                problems.push(format!("invalid {}: {}", name, value));
+                StdDuration::from_secs(default_secs)
+            }
+        },
+        None => StdDuration::from_secs(default_secs),
+    }
+}
+
+/// The message of a configuration error, for listing it among others.
+fn problem(err: AppError) -> String {
+    match err {
+        AppError::Config(message) => message,
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_type_from_event() {
+        assert_eq!(ReportType::from_event(Some("weekly")), ReportType::Weekly);
+        assert_eq!(
+            ReportType::from_event(Some(" detailed ")),
+            ReportType::Detailed
+        );
+        assert_eq!(ReportType::from_event(Some("daily")), ReportType::Daily);
+        assert_eq!(ReportType::from_event(Some("monthly")), ReportType::Daily);
+        assert_eq!(ReportType::from_event(Some("")), ReportType::Daily);
+        assert_eq!(ReportType::from_event(None), ReportType::Daily);
+    }
+
+    #[test]
+    fn report_presets() {
+        let daily = ReportType::Daily.preset();
+        assert_eq!(daily.lookback, Duration::days(1));
+        assert_eq!(daily.top_n, None);
+        assert_eq!(daily.format, ReportFormat::Compact);
+        let weekly = ReportType::Weekly.preset();
+        assert_eq!(weekly.lookback, Duration::days(7));
+        assert_eq!(weekly.top_n, Some(10));
+        assert_eq!(weekly.format, ReportFormat::Compact);
+        let detailed = ReportType::Detailed.preset();
+        assert_eq!(detailed.lookback, Duration::days(1));
+        assert_eq!(detailed.top_n, None);
+        assert_eq!(detailed.format, ReportFormat::Full);
+    }
+
+    fn from_vars(vars: &[(&str, &str)]) -> Result<Config, AppError> {
+        Config::from_lookup(|name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        })
+    }
+
+    /// The problems `vars` have, as listed in the error.
+    fn problems(vars: &[(&str, &str)]) -> String {
+        match from_vars(vars) {
+            Ok(_) => panic!("{:?} is a valid configuration", vars),
+            Err(AppError::Config(problems)) => problems,
+            Err(err) => panic!("not a configuration error: {}", err),
+        }
+    }
+
+    #[test]
+    fn per_service_alert_threshold() {
+        let config = from_vars(&[("PER_SERVICE_ALERT_THRESHOLD", "100.5")]).unwrap();
+        assert_eq!(config.service_threshold, Some(Decimal::new(1005, 1)));
+        assert_eq!(from_vars(&[]).unwrap().service_threshold, None);
+        assert!(problems(&[("PER_SERVICE_ALERT_THRESHOLD", "lots")])
+            .starts_with("invalid PER_SERVICE_ALERT_THRESHOLD: "));
+    }
+
+    #[test]
+    fn group_by() {
+        assert_eq!(from_vars(&[]).unwrap().group_by, GroupBy::Service);
+        assert_eq!(
+            from_vars(&[("GROUP_BY", "service")]).unwrap().group_by,
+            GroupBy::Service
+        );
+        #[cfg(feature = "cost-explorer")]
+        assert_eq!(
+            from_vars(&[("GROUP_BY", "service, region")])
+                .unwrap()
+                .group_by,
+            GroupBy::ServiceAndRegion
+        );
+        assert_eq!(
+            problems(&[("GROUP_BY", "region,service")]),
+            "invalid GROUP_BY: region,service"
+        );
+    }
+
+    #[test]
+    fn staleness_warn_hours() {
+        assert_eq!(
+            from_vars(&[]).unwrap().staleness_threshold,
+            Duration::hours(24)
+        );
+        assert_eq!(
+            from_vars(&[("STALENESS_WARN_HOURS", "6")])
+                .unwrap()
+                .staleness_threshold,
+            Duration::hours(6)
+        );
+        assert!(problems(&[("STALENESS_WARN_HOURS", "a day")])
+            .starts_with("invalid STALENESS_WARN_HOURS: "));
+    }
+
+    #[test]
+    fn slack_paginate_is_off_unless_true() {
+        assert!(!from_vars(&[]).unwrap().slack_paginate);
+        assert!(
+            from_vars(&[("SLACK_PAGINATE", "true")])
+                .unwrap()
+                .slack_paginate
+        );
+        assert!(
+            !from_vars(&[("SLACK_PAGINATE", "false")])
+                .unwrap()
+                .slack_paginate
+        );
+    }
+
+    #[test]
+    fn show_usage_needs_cost_explorer() {
+        let config = from_vars(&[("SHOW_USAGE", "true")]);
+        if cfg!(feature = "cost-explorer") {
+            let config = config.unwrap();
+            assert!(config.show_usage);
+            assert!(config.uses_cost_explorer());
+        } else {
+            assert!(config.is_err());
+        }
+        assert!(!from_vars(&[]).unwrap().show_usage);
+    }
+
+    #[test]
+    fn digest_period() {
+        assert_eq!(from_vars(&[]).unwrap().digest_period, None);
+        assert_eq!(
+            from_vars(&[("DIGEST_PERIOD", "week")])
+                .unwrap()
+                .digest_period
+                .map(DigestPeriod::window),
+            Some(Duration::days(7))
+        );
+        assert_eq!(
+            from_vars(&[("DIGEST_PERIOD", "month")])
+                .unwrap()
+                .digest_period,
+            Some(DigestPeriod::Month)
+        );
+        assert_eq!(
+            problems(&[("DIGEST_PERIOD", "fortnight")]),
+            "invalid DIGEST_PERIOD: fortnight"
+        );
+    }
+
+    #[test]
+    fn billing_alert_threshold_and_mention() {
+        let config = from_vars(&[
+            ("BILLING_ALERT_THRESHOLD", "1000"),
+            ("ALERT_MENTION", "<@U123>"),
+        ])
+        .unwrap();
+        assert_eq!(config.alert_threshold, Some(Decimal::from(1000)));
+        assert_eq!(config.alert_mention.as_deref(), Some("<@U123>"));
+        let config = from_vars(&[("ALERT_MENTION", "")]).unwrap();
+        assert_eq!(config.alert_threshold, None);
+        assert_eq!(config.alert_mention, None);
+        assert!(problems(&[("BILLING_ALERT_THRESHOLD", "$1000")])
+            .starts_with("invalid BILLING_ALERT_THRESHOLD: "));
+    }
+
+    #[cfg(feature = "cost-explorer")]
+    #[test]
+    fn exclude_types_are_trimmed() {
+        let config = from_vars(&[("EXCLUDE_TYPES", " Credit, Refund,,Tax ")]).unwrap();
+        assert_eq!(config.exclude_types, ["Credit", "Refund", "Tax"]);
+        assert!(from_vars(&[]).unwrap().exclude_types.is_empty());
+    }
+
+    #[cfg(not(feature = "cost-explorer"))]
+    #[test]
+    fn exclude_types_need_cost_explorer() {
+        assert!(problems(&[("EXCLUDE_TYPES", "Credit")]).contains("cost-explorer feature"));
+    }
+
+    #[test]
+    fn webhook_url() {
+        let webhook = "https://hooks.slack.com/services/T0/B0/secret";
+        let config = from_vars(&[("WEBHOOK_URL", webhook)]).unwrap();
+        assert_eq!(config.webhook_url.unwrap().expose(), webhook);
+        assert!(from_vars(&[("WEBHOOK_URL", "")])
+            .unwrap()
+            .webhook_url
+            .is_none());
+    }
+
+    #[cfg(feature = "history-dynamodb")]
+    #[test]
+    fn history_table() {
+        let config = from_vars(&[("HISTORY_TABLE", "billing-history")]).unwrap();
+        assert_eq!(config.history_table.as_deref(), Some("billing-history"));
+        assert_eq!(
+            from_vars(&[("HISTORY_TABLE", "")]).unwrap().history_table,
+            None
+        );
+    }
+
+    #[cfg(not(feature = "history-dynamodb"))]
+    #[test]
+    fn history_table_needs_history_dynamodb() {
+        assert!(
+            problems(&[("HISTORY_TABLE", "billing-history")]).contains("history-dynamodb feature")
+        );
+    }
+
+    #[test]
+    fn emit_metrics_is_off_unless_true() {
+        assert!(!from_vars(&[]).unwrap().emit_metrics);
+        assert!(from_vars(&[("EMIT_METRICS", "true")]).unwrap().emit_metrics);
+        assert_eq!(ReportType::Detailed.as_str(), "detailed");
+    }
+
+    #[test]
+    fn calendar_day_alignment_and_timezone() {
+        let config = from_vars(&[
+            ("ALIGN_TO_CALENDAR_DAY", "true"),
+            ("BILLING_TIMEZONE", "Asia/Tokyo"),
+        ])
+        .unwrap();
+        assert!(config.align_to_calendar_day);
+        assert_eq!(config.timezone, Tz::Asia__Tokyo);
+        let config = from_vars(&[]).unwrap();
+        assert!(!config.align_to_calendar_day);
+        assert_eq!(config.timezone, Tz::UTC);
+        assert!(problems(&[("BILLING_TIMEZONE", "JST")]).starts_with("invalid BILLING_TIMEZONE: "));
+    }
+
+    #[test]
+    fn billing_namespaces() {
+        assert_eq!(from_vars(&[]).unwrap().namespaces, [BILLING_NAMESPACE]);
+        assert_eq!(
+            from_vars(&[("BILLING_NAMESPACE", "AWS/Billing, Custom/Costs,")])
+                .unwrap()
+                .namespaces,
+            ["AWS/Billing", "Custom/Costs"]
+        );
+        assert_eq!(
+            problems(&[("BILLING_NAMESPACE", " , ")]),
+            "BILLING_NAMESPACE is empty"
+        );
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let problems = problems(&[
+            ("PER_SERVICE_ALERT_THRESHOLD", "lots"),
+            ("STALENESS_WARN_HOURS", "a day"),
+            ("NOTIFY_MODE", "sometimes"),
+            ("BILLING_NAMESPACE", ","),
+        ]);
+        let listed: Vec<&str> = problems.split("; ").collect();
+        assert_eq!(listed.len(), 4, "{}", problems);
+        assert!(listed[0].starts_with("invalid PER_SERVICE_ALERT_THRESHOLD: "));
+        assert!(problems.contains("invalid STALENESS_WARN_HOURS: "));
+        assert!(problems.contains("invalid NOTIFY_MODE: sometimes"));
+        assert!(problems.contains("BILLING_NAMESPACE is empty"));
+    }
+
+    #[test]
+    fn min_change_is_an_amount_or_a_percent() {
+        assert_eq!(
+            MinChange::parse(None).unwrap(),
+            MinChange::Absolute(Decimal::new(1, 2))
+        );
+        assert_eq!(
+            MinChange::parse(Some(" 0.5 ")).unwrap(),
+            MinChange::Absolute(Decimal::new(5, 1))
+        );
+        assert_eq!(
+            MinChange::parse(Some("5 %")).unwrap(),
+            MinChange::Percent(Decimal::from(5))
+        );
+        let err = MinChange::parse(Some("five%")).unwrap_err().to_string();
+        assert!(err.contains("invalid MIN_CHANGE: five%"), "{}", err);
+
+        let config =
+            from_vars(&[("NOTIFY_ONLY_ON_CHANGE", "true"), ("MIN_CHANGE", "10%")]).unwrap();
+        assert!(config.notify_only_on_change);
+        assert_eq!(config.min_change, MinChange::Percent(Decimal::from(10)));
+    }
+
+    #[test]
+    fn timeouts_are_positive_seconds() {
+        let config = from_vars(&[]).unwrap();
+        assert_eq!(config.aws_timeout, StdDuration::from_secs(10));
+        assert_eq!(config.notify_timeout, StdDuration::from_secs(5));
+        let config =
+            from_vars(&[("AWS_TIMEOUT_SECS", "3"), ("NOTIFY_TIMEOUT_SECS", "20")]).unwrap();
+        assert_eq!(config.aws_timeout, StdDuration::from_secs(3));
+        assert_eq!(config.notify_timeout, StdDuration::from_secs(20));
+        assert_eq!(
+            problems(&[("AWS_TIMEOUT_SECS", "0"), ("NOTIFY_TIMEOUT_SECS", "1.5")]),
+            "invalid AWS_TIMEOUT_SECS: 0; invalid NOTIFY_TIMEOUT_SECS: 1.5"
+        );
+    }
+
+    #[test]
+    fn console_links_are_off_unless_true() {
+        assert!(!from_vars(&[]).unwrap().console_links);
+        assert!(
+            from_vars(&[("CONSOLE_LINKS", "true")])
+                .unwrap()
+                .console_links
+        );
+    }
+
+    #[test]
+    fn attachment_title_defaults_by_language() {
+        assert_eq!(from_vars(&[]).unwrap().attachment_title, "サービス別内訳");
+        let english = from_vars(&[("LANGUAGE", "en")]).unwrap();
+        assert_eq!(english.attachment_title, "Breakdown by service");
+        let custom = from_vars(&[("ATTACHMENT_TITLE", "Costs")]).unwrap();
+        assert_eq!(custom.attachment_title, "Costs");
+        assert_eq!(
+            from_vars(&[("ATTACHMENT_TITLE", "")])
+                .unwrap()
+                .attachment_title,
+            ""
+        );
+    }
+
+    #[cfg(feature = "slack")]
+    #[test]
+    fn slack_compatible_webhook_lifts_the_host_check() {
+        let config = from_vars(&[]).unwrap();
+        assert_eq!(config.slack_webhook_host(), Some("hooks.slack.com"));
+        let config = from_vars(&[("SLACK_COMPATIBLE_WEBHOOK", "true")]).unwrap();
+        assert_eq!(config.slack_webhook_host(), None);
+        let config = from_vars(&[("NOTIFY_CHANNELS", "generic-webhook")]).unwrap();
+        assert_eq!(config.slack_webhook_host(), None);
+    }
+
+    #[cfg(feature = "slack")]
+    #[test]
+    fn webhook_url_is_checked_against_the_channels() {
+        let other = "https://example.com/hooks/secret";
+        let problems = problems(&[("WEBHOOK_URL", other)]);
+        assert!(problems.contains("WEBHOOK_URL is not a valid webhook URL"));
+        assert!(!problems.contains("secret"), "{}", problems);
+        assert!(from_vars(&[
+            ("WEBHOOK_URL", other),
+            ("NOTIFY_CHANNELS", "generic-webhook")
+        ])
+        .is_ok());
+    }
+
+    #[cfg(feature = "slack")]
+    #[test]
+    fn slack_post_at_needs_the_bot() {
+        let config = from_vars(&[
+            ("NOTIFY_CHANNELS", "slack-bot"),
+            ("SLACK_CHANNEL", "C123"),
+            ("SLACK_POST_AT", "09:00"),
+        ])
+        .unwrap();
+        assert!(config.uses_slack_bot());
+        assert_eq!(config.slack_post_at, NaiveTime::from_hms_opt(9, 0, 0));
+        assert!(problems(&[("NOTIFY_CHANNELS", "slack-bot")])
+            .contains("the slack-bot channel needs SLACK_CHANNEL"));
+        assert!(problems(&[("SLACK_POST_AT", "09:00")])
+            .contains("SLACK_POST_AT=09:00 needs the slack-bot channel"));
+        assert!(problems(&[
+            ("NOTIFY_CHANNELS", "slack-bot"),
+            ("SLACK_CHANNEL", "C123"),
+            ("SLACK_POST_AT", "9am"),
+        ])
+        .contains("invalid SLACK_POST_AT: 9am"));
+    }
+
+    #[test]
+    fn service_rollup_takes_the_first_pattern_alphabetically() {
+        let config =
+            from_vars(&[("SERVICE_ROLLUP", r#"{"EC2": "Compute", "Amazon": "AWS"}"#)]).unwrap();
+        let labels: Vec<_> = config
+            .service_rollup
+            .iter()
+            .filter(|rollup| rollup.pattern.is_match("AmazonEC2"))
+            .map(|rollup| rollup.label.as_str())
+            .collect();
+        assert_eq!(labels, ["AWS", "Compute"]);
+        assert!(from_vars(&[("SERVICE_ROLLUP", " ")])
+            .unwrap()
+            .service_rollup
+            .is_empty());
+    }
+
+    #[test]
+    fn invalid_service_rollup() {
+        assert!(problems(&[("SERVICE_ROLLUP", "EC2=EC2")])
+            .starts_with("invalid SERVICE_ROLLUP: expected a JSON object of patterns to labels"));
+        assert_eq!(
+            problems(&[("SERVICE_ROLLUP", r#"{"EC2": ""}"#)]),
+            "invalid SERVICE_ROLLUP: the label of EC2 must be a non-empty string"
+        );
+        assert!(problems(&[("SERVICE_ROLLUP", r#"{"EC2(": "EC2"}"#)])
+            .starts_with("invalid SERVICE_ROLLUP pattern EC2(: "));
+    }
+
+    #[cfg(feature = "s3")]
+    #[test]
+    fn s3_report_settings() {
+        let config = from_vars(&[
+            ("S3_REPORT_BUCKET", "reports"),
+            ("S3_REPORT_PREFIX", "billing/"),
+            ("S3_REPORT_CSV", "true"),
+        ])
+        .unwrap();
+        assert_eq!(config.s3_report_bucket.as_deref(), Some("reports"));
+        assert_eq!(config.s3_report_prefix, "billing/");
+        assert!(config.s3_report_csv);
+        let config = from_vars(&[("S3_REPORT_BUCKET", "")]).unwrap();
+        assert_eq!(config.s3_report_bucket, None);
+        assert!(!config.s3_report_csv);
+    }
+
+    #[cfg(not(feature = "s3"))]
+    #[test]
+    fn s3_report_bucket_needs_s3() {
+        assert_eq!(
+            problems(&[("S3_REPORT_BUCKET", "reports")]),
+            "S3_REPORT_BUCKET needs the s3 feature, which this build lacks"
+        );
+    }
+
+    #[test]
+    fn free_tier_hints_by_service() {
+        let config =
+            from_vars(&[("FREE_TIER_HINTS", r#"{"AmazonEC2": "750時間/月まで無料"}"#)]).unwrap();
+        assert_eq!(
+            config.free_tier_hints.get("AmazonEC2").map(String::as_str),
+            Some("750時間/月まで無料")
+        );
+        assert!(from_vars(&[("FREE_TIER_HINTS", " ")])
+            .unwrap()
+            .free_tier_hints
+            .is_empty());
+        assert!(
+            problems(&[("FREE_TIER_HINTS", r#"["AmazonEC2"]"#)]).starts_with(
+                "invalid FREE_TIER_HINTS: expected a JSON object of service names to notes: "
+            )
+        );
+    }
+
+    #[test]
+    fn warning_threshold() {
+        let config = from_vars(&[("BILLING_WARNING_THRESHOLD", "50")]).unwrap();
+        assert_eq!(config.warning_threshold, Some(Decimal::from(50)));
+        assert_eq!(from_vars(&[]).unwrap().warning_threshold, None);
+        assert!(problems(&[("BILLING_WARNING_THRESHOLD", "fifty")])
+            .starts_with("invalid BILLING_WARNING_THRESHOLD: "));
+    }
+
+    #[test]
+    fn total_rounding() {
+        let rounding = |mode: &str, precision: &str, total: &str| {
+            TotalRounding::parse(Some(mode), Some(precision))
+                .unwrap()
+                .apply(total.parse().unwrap())
+                .to_string()
+        };
+        assert_eq!(rounding("", "", "1234.565"), "1234.57");
+        assert_eq!(rounding("nearest", "0", "1234.5"), "1235");
+        assert_eq!(rounding("up", "0", "1234.01"), "1235");
+        assert_eq!(rounding("down", "1", "1234.99"), "1234.9");
+        assert_eq!(rounding("up", "0", "-0.5"), "0");
+        assert_eq!(
+            TotalRounding::parse(None, None).unwrap(),
+            TotalRounding::default()
+        );
+        assert_eq!(
+            problems(&[("ROUND_TOTAL", "ceil")]),
+            "invalid ROUND_TOTAL: ceil"
+        );
+        assert_eq!(
+            problems(&[("ROUND_TOTAL_PRECISION", "11")]),
+            "invalid ROUND_TOTAL_PRECISION: 11"
+        );
+    }
+
+    #[test]
+    fn monthly_budget_must_be_positive() {
+        let config = from_vars(&[("MONTHLY_BUDGET", "500")]).unwrap();
+        assert_eq!(config.monthly_budget, Some(Decimal::from(500)));
+        assert_eq!(
+            problems(&[("MONTHLY_BUDGET", "0")]),
+            "invalid MONTHLY_BUDGET: 0"
+        );
+        assert_eq!(
+            problems(&[("MONTHLY_BUDGET", "$500")]),
+            "invalid MONTHLY_BUDGET: $500"
+        );
+    }
+
+    #[test]
+    fn discrepancy_tolerance() {
+        assert_eq!(
+            from_vars(&[]).unwrap().discrepancy_tolerance,
+            MinChange::Absolute(Decimal::ONE)
+        );
+        let config = from_vars(&[("DISCREPANCY_TOLERANCE", "2%")]).unwrap();
+        assert_eq!(
+            config.discrepancy_tolerance,
+            MinChange::Percent(Decimal::TWO)
+        );
+        assert!(problems(&[("DISCREPANCY_TOLERANCE", "a lot")])
+            .starts_with("invalid DISCREPANCY_TOLERANCE: a lot: "));
+    }
+
+    #[test]
+    fn show_request_id_is_off_unless_true() {
+        assert!(!from_vars(&[]).unwrap().show_request_id);
+        assert!(
+            from_vars(&[("SHOW_REQUEST_ID", "true")])
+                .unwrap()
+                .show_request_id
+        );
+    }
+
+    #[test]
+    fn other_threshold_is_a_percentage() {
+        let config = from_vars(&[("OTHER_THRESHOLD_PERCENT", "1.5")]).unwrap();
+        assert_eq!(config.other_threshold, Some(Decimal::new(15, 1)));
+        assert_eq!(from_vars(&[]).unwrap().other_threshold, None);
+        for invalid in ["0", "101", "1%"] {
+            assert_eq!(
+                problems(&[("OTHER_THRESHOLD_PERCENT", invalid)]),
+                format!("invalid OTHER_THRESHOLD_PERCENT: {}", invalid)
+            );
+        }
+    }
+
+    #[test]
+    fn aws_region() {
+        assert_eq!(from_vars(&[]).unwrap().region, None);
+        let config = from_vars(&[("AWS_REGION", " eu-west-1 ")]).unwrap();
+        assert_eq!(config.region.as_deref(), Some("eu-west-1"));
+        assert!(problems(&[("AWS_REGION", "EU WEST")]).contains("invalid AWS_REGION"));
+    }
+
+    #[cfg(feature = "cost-explorer")]
+    #[test]
+    fn hybrid_uses_cost_explorer() {
+        assert!(!from_vars(&[]).unwrap().uses_cost_explorer());
+        assert!(from_vars(&[("HYBRID", "true")])
+            .unwrap()
+            .uses_cost_explorer());
+    }
+
+    #[cfg(not(feature = "cost-explorer"))]
+    #[test]
+    fn hybrid_needs_cost_explorer() {
+        assert!(problems(&[("HYBRID", "true")]).contains("cost-explorer feature"));
+    }
+
+    #[test]
+    fn markdown_format_and_markdown_only() {
+        let config =
+            from_vars(&[("MESSAGE_FORMAT", "markdown"), ("MARKDOWN_ONLY", "true")]).unwrap();
+        assert_eq!(config.message_format, MessageFormat::Markdown);
+        assert!(config.markdown_only);
+        assert!(!from_vars(&[]).unwrap().markdown_only);
+    }
+
+    #[test]
+    fn group_by_keys() {
+        assert_eq!(GroupBy::parse(None).unwrap(), GroupBy::Service);
+        assert_eq!(GroupBy::parse(Some("region")).unwrap(), GroupBy::Region);
+        assert_eq!(
+            GroupBy::parse(Some("service, region")).unwrap(),
+            GroupBy::ServiceAndRegion
+        );
+        assert!(GroupBy::parse(Some("region,service")).is_err());
+    }
+
+    #[cfg(not(feature = "cost-explorer"))]
+    #[test]
+    fn group_by_region_needs_cost_explorer() {
+        assert!(problems(&[("GROUP_BY", "region")]).contains("cost-explorer feature"));
+    }
+
+    #[cfg(feature = "slack")]
+    #[test]
+    fn slack_webhook_host() {
+        let config = from_vars(&[("SLACK_WEBHOOK_HOST", "Hooks.Slack-Gov.com")]).unwrap();
+        assert_eq!(config.slack_webhook_host(), Some("hooks.slack-gov.com"));
+        let config = from_vars(&[("SLACK_WEBHOOK_HOST", " ")]).unwrap();
+        assert_eq!(config.slack_webhook_host(), Some("hooks.slack.com"));
+        assert!(
+            problems(&[("SLACK_WEBHOOK_HOST", "https://hooks.slack-gov.com")])
+                .contains("invalid SLACK_WEBHOOK_HOST")
+        );
+    }
+
+    #[test]
+    fn account_min_share_is_a_percentage() {
+        let config = from_vars(&[("ACCOUNT_MIN_SHARE_PERCENT", "2.5")]).unwrap();
+        assert_eq!(config.account_min_share, Some(Decimal::new(25, 1)));
+        for value in ["0", "101", "few"] {
+            assert!(problems(&[("ACCOUNT_MIN_SHARE_PERCENT", value)])
+                .contains("invalid ACCOUNT_MIN_SHARE_PERCENT"));
+        }
+    }
+
+    #[test]
+    fn account_names_by_id() {
+        let config = from_vars(&[("ACCOUNT_NAMES", r#"{"111111111111": "production"}"#)]).unwrap();
+        assert_eq!(
+            config.account_names.get("111111111111").map(String::as_str),
+            Some("production")
+        );
+        assert!(problems(&[("ACCOUNT_NAMES", "production")]).starts_with(
+            "invalid ACCOUNT_NAMES: expected a JSON object of account ids to names: "
+        ));
+    }
+
+    #[test]
+    fn billing_statistics_keep_their_order() {
+        assert_eq!(
+            BillingStatistic::parse_list(None).unwrap(),
+            [BillingStatistic::Maximum]
+        );
+        assert_eq!(
+            BillingStatistic::parse_list(Some("average, maximum,average")).unwrap(),
+            [BillingStatistic::Average, BillingStatistic::Maximum]
+        );
+        assert!(problems(&[("BILLING_STATISTIC", "maximum,sum")])
+            .contains("invalid BILLING_STATISTIC: sum"));
+    }
+
+    #[test]
+    fn message_style_is_read_without_message_format() {
+        let config = from_vars(&[("MESSAGE_STYLE", "table")]).unwrap();
+        assert_eq!(config.message_format, MessageFormat::Table);
+        let config =
+            from_vars(&[("MESSAGE_FORMAT", "fields"), ("MESSAGE_STYLE", "table")]).unwrap();
+        assert_eq!(config.message_format, MessageFormat::Fields);
+    }
+
+    #[test]
+    fn attach_chart_needs_somewhere_to_put_it() {
+        if cfg!(feature = "chart") {
+            assert!(problems(&[("ATTACH_CHART", "true")])
+                .contains("ATTACH_CHART needs S3_REPORT_BUCKET"));
+            let config =
+                from_vars(&[("ATTACH_CHART", "true"), ("S3_REPORT_BUCKET", "reports")]).unwrap();
+            assert!(config.attach_chart);
+        } else {
+            assert!(problems(&[("ATTACH_CHART", "true")]).contains("chart feature"));
+        }
+    }
+
+    #[test]
+    fn top_increases_floor() {
+        assert_eq!(from_vars(&[]).unwrap().increase_floor, Decimal::ONE);
+        let config = from_vars(&[("TOP_INCREASES_FLOOR", "0")]).unwrap();
+        assert_eq!(config.increase_floor, Decimal::ZERO);
+        assert!(problems(&[("TOP_INCREASES_FLOOR", "-1")]).contains("invalid TOP_INCREASES_FLOOR"));
+    }
+
+    #[test]
+    fn currency_dimension_is_included_unless_false() {
+        assert!(from_vars(&[]).unwrap().include_currency_dimension);
+        let config = from_vars(&[("INCLUDE_CURRENCY_DIMENSION", "false")]).unwrap();
+        assert!(!config.include_currency_dimension);
+    }
+
+    #[test]
+    fn slack_max_bytes() {
+        assert_eq!(from_vars(&[]).unwrap().slack_max_bytes, 40_000);
+        let config = from_vars(&[("SLACK_MAX_BYTES", "8000")]).unwrap();
+        assert_eq!(config.slack_max_bytes, 8_000);
+        assert!(problems(&[("SLACK_MAX_BYTES", "0")]).contains("invalid SLACK_MAX_BYTES"));
+    }
+
+    #[test]
+    fn notify_min_total_and_force_day() {
+        let config = from_vars(&[("NOTIFY_MIN_TOTAL", "10.5"), ("NOTIFY_FORCE_DAY", "1")]).unwrap();
+        assert_eq!(config.notify_min_total, Some(Decimal::new(105, 1)));
+        assert_eq!(config.notify_force_day, Some(1));
+        assert!(problems(&[("NOTIFY_MIN_TOTAL", "ten")]).contains("invalid NOTIFY_MIN_TOTAL"));
+        assert!(problems(&[("NOTIFY_FORCE_DAY", "32")]).contains("invalid NOTIFY_FORCE_DAY: 32"));
+    }
+
+    #[test]
+    fn spend_buckets_tier_the_total() {
+        let buckets = SpendBuckets::parse("100, 500").unwrap();
+        assert_eq!(buckets.style(Decimal::new(9999, 2)), ("good", "🟢"));
+        assert_eq!(buckets.style(Decimal::new(100, 0)), ("warning", "🟡"));
+        assert_eq!(buckets.style(Decimal::new(500, 0)), ("danger", "🔴"));
+        let buckets = SpendBuckets::parse("10,20,30").unwrap();
+        assert_eq!(buckets.style(Decimal::new(25, 0)), ("#ff8c00", "🟠"));
+
+        for (value, reason) in [
+            ("100,abc", "thresholds must be amounts"),
+            ("1,2,3,4", "expected 1 to 3 thresholds"),
+            ("500,100", "thresholds must be ascending"),
+        ] {
+            assert!(
+                problems(&[("SPEND_BUCKETS", value)]).contains(reason),
+                "{}",
+                value
+            );
+        }
+        assert_eq!(
+            from_vars(&[("SPEND_BUCKETS", " ")]).unwrap().spend_buckets,
+            None
+        );
+    }
+
+    #[test]
+    fn skip_unchanged_max_hours() {
+        let config = from_vars(&[
+            ("SKIP_UNCHANGED", "true"),
+            ("SKIP_UNCHANGED_MAX_HOURS", "24"),
+        ])
+        .unwrap();
+        assert!(config.skip_unchanged);
+        assert_eq!(config.skip_unchanged_max_age, Some(Duration::hours(24)));
+        assert!(problems(&[("SKIP_UNCHANGED_MAX_HOURS", "0")])
+            .contains("invalid SKIP_UNCHANGED_MAX_HOURS"));
+    }
+
+    #[test]
+    fn language() {
+        assert_eq!(from_vars(&[]).unwrap().language, Language::Ja);
+        assert_eq!(
+            from_vars(&[("LANGUAGE", " en ")]).unwrap().language,
+            Language::En
+        );
+        assert!(problems(&[("LANGUAGE", "fr")]).contains("invalid LANGUAGE: fr"));
+    }
+
+    #[test]
+    fn convert_to_currency_needs_a_rate() {
+        let config =
+            from_vars(&[("CONVERT_TO_CURRENCY", " jpy"), ("EXCHANGE_RATE", "154.2")]).unwrap();
+        assert_eq!(config.convert_to_currency.as_deref(), Some("JPY"));
+        assert_eq!(config.exchange_rate, Some(Decimal::new(1542, 1)));
+        assert!(problems(&[("CONVERT_TO_CURRENCY", "JPY")])
+            .contains("CONVERT_TO_CURRENCY needs EXCHANGE_RATE or EXCHANGE_RATE_URL"));
+        assert!(problems(&[("EXCHANGE_RATE", "-1")]).contains("invalid EXCHANGE_RATE"));
+    }
+
+    #[test]
+    fn chart_days() {
+        assert_eq!(from_vars(&[]).unwrap().chart_days, None);
+        assert_eq!(
+            from_vars(&[("CHART_DAYS", "14")]).unwrap().chart_days,
+            Some(14)
+        );
+        for days in ["1", "32", "week"] {
+            assert!(problems(&[("CHART_DAYS", days)]).contains("invalid CHART_DAYS"));
+        }
+    }
+}
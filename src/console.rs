@@ -0,0 +1,162 @@
+//! Links into the AWS console, so a figure in the report leads to where it can be explained.
+
+use chrono::{Datelike, NaiveDate};
+
+/// CloudWatch `ServiceName` values and the `SERVICE` names Cost Explorer uses for them. Only
+/// services listed here (under either name) get a link.
+const SERVICE_NAMES: &[(&str, &str)] = &[
+    ("AmazonEC2", "Amazon Elastic Compute Cloud - Compute"),
+    ("AmazonS3", "Amazon Simple Storage Service"),
+    ("AmazonRDS", "Amazon Relational Database Service"),
+    ("AWSLambda", "AWS Lambda"),
+    ("AmazonDynamoDB", "Amazon DynamoDB"),
+    ("AmazonCloudWatch", "AmazonCloudWatch"),
+    ("AmazonCloudFront", "Amazon CloudFront"),
+    ("AmazonRoute53", "Amazon Route 53"),
+    ("AmazonSNS", "Amazon Simple Notification Service"),
+    ("AWSQueueService", "Amazon Simple Queue Service"),
+    ("AmazonVPC", "Amazon Virtual Private Cloud"),
+    ("AWSDataTransfer", "AWS Data Transfer"),
+    ("AmazonECS", "Amazon Elastic Container Service"),
+    (
+        "AmazonEKS",
+        "Amazon Elastic Container Service for Kubernetes",
+    ),
+    ("AmazonElastiCache", "Amazon ElastiCache"),
+    ("awskms", "AWS Key Management Service"),
+    ("AWSSecretsManager", "AWS Secrets Manager"),
+];
+
+/// Cost Explorer in the console, which only lives in us-east-1.
+const COST_EXPLORER_URL: &str =
+    "https://us-east-1.console.aws.amazon.com/cost-management/home#/cost-explorer";
+
+/// The `SERVICE` name Cost Explorer uses for `service`, given under either name.
+pub fn cost_explorer_service_name(service: &str) -> Option<&'static str> {
+    SERVICE_NAMES
+        .iter()
+        .find(|(cloudwatch, cost_explorer)| *cloudwatch == service || *cost_explorer == service)
+        .map(|(_, cost_explorer)| *cost_explorer)
+}
+
+/// Cost Explorer filtered to `service`, or `None` for a service it can't be matched to.
+pub fn cost_explorer_link(service: &str) -> Option<String> {
+    let name = cost_explorer_service_name(service)?;
+    let filter = serde_json::json!([{
+        "dimension": {"id": "Service", "displayValue": "Service"},
+        "operator": "INCLUDES",
+        "values": [{"value": name, "displayValue": name}],
+    }]);
+    Some(format!(
+        "{}?groupBy={}&filter={}",
+        COST_EXPLORER_URL,
+        percent_encode(r#"["Service"]"#),
+        percent_encode(&filter.to_string())
+    ))
+}
+
+/// Cost Explorer and the Billing dashboard, for the report as a whole.
+pub struct ReportLinks {
+    pub cost_explorer: String,
+    pub billing: String,
+}
+
+/// Region prefixes of partitions with consoles of their own (China, GovCloud, ISO), whose URLs
+/// differ from the standard ones.
+const OTHER_PARTITION_PREFIXES: &[&str] = &["cn-", "us-gov-", "us-iso"];
+
+/// Cost Explorer grouped by service over the month of `today` so far, and the Billing
+/// dashboard. `None` in a `region` outside the standard partition.
+pub fn report_links(region: Option<&str>, today: NaiveDate) -> Option<ReportLinks> {
+    if region.is_some_and(|region| {
+        OTHER_PARTITION_PREFIXES
+            .iter()
+            .any(|prefix| region.starts_with(prefix))
+    }) {
+        return None;
+    }
+    let first = today.with_day(1).unwrap_or(today);
+    Some(ReportLinks {
+        cost_explorer: format!(
+            "{}?startDate={}&endDate={}&granularity=Monthly&groupBy={}",
+            COST_EXPLORER_URL,
+            first.format("%Y-%m-%d"),
+            today.format("%Y-%m-%d"),
+            percent_encode(r#"["Service"]"#)
+        ),
+        billing: "https://us-east-1.console.aws.amazon.com/billing/home#/".to_string(),
+    })
+}
+
+/// Percent-encodes everything but unreserved characters, which also keeps the URL clear of
+/// the `|` and `>` that would end a Slack link.
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn service_names_match_either_way() {
+        assert_eq!(
+            cost_explorer_service_name("AmazonEC2"),
+            Some("Amazon Elastic Compute Cloud - Compute")
+        );
+        assert_eq!(
+            cost_explorer_service_name("Amazon Simple Storage Service"),
+            Some("Amazon Simple Storage Service")
+        );
+        assert_eq!(cost_explorer_service_name("Datadog"), None);
+        assert_eq!(cost_explorer_link("Datadog"), None);
+    }
+
+    #[test]
+    fn cost_explorer_link_is_safe_in_a_slack_link() {
+        let link = cost_explorer_link("AWSLambda").unwrap();
+        assert!(link.starts_with(COST_EXPLORER_URL));
+        assert!(link.contains("groupBy=%5B%22Service%22%5D"), "{}", link);
+        assert!(
+            link.contains("%22value%22%3A%22AWS%20Lambda%22"),
+            "{}",
+            link
+        );
+        let query = &link[COST_EXPLORER_URL.len()..];
+        assert!(!query.contains(['|', '>', '<', ' ', '"']), "{}", link);
+    }
+
+    #[test]
+    fn percent_encode_keeps_unreserved_characters() {
+        assert_eq!(percent_encode("a-Z_0.9~"), "a-Z_0.9~");
+        assert_eq!(percent_encode("a b|c>é"), "a%20b%7Cc%3E%C3%A9");
+    }
+
+    #[test]
+    fn report_links_cover_the_month_so_far() {
+        let today = NaiveDate::from_ymd_opt(2024, 6, 14).unwrap();
+        let links = report_links(Some("ap-northeast-1"), today).unwrap();
+        assert!(
+            links
+                .cost_explorer
+                .ends_with("?startDate=2024-06-01&endDate=2024-06-14&granularity=Monthly&groupBy=%5B%22Service%22%5D"),
+            "{}",
+            links.cost_explorer
+        );
+        assert_eq!(
+            links.billing,
+            "https://us-east-1.console.aws.amazon.com/billing/home#/"
+        );
+        assert!(report_links(None, today).is_some());
+        for region in ["cn-north-1", "us-gov-west-1", "us-isob-east-1"] {
+            assert!(report_links(Some(region), today).is_none(), "{}", region);
+        }
+    }
+}
@@ -0,0 +1,523 @@
+//! Per-service costs from Cost Explorer, for breakdowns `AWS/Billing` metrics can't provide.
+
+use aws_sdk_costexplorer::types::{
+    DateInterval, Dimension, DimensionValues, Expression, Granularity, Group, GroupDefinition,
+    GroupDefinitionType, Metric, MetricValue,
+};
+use aws_sdk_costexplorer::Client as CostExplorerClient;
+use chrono::{Datelike, NaiveDate};
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+
+use crate::billing::{ServiceBilling, Usage};
+use crate::config::Language;
+use crate::retry::{self, RetryPolicy};
+use crate::AppError;
+
+pub struct CostExplorerFacade {
+    client: CostExplorerClient,
+    retry: RetryPolicy,
+    filter: Option<Expression>,
+}
+
+impl CostExplorerFacade {
+    /// Costs of the record types in `exclude_types` (e.g. "Credit", "Tax") are left out of
+    /// every query.
+    pub fn new(client: CostExplorerClient, retry: RetryPolicy, exclude_types: &[String]) -> Self {
+        CostExplorerFacade {
+            client,
+            retry,
+            filter: exclude_record_types_filter(exclude_types),
+        }
+    }
+
+    /// Month-to-date `UnblendedCost` per service and region, named like
+    /// "Amazon EC2 (ap-northeast-1)".
+    pub async fn get_costs_by_service_and_region(
+        &self,
+        today: NaiveDate,
+    ) -> Result<Vec<ServiceBilling>, AppError> {
+        let groups = self
+            .get_month_to_date_groups(today, &["SERVICE", "REGION"], &["UnblendedCost"])
+            .await?;
+        Ok(parse_service_region_groups(&groups))
+    }
+
+    /// Month-to-date `UnblendedCost` per region, named like "Asia Pacific (Tokyo)".
+    pub async fn get_costs_by_region(
+        &self,
+        today: NaiveDate,
+        language: Language,
+    ) -> Result<Vec<ServiceBilling>, AppError> {
+        let groups = self
+            .get_month_to_date_groups(today, &["REGION"], &["UnblendedCost"])
+            .await?;
+        Ok(parse_region_groups(&groups, language))
+    }
+
+    /// Month-to-date `UnblendedCost` per linked account and service, as `(account id, service)`.
+    pub async fn get_costs_by_account_and_service(
+        &self,
+        today: NaiveDate,
+    ) -> Result<Vec<(String, ServiceBilling)>, AppError> {
+        let groups = self
+            .get_month_to_date_groups(today, &["LINKED_ACCOUNT", "SERVICE"], &["UnblendedCost"])
+            .await?;
+        Ok(parse_account_service_groups(&groups))
+    }
+
+    /// Month-to-date `UnblendedCost` and `UsageQuantity` per service.
+    pub async fn get_costs_and_usage_by_service(
+        &self,
+        today: NaiveDate,
+    ) -> Result<Vec<ServiceBilling>, AppError> {
+        let groups = self
+            .get_month_to_date_groups(today, &["SERVICE"], &["UnblendedCost", "UsageQuantity"])
+            .await?;
+        Ok(parse_service_usage_groups(&groups))
+    }
+
+    /// Forecast `UnblendedCost` of `service` (as Cost Explorer names it) from `today` to the end
+    /// of the month, on top of what it cost so far. Fails for services without enough history.
+    pub async fn get_rest_of_month_forecast(
+        &self,
+        service: &str,
+        today: NaiveDate,
+    ) -> Result<Decimal, AppError> {
+        let period = rest_of_month_interval(today)?;
+        let service_filter = Expression::builder()
+            .dimensions(
+                DimensionValues::builder()
+                    .key(Dimension::Service)
+                    .values(service)
+                    .build(),
+            )
+            .build();
+        let filter = match &self.filter {
+            Some(filter) => Expression::builder()
+                .and(service_filter)
+                .and(filter.clone())
+                .build(),
+            None => service_filter,
+        };
+        let output = retry::with_retry(&self.retry, "GetCostForecast", || async {
+            self.client
+                .get_cost_forecast()
+                .time_period(period.clone())
+                .metric(Metric::UnblendedCost)
+                .granularity(Granularity::Monthly)
+                .filter(filter.clone())
+                .send()
+                .await
+                .map_err(|err| AppError::CostExplorer {
+                    operation: "GetCostForecast",
+                    source: err.map_service_error(Into::into),
+                })
+        })
+        .await?;
+        Ok(output.total().and_then(amount).unwrap_or_default())
+    }
+
+    /// Follows `next_page_token` until every page of the month-to-date groups is read.
+    async fn get_month_to_date_groups(
+        &self,
+        today: NaiveDate,
+        group_keys: &[&str],
+        metrics: &[&str],
+    ) -> Result<Vec<Group>, AppError> {
+        let period = month_to_date_interval(today)?;
+        let mut groups = vec![];
+        let mut next_page_token = None;
+        loop {
+            let mut request = self
+                .client
+                .get_cost_and_usage()
+                .time_period(period.clone())
+                .granularity(Granularity::Monthly)
+                .set_filter(self.filter.clone())
+                .set_next_page_token(next_page_token);
+            for metric in metrics {
+                request = request.metrics(*metric);
+            }
+            for key in group_keys {
+                request = request.group_by(
+                    GroupDefinition::builder()
+                        .r#type(GroupDefinitionType::Dimension)
+                        .key(*key)
+                        .build(),
+                );
+            }
+            let output = retry::with_retry(&self.retry, "GetCostAndUsage", || async {
+                request
+                    .clone()
+                    .send()
+                    .await
+                    .map_err(|err| AppError::CostExplorer {
+                        operation: "GetCostAndUsage",
+                        source: err.map_service_error(Into::into),
+                    })
+            })
+            .await?;
+            for result in output.results_by_time() {
+                groups.extend(result.groups().iter().cloned());
+            }
+            next_page_token = output.next_page_token().map(str::to_string);
+            if next_page_token.is_none() {
+                return Ok(groups);
+            }
+        }
+    }
+}
+
+/// Cost Explorer periods are date based and the end date is exclusive, so the current
+/// (partial) day is covered by ending tomorrow.
+pub fn month_to_date_interval(today: NaiveDate) -> Result<DateInterval, AppError> {
+    let start = today.with_day(1).unwrap_or(today);
+    let end = today.succ_opt().unwrap_or(today);
+    DateInterval::builder()
+        .start(start.format("%Y-%m-%d").to_string())
+        .end(end.format("%Y-%m-%d").to_string())
+        .build()
+        .map_err(AppError::from)
+}
+
+/// From `today` up to the first of next month; forecasts can't start in the past.
+pub fn rest_of_month_interval(today: NaiveDate) -> Result<DateInterval, AppError> {
+    let first_of_next_month = today
+        .with_day(1)
+        .and_then(|first| first.checked_add_months(chrono::Months::new(1)))
+        .unwrap_or(today);
+    DateInterval::builder()
+        .start(today.format("%Y-%m-%d").to_string())
+        .end(first_of_next_month.format("%Y-%m-%d").to_string())
+        .build()
+        .map_err(AppError::from)
+}
+
+/// `NOT RECORD_TYPE IN (exclude_types)`, or no filter when there is nothing to exclude.
+pub fn exclude_record_types_filter(exclude_types: &[String]) -> Option<Expression> {
+    if exclude_types.is_empty() {
+        return None;
+    }
+    let record_types = DimensionValues::builder()
+        .key(Dimension::RecordType)
+        .set_values(Some(exclude_types.to_vec()))
+        .build();
+    Some(
+        Expression::builder()
+            .not(Expression::builder().dimensions(record_types).build())
+            .build(),
+    )
+}
+
+fn metric<'a>(group: &'a Group, name: &str) -> Option<&'a MetricValue> {
+    group.metrics().and_then(|metrics| metrics.get(name))
+}
+
+/// Amounts are decimal strings, sometimes in scientific notation for tiny costs.
+fn amount(value: &MetricValue) -> Option<Decimal> {
+    let amount = value.amount()?;
+    amount
+        .parse::<Decimal>()
+        .or_else(|_| Decimal::from_scientific(amount))
+        .ok()
+}
+
+/// Turns groups keyed by `[SERVICE, REGION]` into "service (region)" entries.
+pub fn parse_service_region_groups(groups: &[Group]) -> Vec<ServiceBilling> {
+    groups
+        .iter()
+        .filter_map(|group| {
+            let (service, region) = match group.keys() {
+                [service, region] => (service, region),
+                _ => return None,
+            };
+            Some(ServiceBilling {
+                name: format!("{} ({})", service, region),
+                cost: metric(group, "UnblendedCost")
+                    .and_then(amount)
+                    .unwrap_or_default(),
+                usage: None,
+            })
+        })
+        .collect()
+}
+
+/// Turns groups keyed by `[LINKED_ACCOUNT, SERVICE]` into `(account id, service)` pairs.
+pub fn parse_account_service_groups(groups: &[Group]) -> Vec<(String, ServiceBilling)> {
+    groups
+        .iter()
+        .filter_map(|group| {
+            let (account, service) = match group.keys() {
+                [account, service] => (account, service),
+                _ => return None,
+            };
+            let service = ServiceBilling {
+                name: service.clone(),
+                cost: metric(group, "UnblendedCost")
+                    .and_then(amount)
+                    .unwrap_or_default(),
+                usage: None,
+            };
+            Some((account.clone(), service))
+        })
+        .collect()
+}
+
+/// Names of the `REGION` values Cost Explorer reports, as the console shows them.
+const REGION_NAMES: &[(&str, &str)] = &[
+    ("us-east-1", "US East (N. Virginia)"),
+    ("us-east-2", "US East (Ohio)"),
+    ("us-west-1", "US West (N. California)"),
+    ("us-west-2", "US West (Oregon)"),
+    ("af-south-1", "Africa (Cape Town)"),
+    ("ap-east-1", "Asia Pacific (Hong Kong)"),
+    ("ap-south-1", "Asia Pacific (Mumbai)"),
+    ("ap-south-2", "Asia Pacific (Hyderabad)"),
+    ("ap-northeast-1", "Asia Pacific (Tokyo)"),
+    ("ap-northeast-2", "Asia Pacific (Seoul)"),
+    ("ap-northeast-3", "Asia Pacific (Osaka)"),
+    ("ap-southeast-1", "Asia Pacific (Singapore)"),
+    ("ap-southeast-2", "Asia Pacific (Sydney)"),
+    ("ap-southeast-3", "Asia Pacific (Jakarta)"),
+    ("ap-southeast-4", "Asia Pacific (Melbourne)"),
+    ("ca-central-1", "Canada (Central)"),
+    ("eu-central-1", "Europe (Frankfurt)"),
+    ("eu-central-2", "Europe (Zurich)"),
+    ("eu-west-1", "Europe (Ireland)"),
+    ("eu-west-2", "Europe (London)"),
+    ("eu-west-3", "Europe (Paris)"),
+    ("eu-south-1", "Europe (Milan)"),
+    ("eu-south-2", "Europe (Spain)"),
+    ("eu-north-1", "Europe (Stockholm)"),
+    ("il-central-1", "Israel (Tel Aviv)"),
+    ("me-south-1", "Middle East (Bahrain)"),
+    ("me-central-1", "Middle East (UAE)"),
+    ("sa-east-1", "South America (São Paulo)"),
+];
+
+/// Costs of global services (e.g. Route 53, support) come without a region, as `NoRegion` or
+/// `global`.
+const GLOBAL_REGION_NAMES: (&str, &str) = ("グローバル (リージョンなし)", "Global (no region)");
+
+/// The console name of `region`, or the code itself for a region newer than this list.
+pub fn region_name(region: &str, language: Language) -> &str {
+    match region {
+        "" | "NoRegion" | "global" => language.pick(GLOBAL_REGION_NAMES.0, GLOBAL_REGION_NAMES.1),
+        _ => REGION_NAMES
+            .iter()
+            .find(|(code, _)| *code == region)
+            .map_or(region, |(_, name)| *name),
+    }
+}
+
+/// Turns groups keyed by `[REGION]` into an entry per region, the region-less ones merged
+/// into one.
+pub fn parse_region_groups(groups: &[Group], language: Language) -> Vec<ServiceBilling> {
+    let mut regions: Vec<ServiceBilling> = vec![];
+    for group in groups {
+        let name = match group.keys() {
+            [region] => region_name(region, language),
+            _ => continue,
+        };
+        let cost = metric(group, "UnblendedCost")
+            .and_then(amount)
+            .unwrap_or_default();
+        match regions.iter_mut().find(|region| region.name == name) {
+            Some(region) => region.cost += cost,
+            None => regions.push(ServiceBilling {
+                name: name.to_string(),
+                cost,
+                usage: None,
+            }),
+        }
+    }
+    regions
+}
+
+/// Turns groups keyed by `[SERVICE]` with `UnblendedCost` and `UsageQuantity` into entries
+/// with usage. Services whose usage mixes units (e.g. hours and requests) report no single
+/// unit, and adding their quantities up is meaningless, so they get none.
+pub fn parse_service_usage_groups(groups: &[Group]) -> Vec<ServiceBilling> {
+    groups
+        .iter()
+        .filter_map(|group| {
+            let service = match group.keys() {
+                [service] => service,
+                _ => return None,
+            };
+            let usage = metric(group, "UsageQuantity").and_then(|value| {
+                let unit = value
+                    .unit()
+                    .filter(|unit| !unit.is_empty() && *unit != "N/A")?;
+                Some(Usage {
+                    quantity: amount(value)
+                        .and_then(|quantity| quantity.to_f64())
+                        .filter(|quantity| *quantity > 0.0)?,
+                    unit: unit.to_string(),
+                })
+            });
+            Some(ServiceBilling {
+                name: service.clone(),
+                cost: metric(group, "UnblendedCost")
+                    .and_then(amount)
+                    .unwrap_or_default(),
+                usage,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(amount: &str, unit: &str) -> MetricValue {
+        MetricValue::builder().amount(amount).unit(unit).build()
+    }
+
+    fn group(keys: &[&str], metrics: Vec<(&str, MetricValue)>) -> Group {
+        let mut group = Group::builder();
+        for key in keys {
+            group = group.keys(*key);
+        }
+        for (name, value) in metrics {
+            group = group.metrics(name, value);
+        }
+        group.build()
+    }
+
+    #[test]
+    fn service_region_groups_are_named_after_both_keys() {
+        let groups = [
+            group(
+                &["Amazon Elastic Compute Cloud - Compute", "ap-northeast-1"],
+                vec![("UnblendedCost", value("12.5", "USD"))],
+            ),
+            group(&["AWS Lambda", "us-east-1"], vec![]),
+            group(&["Tax"], vec![("UnblendedCost", value("3", "USD"))]),
+        ];
+        let services = parse_service_region_groups(&groups);
+        let services: Vec<_> = services
+            .iter()
+            .map(|service| (service.name.as_str(), service.cost))
+            .collect();
+        assert_eq!(
+            services,
+            [
+                (
+                    "Amazon Elastic Compute Cloud - Compute (ap-northeast-1)",
+                    12.5
+                ),
+                ("AWS Lambda (us-east-1)", 0.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn month_to_date_ends_tomorrow() {
+        let today = NaiveDate::from_ymd_opt(2024, 6, 30).unwrap();
+        let period = month_to_date_interval(today).unwrap();
+        assert_eq!(period.start(), "2024-06-01");
+        assert_eq!(period.end(), "2024-07-01");
+    }
+
+    #[test]
+    fn usage_is_kept_when_in_a_single_unit() {
+        let groups = [
+            group(
+                &["Amazon Simple Storage Service"],
+                vec![
+                    ("UnblendedCost", value("23.5", "USD")),
+                    ("UsageQuantity", value("1024", "GB-Mo")),
+                ],
+            ),
+            group(
+                &["Amazon Elastic Compute Cloud - Compute"],
+                vec![
+                    ("UnblendedCost", value("1.2E+2", "USD")),
+                    ("UsageQuantity", value("3000", "N/A")),
+                ],
+            ),
+            group(
+                &["Tax"],
+                vec![
+                    ("UnblendedCost", value("3", "USD")),
+                    ("UsageQuantity", value("0", "")),
+                ],
+            ),
+            group(&["AWS Lambda", "us-east-1"], vec![]),
+        ];
+        let services = parse_service_usage_groups(&groups);
+        assert_eq!(services.len(), 3);
+        assert_eq!(services[0].cost, Decimal::new(235, 1));
+        let usage = services[0].usage.as_ref().unwrap();
+        assert_eq!(usage.quantity, 1024.0);
+        assert_eq!(usage.unit, "GB-Mo");
+        assert_eq!(services[1].cost, Decimal::from(120));
+        assert!(services[1].usage.is_none());
+        assert!(services[2].usage.is_none());
+    }
+
+    #[test]
+    fn excluded_record_types_are_negated() {
+        assert!(exclude_record_types_filter(&[]).is_none());
+        let filter =
+            exclude_record_types_filter(&["Credit".to_string(), "Tax".to_string()]).unwrap();
+        let excluded = filter.not().and_then(Expression::dimensions).unwrap();
+        assert_eq!(excluded.key(), Some(&Dimension::RecordType));
+        assert_eq!(excluded.values(), ["Credit", "Tax"]);
+    }
+
+    #[test]
+    fn regions_are_named_like_the_console() {
+        assert_eq!(
+            region_name("ap-northeast-1", Language::Ja),
+            "Asia Pacific (Tokyo)"
+        );
+        assert_eq!(region_name("xx-new-1", Language::Ja), "xx-new-1");
+        assert_eq!(region_name("NoRegion", Language::En), "Global (no region)");
+
+        let groups = [
+            group(
+                &["ap-northeast-1"],
+                vec![("UnblendedCost", value("10", "USD"))],
+            ),
+            group(&["NoRegion"], vec![("UnblendedCost", value("2", "USD"))]),
+            group(&["global"], vec![("UnblendedCost", value("1.5", "USD"))]),
+            group(
+                &["us-east-1", "extra"],
+                vec![("UnblendedCost", value("9", "USD"))],
+            ),
+        ];
+        let regions = parse_region_groups(&groups, Language::Ja);
+        let regions: Vec<_> = regions
+            .iter()
+            .map(|region| (region.name.as_str(), region.cost.to_string()))
+            .collect();
+        assert_eq!(
+            regions,
+            [
+                ("Asia Pacific (Tokyo)", "10".to_string()),
+                ("グローバル (リージョンなし)", "3.5".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn groups_by_account_and_service() {
+        let groups = [
+            group(
+                &["111111111111", "Amazon S3"],
+                vec![("UnblendedCost", value("1.25", "USD"))],
+            ),
+            group(&["Amazon EC2"], vec![("UnblendedCost", value("9", "USD"))]),
+        ];
+        let costs = parse_account_service_groups(&groups);
+        let costs: Vec<_> = costs
+            .iter()
+            .map(|(account, service)| (account.as_str(), service.name.as_str(), service.cost))
+            .collect();
+        assert_eq!(costs, [("111111111111", "Amazon S3", Decimal::new(125, 2))]);
+    }
+}
@@ -0,0 +1,269 @@
+//! The error type shared by every module, keeping what failed and why until the runtime
+//! boundary instead of flattening it to a string.
+
+use std::fmt;
+
+use aws_sdk_cloudwatch::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+
+use crate::notify::NotifyError;
+
+/// SDK errors of any CloudWatch operation.
+pub type CloudWatchSdkError = SdkError<aws_sdk_cloudwatch::Error>;
+/// SDK errors of any Cost Explorer operation.
+#[cfg(feature = "cost-explorer")]
+pub type CostExplorerSdkError = SdkError<aws_sdk_costexplorer::Error>;
+/// SDK errors of any DynamoDB operation.
+#[cfg(feature = "history-dynamodb")]
+pub type DynamoDbSdkError = SdkError<aws_sdk_dynamodb::Error>;
+/// SDK errors of any SSM operation.
+pub type SsmSdkError = SdkError<aws_sdk_ssm::Error>;
+/// SDK errors of any S3 operation.
+#[cfg(feature = "s3")]
+pub type S3SdkError = SdkError<aws_sdk_s3::Error>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum AppError {
+    /// invalid environment variable or event field
+    #[error("invalid configuration: {0}")]
+    Config(String),
+
+    #[error("CloudWatch {operation} failed: {}", DisplayErrorContext(.source))]
+    CloudWatch {
+        operation: &'static str,
+        #[source]
+        source: CloudWatchSdkError,
+    },
+
+    /// CloudWatch refused the period or time range of a metric query
+    #[error(
+        "GetMetricStatistics rejected a period of {period_secs}s over {range_secs}s: periods \
+         must be a multiple of 60s and a query may return at most 1440 datapoints, so try a \
+         period of {suggested_period_secs}s ({})",
+        DisplayErrorContext(.source)
+    )]
+    MetricQuery {
+        period_secs: i64,
+        range_secs: i64,
+        suggested_period_secs: i64,
+        #[source]
+        source: CloudWatchSdkError,
+    },
+
+    #[cfg(feature = "cost-explorer")]
+    #[error("Cost Explorer {operation} failed: {}", DisplayErrorContext(.source))]
+    CostExplorer {
+        operation: &'static str,
+        #[source]
+        source: CostExplorerSdkError,
+    },
+
+    #[cfg(feature = "cost-explorer")]
+    #[error("invalid Cost Explorer request: {0}")]
+    CostExplorerRequest(#[from] aws_sdk_costexplorer::error::BuildError),
+
+    #[error("{operation} {parameter} failed: {}", DisplayErrorContext(.source))]
+    Ssm {
+        operation: &'static str,
+        parameter: String,
+        #[source]
+        source: SsmSdkError,
+    },
+
+    #[cfg(feature = "chart")]
+    #[error("rendering the chart failed: {0}")]
+    Chart(#[from] png::EncodingError),
+
+    #[cfg(feature = "s3")]
+    #[error("S3 {operation} {key} failed: {}", DisplayErrorContext(.source))]
+    S3 {
+        operation: &'static str,
+        key: String,
+        #[source]
+        source: S3SdkError,
+    },
+
+    /// the parameter doesn't exist
+    #[error(
+        "parameter {parameter} not found in {region}: create it with the webhook URL as its \
+         value, or set WEBHOOK_URL"
+    )]
+    ParameterNotFound { parameter: String, region: String },
+
+    /// the parameter exists but carries no value
+    #[error("parameter {parameter} has no value: put the webhook URL in it, or set WEBHOOK_URL")]
+    NoData { parameter: String },
+
+    /// the webhook URL can't be posted to. Only the start of it is quoted, the rest may be secret.
+    #[error(
+        "{parameter} is not a valid webhook URL ({length} characters starting with {prefix:?}): \
+         expected {expected}"
+    )]
+    InvalidWebhookUrl {
+        parameter: String,
+        prefix: String,
+        length: usize,
+        expected: &'static str,
+    },
+
+    /// `REQUIRE_DATA=true` and CloudWatch has nothing to report
+    #[error("no billing datapoints available for {currency}")]
+    NoDatapoints { currency: String },
+
+    /// a call took longer than its timeout
+    #[error("{operation} timed out after {timeout:?}")]
+    Timeout {
+        operation: String,
+        timeout: std::time::Duration,
+    },
+
+    /// Slack didn't take a message, after any retries
+    #[error("{0}")]
+    Slack(#[from] crate::slack::PostError),
+
+    #[cfg(feature = "history-dynamodb")]
+    #[error("DynamoDB {operation} failed: {}", DisplayErrorContext(.source))]
+    History {
+        operation: &'static str,
+        #[source]
+        source: DynamoDbSdkError,
+    },
+
+    /// `EXCHANGE_RATE_URL` didn't give a rate
+    #[error("fetching the exchange rate failed: {0}")]
+    ExchangeRate(String),
+
+    /// every notifier that failed, with its error
+    #[error("notification failed: {}", NotifyFailures(.0))]
+    Notify(Vec<(&'static str, NotifyError)>),
+}
+
+impl AppError {
+    /// Variant name, logged with the error so failures can be aggregated by cause.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AppError::Config(_) => "Config",
+            AppError::CloudWatch { .. } => "CloudWatch",
+            AppError::MetricQuery { .. } => "MetricQuery",
+            #[cfg(feature = "cost-explorer")]
+            AppError::CostExplorer { .. } => "CostExplorer",
+            #[cfg(feature = "cost-explorer")]
+            AppError::CostExplorerRequest(_) => "CostExplorerRequest",
+            AppError::Ssm { .. } => "Ssm",
+            #[cfg(feature = "chart")]
+            AppError::Chart(_) => "Chart",
+            #[cfg(feature = "s3")]
+            AppError::S3 { .. } => "S3",
+            AppError::ParameterNotFound { .. } => "ParameterNotFound",
+            AppError::NoData { .. } => "NoData",
+            AppError::InvalidWebhookUrl { .. } => "InvalidWebhookUrl",
+            AppError::NoDatapoints { .. } => "NoDatapoints",
+            AppError::Timeout { .. } => "Timeout",
+            AppError::Slack(_) => "Slack",
+            AppError::ExchangeRate(_) => "ExchangeRate",
+            AppError::Notify(_) => "Notify",
+            #[cfg(feature = "history-dynamodb")]
+            AppError::History { .. } => "History",
+        }
+    }
+
+    /// Whether the same call may succeed if retried: throttling and server-side failures of
+    /// AWS calls and Slack, and timeouts. Everything else, e.g. `AccessDenied` or a validation
+    /// error, would fail the same way again.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            AppError::CloudWatch { source, .. } => is_retryable_sdk_error(source),
+            #[cfg(feature = "cost-explorer")]
+            AppError::CostExplorer { source, .. } => is_retryable_sdk_error(source),
+            AppError::Ssm { source, .. } => is_retryable_sdk_error(source),
+            #[cfg(feature = "s3")]
+            AppError::S3 { source, .. } => is_retryable_sdk_error(source),
+            AppError::Timeout { .. } => true,
+            AppError::Slack(err) => err.is_retryable(),
+            #[cfg(feature = "history-dynamodb")]
+            AppError::History { source, .. } => is_retryable_sdk_error(source),
+            _ => false,
+        }
+    }
+}
+
+/// Error codes AWS services use for rate limiting.
+const THROTTLING_CODES: &[&str] = &[
+    "Throttling",
+    "ThrottlingException",
+    "RequestLimitExceeded",
+    "TooManyRequestsException",
+    // Cost Explorer's rate limit
+    "LimitExceededException",
+];
+
+pub fn is_retryable_sdk_error<E: ProvideErrorMetadata>(err: &SdkError<E>) -> bool {
+    match err {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => {
+            true
+        }
+        SdkError::ServiceError(context) => {
+            context
+                .err()
+                .code()
+                .is_some_and(|code| THROTTLING_CODES.contains(&code))
+                || context.raw().status().is_server_error()
+        }
+        _ => false,
+    }
+}
+
+struct NotifyFailures<'a>(&'a [(&'static str, NotifyError)]);
+
+impl<'a> fmt::Display for NotifyFailures<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, (name, err)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            write!(f, "{}: {}", name, err)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metric_query_error_suggests_a_period() {
+        let err = AppError::MetricQuery {
+            period_secs: 30,
+            range_secs: 86_400,
+            suggested_period_secs: 60,
+            source: SdkError::construction_failure("rejected"),
+        };
+        let message = err.to_string();
+        assert!(
+            message.starts_with("GetMetricStatistics rejected a period of 30s over 86400s"),
+            "{}",
+            message
+        );
+        assert!(message.contains("try a period of 60s"), "{}", message);
+        assert_eq!(err.kind(), "MetricQuery");
+        assert!(!err.is_retryable());
+    }
+
+    #[test]
+    fn missing_webhook_parameter_says_what_to_do() {
+        let not_found = AppError::ParameterNotFound {
+            parameter: "/billing-notification/slack-webhook-url".to_string(),
+            region: "ap-northeast-1".to_string(),
+        };
+        assert_eq!(
+            not_found.to_string(),
+            "parameter /billing-notification/slack-webhook-url not found in ap-northeast-1: \
+             create it with the webhook URL as its value, or set WEBHOOK_URL"
+        );
+        let empty = AppError::NoData {
+            parameter: "/billing-notification/slack-webhook-url".to_string(),
+        };
+        assert!(empty.to_string().ends_with("or set WEBHOOK_URL"));
+        assert!(!not_found.is_retryable());
+    }
+}
@@ -0,0 +1,88 @@
+//! Exchange rates for showing the total in `CONVERT_TO_CURRENCY` as well.
+
+use std::time::Duration as StdDuration;
+
+use rust_decimal::prelude::FromPrimitive;
+use rust_decimal::Decimal;
+use serde_json::Value;
+
+use crate::AppError;
+
+/// The rate from `from` to `to` at `EXCHANGE_RATE_URL`, in which `{from}` and `{to}` are
+/// replaced by the currency codes. The endpoint must answer JSON with the rate under
+/// `rates.<to>`, like `{"rates": {"JPY": 154.2}}`, as most rate APIs do.
+pub async fn fetch_rate(
+    http: &reqwest::Client,
+    url: &str,
+    from: &str,
+    to: &str,
+    timeout: StdDuration,
+) -> Result<Decimal, AppError> {
+    let url = url.replace("{from}", from).replace("{to}", to);
+    let failed = |reason: String| AppError::ExchangeRate(format!("{} to {}: {}", from, to, reason));
+    let res = http
+        .get(&url)
+        .timeout(timeout)
+        .send()
+        .await
+        .map_err(|err| failed(err.without_url().to_string()))?;
+    if !res.status().is_success() {
+        return Err(failed(format!("the endpoint responded {}", res.status())));
+    }
+    let body: Value = res
+        .json()
+        .await
+        .map_err(|err| failed(err.without_url().to_string()))?;
+    let rate = match &body["rates"][to] {
+        Value::Number(rate) => rate.as_f64().and_then(Decimal::from_f64),
+        Value::String(rate) => rate.parse::<Decimal>().ok(),
+        _ => None,
+    };
+    rate.filter(|rate| *rate > Decimal::ZERO)
+        .ok_or_else(|| failed(format!("no rates.{} in the answer", to)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{MockResponse, MockServer};
+
+    async fn fetch(response: MockResponse) -> (Result<Decimal, AppError>, String) {
+        let server = MockServer::start(vec![response]);
+        let url = format!("{}/latest?base={{from}}&symbols={{to}}", server.url);
+        let rate = fetch_rate(
+            &reqwest::Client::new(),
+            &url,
+            "USD",
+            "JPY",
+            StdDuration::from_secs(5),
+        )
+        .await;
+        (rate, server.requests()[0].path.clone())
+    }
+
+    #[tokio::test]
+    async fn rate_is_read_under_rates() {
+        let (rate, path) = fetch(MockResponse::new(200, r#"{"rates": {"JPY": 154.2}}"#)).await;
+        assert_eq!(rate.unwrap(), Decimal::new(1542, 1));
+        assert_eq!(path, "/latest?base=USD&symbols=JPY");
+        let (rate, _) = fetch(MockResponse::new(200, r#"{"rates": {"JPY": "154.25"}}"#)).await;
+        assert_eq!(rate.unwrap(), Decimal::new(15425, 2));
+    }
+
+    #[tokio::test]
+    async fn rate_without_an_answer_fails() {
+        for (response, reason) in [
+            (MockResponse::new(500, "{}"), "the endpoint responded 500"),
+            (MockResponse::new(200, r#"{"rates": {}}"#), "no rates.JPY"),
+            (
+                MockResponse::new(200, r#"{"rates": {"JPY": 0}}"#),
+                "no rates.JPY",
+            ),
+            (MockResponse::new(200, "<html>"), "USD to JPY"),
+        ] {
+            let err = fetch(response).await.0.unwrap_err().to_string();
+            assert!(err.contains(reason), "{}", err);
+        }
+    }
+}
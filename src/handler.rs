@@ -0,0 +1,1653 @@
+//! The invocation flow: collect the bill, then notify.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::future::Future;
+use std::time::{Duration as StdDuration, Instant, SystemTime, UNIX_EPOCH};
+
+use aws_config::BehaviorVersion;
+use aws_sdk_cloudwatch::config::Region;
+use aws_sdk_cloudwatch::Client as CloudWatchClient;
+#[cfg(feature = "cost-explorer")]
+use aws_sdk_costexplorer::Client as CostExplorerClient;
+#[cfg(feature = "history-dynamodb")]
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+#[cfg(feature = "s3")]
+use aws_sdk_s3::Client as S3Client;
+#[cfg(feature = "ses")]
+use aws_sdk_sesv2::Client as SesClient;
+#[cfg(feature = "sns")]
+use aws_sdk_sns::Client as SnsClient;
+use aws_sdk_ssm::Client as SsmClient;
+use chrono::{Datelike, Utc};
+use futures::stream::{self, StreamExt};
+use lambda_runtime::Context;
+use rust_decimal::Decimal;
+use serde_derive::{Deserialize, Serialize};
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::account::{self, AccountIdentity};
+#[cfg(feature = "cost-explorer")]
+use crate::billing::ServiceBilling;
+#[cfg(feature = "templates")]
+use crate::billing::Severity;
+use crate::billing::{self, Billing, Budget, Degraded, Digest, Period};
+#[cfg(feature = "chart")]
+use crate::chart;
+use crate::cloudwatch::{BillingMetricsSource, CloudWatchFacade, RawSeries, TimeWindow};
+use crate::config::{Config, GroupBy, MessageFormat, ReportPreset, ReportType};
+#[cfg(feature = "cost-explorer")]
+use crate::console;
+#[cfg(feature = "cost-explorer")]
+use crate::cost_explorer::CostExplorerFacade;
+use crate::exchange;
+#[cfg(feature = "history-dynamodb")]
+use crate::history;
+use crate::limiter::Limiter;
+use crate::metrics::InvocationMetrics;
+use crate::notify::{self, Receipt};
+use crate::retry::RetryPolicy;
+#[cfg(feature = "s3")]
+use crate::s3;
+use crate::secret::Secret;
+use crate::state;
+#[cfg(feature = "templates")]
+use crate::template;
+use crate::xray::Tracer;
+use crate::AppError;
+
+#[derive(Deserialize, Clone)]
+pub struct CustomEvent {
+    #[serde(default)]
+    pub report_type: Option<String>,
+    /// `report` (default), `explain` (see [`explain`]) or `test-webhook` (see
+    /// [`test_webhook`])
+    #[serde(default)]
+    pub mode: Option<String>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct CustomOutput {
+    /// channels that delivered the report
+    pub delivered_via: Vec<String>,
+    /// what each of them answered
+    pub receipts: Vec<Receipt>,
+    /// only in `explain` mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub explanation: Option<Explanation>,
+    /// the report in Markdown, only with `MESSAGE_FORMAT=markdown`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub markdown: Option<String>,
+    /// why the report was held back on purpose, telling a quiet run from a broken one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suppressed: Option<String>,
+}
+
+/// What CloudWatch returned for the queries of a report.
+#[derive(Serialize, Clone)]
+pub struct Explanation {
+    pub total: Vec<RawSeries>,
+    /// by service name
+    pub services: BTreeMap<String, Vec<RawSeries>>,
+}
+
+/// Clients created once per process and shared by every invocation, so warm invocations reuse
+/// their connections instead of paying for new TLS handshakes.
+///
+/// The AWS region comes from the default provider chain (`AWS_REGION` etc.) and is only read
+/// here, so changing it takes a cold start. Credentials are refreshed by the SDK as they expire.
+/// Regions the SDK doesn't know yet work too, through the standard endpoint pattern.
+pub struct Clients {
+    pub cloudwatch: CloudWatchClient,
+    #[cfg(feature = "cost-explorer")]
+    pub cost_explorer: CostExplorerClient,
+    pub ssm: SsmClient,
+    #[cfg(feature = "sns")]
+    pub sns: SnsClient,
+    #[cfg(feature = "ses")]
+    pub ses: SesClient,
+    #[cfg(feature = "history-dynamodb")]
+    pub dynamodb: DynamoDbClient,
+    #[cfg(feature = "s3")]
+    pub s3: S3Client,
+    pub http: reqwest::Client,
+    /// looked up once at startup
+    pub account: AccountIdentity,
+}
+
+impl Clients {
+    pub async fn from_env() -> Result<Self, AppError> {
+        let mut loader = aws_config::defaults(BehaviorVersion::latest());
+        if let Ok(region) = env::var("AWS_REGION") {
+            loader = loader.region(parse_region(&region)?);
+        }
+        let sdk_config = loader.load().await;
+        // billing metrics are only published to us-east-1, and Cost Explorer is served from there
+        let cloudwatch_config = aws_sdk_cloudwatch::config::Builder::from(&sdk_config)
+            .region(Region::from_static("us-east-1"))
+            .build();
+        #[cfg(feature = "cost-explorer")]
+        let cost_explorer_config = aws_sdk_costexplorer::config::Builder::from(&sdk_config)
+            .region(Region::from_static("us-east-1"))
+            .build();
+        let account = account::resolve(
+            &aws_sdk_sts::Client::new(&sdk_config),
+            &aws_sdk_iam::Client::new(&sdk_config),
+        )
+        .await;
+        Ok(Clients {
+            cloudwatch: CloudWatchClient::from_conf(cloudwatch_config),
+            #[cfg(feature = "cost-explorer")]
+            cost_explorer: CostExplorerClient::from_conf(cost_explorer_config),
+            ssm: SsmClient::new(&sdk_config),
+            #[cfg(feature = "sns")]
+            sns: SnsClient::new(&sdk_config),
+            #[cfg(feature = "ses")]
+            ses: SesClient::new(&sdk_config),
+            #[cfg(feature = "history-dynamodb")]
+            dynamodb: DynamoDbClient::new(&sdk_config),
+            #[cfg(feature = "s3")]
+            s3: S3Client::new(&sdk_config),
+            http: reqwest::Client::new(),
+            account,
+        })
+    }
+}
+
+/// Accepts anything shaped like a region name (`ap-northeast-1`, `us-gov-west-1`, or one
+/// newer than the SDK), and rejects what can't be one, e.g. an empty string or a typo with
+/// spaces, which would otherwise only fail later as an unreachable endpoint.
+pub fn parse_region(region: &str) -> Result<Region, AppError> {
+    let region = region.trim();
+    let plausible = region.starts_with(|c: char| c.is_ascii_lowercase())
+        && region.contains('-')
+        && region
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if !plausible {
+        return Err(AppError::Config(format!(
+            "invalid AWS_REGION: {:?}",
+            region
+        )));
+    }
+    Ok(Region::new(region.to_string()))
+}
+
+pub async fn handle(
+    e: CustomEvent,
+    c: &Context,
+    clients: &Clients,
+) -> Result<CustomOutput, AppError> {
+    info!(deadline_ms = c.deadline, "start request");
+    let report_type = ReportType::from_event(e.report_type.as_deref());
+    let mut metrics = InvocationMetrics::new(report_type);
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(err) => {
+            // counted as a failed run all the same, with `EMIT_METRICS` read on its own
+            if env::var("EMIT_METRICS").is_ok_and(|v| v == "true") {
+                metrics.emit();
+            }
+            return Err(err);
+        }
+    };
+    // `deadline` is in epoch milliseconds
+    let invocation = Invocation {
+        deadline: Some(UNIX_EPOCH + StdDuration::from_millis(c.deadline)),
+        trace_id: c.xray_trace_id.as_deref(),
+        request_id: Some(&c.request_id),
+        account_id: account_id_of(&c.invoked_function_arn),
+    };
+    let preset = report_type.preset();
+    let result = match e.mode.as_deref() {
+        None | Some("report") => report(&config, &preset, &invocation, clients, &mut metrics).await,
+        Some("explain") => explain(&config, &preset, &invocation, clients).await,
+        Some("test-webhook") => test_webhook(&config, &preset, &invocation, clients).await,
+        Some(other) => Err(AppError::Config(format!("invalid mode: {}", other))),
+    };
+    if config.emit_metrics {
+        metrics.notification_success = result.is_ok();
+        metrics.emit();
+    }
+    result
+}
+
+/// What the report needs of the Lambda `Context`, so only [`handle`] depends on the runtime and
+/// the rest can be driven without one.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Invocation<'a> {
+    /// retries that can't finish before it aren't started
+    pub deadline: Option<SystemTime>,
+    pub trace_id: Option<&'a str>,
+    pub request_id: Option<&'a str>,
+    /// the account the function runs in, which is the one billed
+    pub account_id: Option<&'a str>,
+}
+
+/// The account id in `arn`, e.g. `123456789012` in
+/// `arn:aws:lambda:us-east-1:123456789012:function:billing`.
+pub fn account_id_of(arn: &str) -> Option<&str> {
+    arn.split(':').nth(4).filter(|id| !id.is_empty())
+}
+
+/// Collects the bill and notifies every channel.
+pub async fn report(
+    config: &Config,
+    preset: &ReportPreset,
+    invocation: &Invocation<'_>,
+    clients: &Clients,
+    metrics: &mut InvocationMetrics,
+) -> Result<CustomOutput, AppError> {
+    let mut retry = RetryPolicy::new(invocation.deadline);
+    retry.attempt_timeout = config.aws_timeout;
+    let tracer = Tracer::for_invocation(invocation.trace_id);
+    // before the queries, so a template that doesn't parse fails the report early
+    #[cfg(feature = "templates")]
+    let templates = template::get_templates(&clients.ssm, &retry, &tracer, config).await?;
+    let mut billing = collect(config, preset, clients, retry, &tracer, metrics).await?;
+    if config.show_request_id {
+        billing.request_id = invocation.request_id.map(str::to_string);
+    }
+    billing.account_id = invocation
+        .account_id
+        .map(str::to_string)
+        .or_else(|| clients.account.id.clone());
+    billing.account_alias = config
+        .account_name
+        .clone()
+        .or_else(|| clients.account.alias.clone());
+    if let Some(ref currency) = config.convert_to_currency {
+        billing.conversion = exchange_rate(config, currency, &clients.http)
+            .await
+            .map(|rate| billing::convert(billing.total, rate, currency));
+        if billing.conversion.is_none() {
+            billing
+                .degraded
+                .push(Degraded::Conversion.note(config.language));
+        }
+    }
+    if let Some(reason) = below_min_total(config, &billing) {
+        info!("suppressed: {}", reason);
+        return Ok(CustomOutput {
+            delivered_via: vec![],
+            receipts: vec![],
+            explanation: None,
+            markdown: None,
+            suppressed: Some(reason),
+        });
+    }
+    if config.notify_only_on_change {
+        let previous = state::get_previous_total(&clients.ssm, &retry, &tracer).await?;
+        if !billing::has_changed(previous, billing.total, config.min_change) {
+            info!(
+                total = %billing.total,
+                previous = ?previous,
+                "total barely changed, skipping the report"
+            );
+            return Ok(CustomOutput {
+                delivered_via: vec![],
+                receipts: vec![],
+                explanation: None,
+                markdown: None,
+                suppressed: Some("the total barely changed".to_string()),
+            });
+        }
+    }
+    let fingerprint = billing::fingerprint(&billing);
+    if config.skip_unchanged {
+        // without the previous report this one goes out, rather than none at all
+        let previous = match state::get_previous_report(&clients.ssm, &retry, &tracer).await {
+            Ok(previous) => previous,
+            Err(err) => {
+                warn!(
+                    error_kind = err.kind(),
+                    "failed to read the previous report, sending this one: {}", err
+                );
+                None
+            }
+        };
+        let previous = previous.as_ref().and_then(|previous| {
+            let sent_at = chrono::DateTime::from_timestamp(previous.sent_at, 0)?;
+            Some((previous.fingerprint.as_str(), sent_at))
+        });
+        if billing::is_unchanged(
+            previous,
+            &fingerprint,
+            Utc::now(),
+            config.skip_unchanged_max_age,
+        ) {
+            info!(fingerprint, "same figures as the last report, skipping it");
+            return Ok(CustomOutput {
+                delivered_via: vec![],
+                receipts: vec![],
+                explanation: None,
+                markdown: None,
+                suppressed: Some("the figures are the same as the last report's".to_string()),
+            });
+        }
+    }
+    if config.show_rank_changes {
+        // the ranks are only an extra, so the report goes out without them
+        match state::get_previous_ranks(&clients.ssm, &retry, &tracer).await {
+            Ok(previous) => {
+                let rank_changes = billing::rank_changes(&billing.ranking(), previous.as_deref());
+                billing.rank_changes = rank_changes;
+            }
+            Err(err) => {
+                warn!(
+                    error_kind = err.kind(),
+                    "skipping the rank changes: {}", err
+                );
+                billing
+                    .degraded
+                    .push(Degraded::RankChanges.note(config.language));
+            }
+        }
+    }
+    #[cfg(feature = "chart")]
+    if config.attach_chart {
+        // the chart is only an extra, so the report goes out without it
+        match put_chart(config, preset, clients, retry, &tracer, &billing).await {
+            Ok(url) => billing.chart_url = url,
+            Err(err) => {
+                warn!(error_kind = err.kind(), "skipping the chart: {}", err);
+                billing.degraded.push(Degraded::Chart.note(config.language));
+            }
+        }
+    }
+    #[cfg(feature = "templates")]
+    if let Some(templates) = &templates {
+        let severity = Severity::of(
+            billing.total,
+            config.warning_threshold,
+            config.alert_threshold,
+        );
+        match templates.render(&billing, severity) {
+            Ok((message, attachment)) => {
+                billing.message_text = message;
+                billing.attachment_text = attachment;
+            }
+            Err(err) => warn!(
+                error_kind = err.kind(),
+                "falling back to the built-in wording: {}", err
+            ),
+        }
+    }
+    let markdown = (config.message_format == MessageFormat::Markdown)
+        .then(|| notify::build_markdown(&billing, config.total_rounding));
+    if markdown.is_some() && config.markdown_only {
+        info!("MARKDOWN_ONLY is set, returning the report without notifying");
+        return Ok(CustomOutput {
+            delivered_via: vec![],
+            receipts: vec![],
+            explanation: None,
+            markdown,
+            suppressed: None,
+        });
+    }
+    let webhook_url = webhook_url(config, clients, &retry, &tracer).await?;
+    let bot_token = bot_token(config, clients, &retry, &tracer).await?;
+    let notifiers = notify::build_notifiers(
+        config,
+        preset,
+        webhook_url.as_ref(),
+        bot_token.as_ref(),
+        clients,
+        retry.deadline,
+    );
+    let receipts = async {
+        let started = Instant::now();
+        let receipts = notify::dispatch(
+            &notifiers,
+            &billing,
+            &tracer,
+            config.delivery,
+            config.notify_timeout,
+            retry.deadline,
+        )
+        .await?;
+        info!(
+            notifier_count = notifiers.len(),
+            delivered_via = ?receipts.iter().map(|receipt| receipt.channel).collect::<Vec<_>>(),
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "notified"
+        );
+        Ok::<_, AppError>(receipts)
+    }
+    .instrument(info_span!("notify"))
+    .await?;
+    // the report is out, so from here on failing would only get it sent twice on a retry
+    #[cfg(feature = "s3")]
+    if let Some(bucket) = &config.s3_report_bucket {
+        let date = billing
+            .generated_at
+            .with_timezone(&config.timezone)
+            .date_naive();
+        let call = s3::put_report(
+            &clients.s3,
+            &retry,
+            &tracer,
+            bucket,
+            &config.s3_report_prefix,
+            config.s3_report_csv,
+            &billing,
+            date,
+        );
+        match call.await {
+            Ok(keys) => info!(bucket = bucket.as_str(), keys = ?keys, "put the report in S3"),
+            Err(err) => warn!(
+                error_kind = err.kind(),
+                "failed to put the report in S3: {}", err
+            ),
+        }
+    }
+    if config.notify_only_on_change {
+        if let Err(err) =
+            state::put_previous_total(&clients.ssm, &retry, &tracer, billing.total).await
+        {
+            warn!(
+                error_kind = err.kind(),
+                "failed to store the total: {}", err
+            );
+        }
+    }
+    #[cfg(feature = "history-dynamodb")]
+    if let Some(table) = &config.history_table {
+        if let Err(err) = history::record(&clients.dynamodb, table, &billing, &retry, &tracer).await
+        {
+            warn!(
+                error_kind = err.kind(),
+                "report not recorded in {}: {}", table, err
+            );
+        }
+    }
+    if config.skip_unchanged {
+        let sent = state::SentReport {
+            fingerprint,
+            sent_at: Utc::now().timestamp(),
+        };
+        if let Err(err) = state::put_previous_report(&clients.ssm, &retry, &tracer, &sent).await {
+            warn!(
+                error_kind = err.kind(),
+                "failed to store the report's fingerprint: {}", err
+            );
+        }
+    }
+    if config.show_rank_changes {
+        if let Err(err) =
+            state::put_previous_ranks(&clients.ssm, &retry, &tracer, &billing.ranking()).await
+        {
+            warn!(
+                error_kind = err.kind(),
+                "failed to store the ranks: {}", err
+            );
+        }
+    }
+
+    Ok(CustomOutput {
+        delivered_via: receipts
+            .iter()
+            .map(|receipt| receipt.channel.to_string())
+            .collect(),
+        receipts,
+        explanation: None,
+        markdown,
+        suppressed: None,
+    })
+}
+
+/// Why `billing` is held back by `NOTIFY_MIN_TOTAL`, unless it's the `NOTIFY_FORCE_DAY`.
+fn below_min_total(config: &Config, billing: &Billing) -> Option<String> {
+    let min_total = config.notify_min_total?;
+    let today = billing.generated_at.with_timezone(&config.timezone).day();
+    (billing.total < min_total && config.notify_force_day != Some(today)).then(|| {
+        let money = |amount| notify::format_money(amount, &billing.currency, config.language);
+        format!("total {} below {}", money(billing.total), money(min_total))
+    })
+}
+
+/// Draws the daily spend of the month so far, or of the last `CHART_DAYS`, and puts it in S3,
+/// returning the URL Slack can fetch it from. `None` when there are too few days to draw.
+#[cfg(feature = "chart")]
+async fn put_chart(
+    config: &Config,
+    preset: &ReportPreset,
+    clients: &Clients,
+    retry: RetryPolicy,
+    tracer: &Tracer,
+    billing: &Billing,
+) -> Result<Option<String>, AppError> {
+    let bucket = config
+        .s3_report_bucket
+        .as_deref()
+        .ok_or_else(|| AppError::Config("ATTACH_CHART needs S3_REPORT_BUCKET".to_string()))?;
+    let cloudwatch = cloudwatch_facade(config, preset, clients, retry);
+    let days = config
+        .chart_days
+        .unwrap_or_else(|| i64::from(billing.period.end.day()));
+    let daily_totals = tracer
+        .trace(
+            "CloudWatch",
+            "aws",
+            cloudwatch.get_daily_totals(chrono::Duration::days(days)),
+        )
+        .await?;
+    let daily_spend = billing::daily_deltas(&daily_totals);
+    // e.g. early in the month, or on a new account
+    if daily_spend.len() < chart::MIN_BARS {
+        info!(
+            days = daily_spend.len(),
+            "too little history for the chart, leaving it out"
+        );
+        return Ok(None);
+    }
+    let png = chart::render_bar_chart(&daily_spend)?;
+    let date = billing
+        .generated_at
+        .with_timezone(&config.timezone)
+        .date_naive();
+    s3::put_chart(
+        &clients.s3,
+        &retry,
+        tracer,
+        bucket,
+        &config.s3_report_prefix,
+        png,
+        date,
+    )
+    .await
+    .map(Some)
+}
+
+/// Collects the bill from the sources `config` selects, without notifying anyone.
+pub async fn collect(
+    config: &Config,
+    preset: &ReportPreset,
+    clients: &Clients,
+    retry: RetryPolicy,
+    tracer: &Tracer,
+    metrics: &mut InvocationMetrics,
+) -> Result<Billing, AppError> {
+    let cloudwatch = cloudwatch_facade(config, preset, clients, retry);
+    #[cfg(feature = "cost-explorer")]
+    let cost_explorer =
+        CostExplorerFacade::new(clients.cost_explorer.clone(), retry, &config.exclude_types);
+    collect_billing(
+        &cloudwatch,
+        #[cfg(feature = "cost-explorer")]
+        &cost_explorer,
+        config,
+        preset,
+        tracer,
+        metrics,
+    )
+    .await
+}
+
+/// Runs the CloudWatch queries of a report and returns what they returned instead of notifying,
+/// for checking the figures of a report that looks wrong. SSM isn't read, so the webhook URL
+/// stays untouched.
+pub async fn explain(
+    config: &Config,
+    preset: &ReportPreset,
+    invocation: &Invocation<'_>,
+    clients: &Clients,
+) -> Result<CustomOutput, AppError> {
+    let mut retry = RetryPolicy::new(invocation.deadline);
+    retry.attempt_timeout = config.aws_timeout;
+    let tracer = Tracer::for_invocation(invocation.trace_id);
+    let cloudwatch = cloudwatch_facade(config, preset, clients, retry);
+    let total = tracer
+        .trace("CloudWatch", "aws", cloudwatch.get_raw_series(None))
+        .await?;
+    let names = tracer
+        .trace(
+            "CloudWatch",
+            "aws",
+            cloudwatch.get_services_in_billing_namespace(),
+        )
+        .await?;
+    let mut services = BTreeMap::new();
+    for name in names.values {
+        let call = cloudwatch.get_raw_series(Some(&name));
+        let series = tracer.trace("CloudWatch", "aws", call).await?;
+        services.insert(name, series);
+    }
+    info!(service_count = services.len(), "explained");
+    Ok(CustomOutput {
+        delivered_via: vec![],
+        receipts: vec![],
+        explanation: Some(Explanation { total, services }),
+        markdown: None,
+        suppressed: None,
+    })
+}
+
+/// Sends a test message through each of `NOTIFY_CHANNELS` instead of a report, for checking
+/// the channels (the webhook in SSM, say) after setting them up. CloudWatch isn't queried.
+pub async fn test_webhook(
+    config: &Config,
+    preset: &ReportPreset,
+    invocation: &Invocation<'_>,
+    clients: &Clients,
+) -> Result<CustomOutput, AppError> {
+    let mut retry = RetryPolicy::new(invocation.deadline);
+    retry.attempt_timeout = config.aws_timeout;
+    let tracer = Tracer::for_invocation(invocation.trace_id);
+    let webhook_url = webhook_url(config, clients, &retry, &tracer).await?;
+    let bot_token = bot_token(config, clients, &retry, &tracer).await?;
+    let notifiers = notify::build_notifiers(
+        config,
+        preset,
+        webhook_url.as_ref(),
+        bot_token.as_ref(),
+        clients,
+        retry.deadline,
+    );
+    let receipts = notify::dispatch_test(
+        &notifiers,
+        config
+            .language
+            .pick("請求通知のテスト ✅", "billing notifier test ✅"),
+        &tracer,
+        config.notify_timeout,
+        retry.deadline,
+    )
+    .await?;
+    Ok(CustomOutput {
+        delivered_via: receipts
+            .iter()
+            .map(|receipt| receipt.channel.to_string())
+            .collect(),
+        receipts,
+        explanation: None,
+        markdown: None,
+        suppressed: None,
+    })
+}
+
+/// The webhook URL, when one of the channels posts to it.
+async fn webhook_url(
+    config: &Config,
+    clients: &Clients,
+    retry: &RetryPolicy,
+    tracer: &Tracer,
+) -> Result<Option<Secret<String>>, AppError> {
+    if !config.channels.iter().any(|channel| channel.uses_webhook()) {
+        return Ok(None);
+    }
+    let url = notify::get_webhook_url(
+        &clients.ssm,
+        retry,
+        tracer,
+        config.webhook_url.as_ref(),
+        config.slack_webhook_host(),
+    )
+    .await?;
+    Ok(Some(url))
+}
+
+/// The Slack bot token, when the `slack-bot` channel is used.
+async fn bot_token(
+    config: &Config,
+    clients: &Clients,
+    retry: &RetryPolicy,
+    tracer: &Tracer,
+) -> Result<Option<Secret<String>>, AppError> {
+    if !config.uses_slack_bot() {
+        return Ok(None);
+    }
+    let token = notify::get_bot_token(&clients.ssm, retry, tracer).await?;
+    Ok(Some(token))
+}
+
+/// The span the metrics are queried over: `MTD`, else `ALIGN_TO_CALENDAR_DAY`, else the
+/// lookback of `preset` up to now.
+fn time_window(config: &Config, preset: &ReportPreset) -> TimeWindow {
+    if config.month_to_date {
+        TimeWindow::MonthToDate {
+            tz: config.timezone,
+        }
+    } else if config.align_to_calendar_day {
+        TimeWindow::CalendarDays {
+            days: preset.lookback.num_days(),
+            tz: config.timezone,
+        }
+    } else {
+        TimeWindow::Rolling(preset.lookback)
+    }
+}
+
+fn cloudwatch_facade(
+    config: &Config,
+    preset: &ReportPreset,
+    clients: &Clients,
+    retry: RetryPolicy,
+) -> CloudWatchFacade<CloudWatchClient> {
+    CloudWatchFacade::new(
+        clients.cloudwatch.clone(),
+        config.namespaces.clone(),
+        time_window(config, preset),
+        config
+            .include_currency_dimension
+            .then(|| config.currency.clone()),
+        config.billing_statistics.clone(),
+        retry,
+        Limiter::new(config.cloudwatch_max_concurrency, config.cloudwatch_max_rps),
+    )
+}
+
+/// Queries the total and the breakdown selected by `config.group_by`.
+pub async fn collect_billing<S: BillingMetricsSource>(
+    cloudwatch: &CloudWatchFacade<S>,
+    #[cfg(feature = "cost-explorer")] cost_explorer: &CostExplorerFacade,
+    config: &Config,
+    preset: &ReportPreset,
+    tracer: &Tracer,
+    metrics: &mut InvocationMetrics,
+) -> Result<Billing, AppError> {
+    let mut warnings = vec![];
+    let mut degraded = vec![];
+    // without the dimension there's no currency to check
+    if config.include_currency_dimension {
+        let available = tracer
+            .trace("CloudWatch", "aws", cloudwatch.get_available_currencies())
+            .await?;
+        // a currency missing from a partial list may well be on the pages that failed
+        if let Some(warning) =
+            billing::currency_mismatch_warning(&config.currency, &available.values, config.language)
+                .filter(|_| !available.incomplete)
+        {
+            warn!("{}", warning);
+            warnings.push(warning);
+        }
+    }
+    let total = run_stage(config, "fetch_total", || {
+        async {
+            let started = Instant::now();
+            let total = tracer
+                .trace("CloudWatch", "aws", cloudwatch.get_total_cost())
+                .await?;
+            info!(
+                total = %total.value,
+                elapsed_ms = started.elapsed().as_millis() as u64,
+                "fetched total"
+            );
+            Ok::<_, AppError>(total)
+        }
+        .instrument(info_span!("fetch_total"))
+    })
+    .await?;
+    if config.require_data && total.datapoints == 0 {
+        return Err(AppError::NoDatapoints {
+            currency: config.currency.clone(),
+        });
+    }
+    let now = Utc::now();
+    if let Some(warning) = billing::staleness_warning(
+        total.as_of,
+        now,
+        config.staleness_threshold,
+        config.language,
+    ) {
+        warn!("{}", warning);
+        warnings.push(warning);
+    }
+    // only broken down by account with `GROUP_BY=account,service`
+    #[cfg_attr(not(feature = "cost-explorer"), allow(unused_mut))]
+    let mut accounts = vec![];
+    let costs = match config.group_by {
+        // CloudWatch has no usage metrics
+        #[cfg(feature = "cost-explorer")]
+        GroupBy::Service if config.show_usage || config.hybrid => {
+            let call = cost_explorer.get_costs_and_usage_by_service(Utc::now().date_naive());
+            let mut costs = tracer.trace("Cost Explorer", "aws", call).await?;
+            metrics.services_queried = costs.len();
+            if !config.show_usage {
+                for cost in &mut costs {
+                    cost.usage = None;
+                }
+            }
+            costs
+        }
+        GroupBy::Service if config.skip_services_when_zero && total.value.is_zero() => {
+            info!("the total is zero, skipping the per-service queries");
+            vec![]
+        }
+        GroupBy::Service => {
+            let services = run_stage(config, "list_services", || {
+                async {
+                    let started = Instant::now();
+                    let mut subsegment = tracer.subsegment("CloudWatch", "aws");
+                    let services = cloudwatch.get_services_in_billing_namespace().await;
+                    if let Ok(ref services) = services {
+                        subsegment.annotate("service_count", services.values.len());
+                    }
+                    subsegment.end(services.is_err());
+                    let services = services?;
+                    info!(
+                        service_count = services.values.len(),
+                        incomplete = services.incomplete,
+                        elapsed_ms = started.elapsed().as_millis() as u64,
+                        "listed services"
+                    );
+                    Ok::<_, AppError>(services)
+                }
+                .instrument(info_span!("list_services"))
+            })
+            .await?;
+            if services.incomplete {
+                let warning = billing::incomplete_services_warning(config.language);
+                warn!("{}", warning);
+                warnings.push(warning);
+                degraded.push(Degraded::ServiceList.note(config.language));
+            }
+            let services = services.values;
+            async {
+                let started = Instant::now();
+                // with the index of the service, to put them back in order once all are in
+                let mut costs = Vec::with_capacity(services.len());
+                // with `HANDLER_RETRY=true` failed services are queried again after the rest,
+                // keeping the costs already fetched
+                let attempts = if config.handler_retry { 2 } else { 1 };
+                let mut pending: Vec<(usize, &str)> =
+                    services.iter().map(String::as_str).enumerate().collect();
+                for attempt in 1..=attempts {
+                    let mut failed = vec![];
+                    // up to `CLOUDWATCH_MAX_CONCURRENCY` at once, each in its own subsegment
+                    let mut results = stream::iter(pending)
+                        .map(|(index, service)| async move {
+                            let mut subsegment = tracer.subsegment("CloudWatch", "aws");
+                            subsegment.annotate("service_name", service);
+                            let cost = cloudwatch.get_cost(service).await;
+                            subsegment.end(cost.is_err());
+                            (index, service, cost)
+                        })
+                        .buffer_unordered(config.cloudwatch_max_concurrency);
+                    while let Some((index, service, cost)) = results.next().await {
+                        // each service once, however many attempts it took
+                        if attempt == 1 {
+                            metrics.services_queried += 1;
+                        }
+                        match cost {
+                            Ok(cost) => costs.push((index, cost)),
+                            Err(err) => {
+                                if attempt == attempts {
+                                    metrics.services_failed += 1;
+                                    return Err(err);
+                                }
+                                warn!(
+                                    service,
+                                    error_kind = err.kind(),
+                                    "fetching the cost failed, trying again after the others: {}",
+                                    err
+                                );
+                                failed.push((index, service));
+                            }
+                        }
+                    }
+                    if failed.is_empty() {
+                        break;
+                    }
+                    pending = failed;
+                }
+                costs.sort_by_key(|(index, _)| *index);
+                let costs: Vec<_> = costs.into_iter().map(|(_, cost)| cost).collect();
+                info!(
+                    service_count = costs.len(),
+                    elapsed_ms = started.elapsed().as_millis() as u64,
+                    "fetched service costs"
+                );
+                Ok::<_, AppError>(costs)
+            }
+            .instrument(info_span!("fetch_service_costs"))
+            .await?
+        }
+        #[cfg(feature = "cost-explorer")]
+        GroupBy::ServiceAndRegion => {
+            let call = cost_explorer.get_costs_by_service_and_region(Utc::now().date_naive());
+            let costs = tracer.trace("Cost Explorer", "aws", call).await?;
+            metrics.services_queried = costs.len();
+            costs
+        }
+        #[cfg(feature = "cost-explorer")]
+        GroupBy::Region => {
+            let call = cost_explorer.get_costs_by_region(Utc::now().date_naive(), config.language);
+            let costs = tracer.trace("Cost Explorer", "aws", call).await?;
+            metrics.services_queried = costs.len();
+            costs
+        }
+        #[cfg(feature = "cost-explorer")]
+        GroupBy::AccountAndService => {
+            let call = cost_explorer.get_costs_by_account_and_service(Utc::now().date_naive());
+            let costs = tracer.trace("Cost Explorer", "aws", call).await?;
+            metrics.services_queried = costs.len();
+            let (by_account, costs) = billing::group_by_account(costs);
+            accounts = by_account;
+            costs
+        }
+        // rejected by `Config::from_env`
+        #[cfg(not(feature = "cost-explorer"))]
+        GroupBy::ServiceAndRegion | GroupBy::Region | GroupBy::AccountAndService => {
+            return Err(AppError::Config(
+                "GROUP_BY other than service needs the cost-explorer feature".to_string(),
+            ))
+        }
+    };
+    let previous_costs = if !config.show_changes {
+        None
+    } else if config.uses_cost_explorer() {
+        warn!("SHOW_CHANGES is ignored since the breakdown comes from Cost Explorer");
+        None
+    } else {
+        let mut previous = Vec::with_capacity(costs.len());
+        for service in &costs {
+            let call = cloudwatch.get_previous_cost(&service.name, chrono::Duration::days(1));
+            previous.push(tracer.trace("CloudWatch", "aws", call).await?);
+        }
+        Some(previous)
+    };
+    let mut costs = billing::roll_up(costs, &config.service_rollup);
+    let changes = match previous_costs {
+        Some(previous) => {
+            billing::cost_changes(&costs, &billing::roll_up(previous, &config.service_rollup))
+        }
+        None => BTreeMap::new(),
+    };
+    let increases = billing::top_increases(&costs, &changes, config.increase_floor);
+    // `EstimatedCharges` includes every record type, so the total without the excluded ones
+    // has to be summed up from the filtered breakdown, before it is cut down to the top N
+    let total_value = if config.exclude_types.is_empty() {
+        total.value
+    } else if config.uses_cost_explorer() {
+        costs.iter().map(|cost| cost.cost).sum()
+    } else {
+        warn!("EXCLUDE_TYPES is ignored since the breakdown comes from CloudWatch");
+        total.value
+    };
+    // before the breakdown is cut down, which would leave it short on purpose
+    if let Some(warning) = billing::discrepancy_warning(
+        total_value,
+        &costs,
+        config.discrepancy_tolerance,
+        &config.currency,
+        config.language,
+    ) {
+        warn!("{}", warning);
+        warnings.push(warning);
+    }
+    if config.hide_negative {
+        costs.retain(|cost| cost.cost >= Decimal::ZERO);
+    }
+    let mut hidden = vec![];
+    costs.retain(|cost| {
+        let hide = config.hide_from_breakdown.contains(&cost.name);
+        if hide && !cost.cost.is_zero() {
+            hidden.push(cost.name.clone());
+        }
+        !hide
+    });
+    if let Some(n) = preset.top_n {
+        billing::keep_most_expensive(&mut costs, n);
+    }
+    let digest = match config.digest_period {
+        Some(period) => {
+            let call = cloudwatch.get_daily_totals(period.window());
+            let daily_totals = tracer.trace("CloudWatch", "aws", call).await?;
+            Some(Digest {
+                period,
+                spend: billing::sum_daily_deltas(&daily_totals),
+            })
+        }
+        None => None,
+    };
+    // the sparkline is only an extra, so the report goes out without it
+    let daily_spend = if config.show_sparkline {
+        let call = cloudwatch.get_daily_totals(chrono::Duration::days(notify::SPARKLINE_DAYS));
+        match tracer.trace("CloudWatch", "aws", call).await {
+            Ok(daily_totals) => billing::daily_deltas(&daily_totals),
+            Err(err) => {
+                warn!(error_kind = err.kind(), "skipping the sparkline: {}", err);
+                degraded.push(Degraded::Sparkline.note(config.language));
+                vec![]
+            }
+        }
+    } else {
+        vec![]
+    };
+
+    let mut billing = Billing {
+        generated_at: now,
+        as_of: total.as_of,
+        total: total_value,
+        currency: config.currency.clone(),
+        services: costs,
+        warnings,
+        degraded,
+        digest,
+        changes,
+        increases,
+        rank_changes: BTreeMap::new(),
+        forecasts: BTreeMap::new(),
+        request_id: None,
+        period: Period::month_to_date(total.as_of.unwrap_or(now)),
+        account_id: None,
+        account_alias: None,
+        conversion: None,
+        timezone: config.timezone,
+        language: config.language,
+        accounts,
+        daily_spend,
+        chart_url: None,
+        message_text: None,
+        attachment_text: None,
+        hidden,
+        // only worth a line next to the maximum
+        statistics: if total.statistics.len() > 1 {
+            total.statistics
+        } else {
+            vec![]
+        },
+        budget: config.monthly_budget.map(|amount| Budget {
+            amount,
+            month_elapsed: billing::month_elapsed_percent(now, config.timezone),
+        }),
+    };
+    billing.sort_services();
+    // forecasts are by service, which the other groupings can't be matched to
+    #[cfg(feature = "cost-explorer")]
+    if config.show_forecast && config.group_by == GroupBy::Service {
+        billing.forecasts = get_forecasts(cost_explorer, &billing.services, tracer).await;
+        let missing = billing.services.len().min(MAX_FORECASTS) - billing.forecasts.len();
+        if missing > 0 {
+            billing
+                .degraded
+                .push(Degraded::Forecasts(missing).note(config.language));
+        }
+    }
+    // after sorting, so the folded entry stays last
+    if let Some(min_share) = config.other_threshold {
+        billing.services = billing::fold_long_tail(billing.services, min_share, config.language);
+    }
+    Ok(billing)
+}
+
+/// The `EXCHANGE_RATE`, or else the one fetched from `EXCHANGE_RATE_URL`. A failed fetch only
+/// leaves the conversion out of the report.
+async fn exchange_rate(config: &Config, to: &str, http: &reqwest::Client) -> Option<Decimal> {
+    if config.exchange_rate.is_some() {
+        return config.exchange_rate;
+    }
+    let url = config.exchange_rate_url.as_deref()?;
+    match exchange::fetch_rate(http, url, &config.currency, to, config.notify_timeout).await {
+        Ok(rate) => {
+            info!(%rate, "fetched the exchange rate");
+            Some(rate)
+        }
+        Err(err) => {
+            warn!(
+                error_kind = err.kind(),
+                "leaving out the converted total: {}", err
+            );
+            None
+        }
+    }
+}
+
+/// Runs `stage`, and with `HANDLER_RETRY=true` once more when it failed even after the retries
+/// of its calls, e.g. when throttling outlasted them.
+async fn run_stage<T, F, Fut>(config: &Config, name: &str, stage: F) -> Result<T, AppError>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T, AppError>>,
+{
+    match stage().await {
+        Err(err) if config.handler_retry => {
+            warn!(
+                stage = name,
+                error_kind = err.kind(),
+                "stage failed, running it again: {}",
+                err
+            );
+            stage().await
+        }
+        result => result,
+    }
+}
+
+/// Most forecasts per report, since each is a billed Cost Explorer call.
+#[cfg(feature = "cost-explorer")]
+const MAX_FORECASTS: usize = 3;
+
+/// End-of-month forecasts of the most expensive of `services`, sorted by cost. Services Cost
+/// Explorer can't forecast, e.g. new ones without history, are left out.
+#[cfg(feature = "cost-explorer")]
+async fn get_forecasts(
+    cost_explorer: &CostExplorerFacade,
+    services: &[ServiceBilling],
+    tracer: &Tracer,
+) -> BTreeMap<String, Decimal> {
+    let today = Utc::now().date_naive();
+    let mut forecasts = BTreeMap::new();
+    for service in services.iter().take(MAX_FORECASTS) {
+        // CloudWatch names the services differently
+        let name =
+            console::cost_explorer_service_name(&service.name).unwrap_or(service.name.as_str());
+        let call = cost_explorer.get_rest_of_month_forecast(name, today);
+        match tracer.trace("Cost Explorer", "aws", call).await {
+            Ok(rest_of_month) => {
+                forecasts.insert(service.name.clone(), service.cost + rest_of_month);
+            }
+            Err(err) => warn!(
+                service = service.name.as_str(),
+                error_kind = err.kind(),
+                "skipping the forecast: {}",
+                err
+            ),
+        }
+    }
+    forecasts
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone};
+    use chrono_tz::Tz;
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::{self, FakeMetricsSource, MockResponse, MockServer};
+
+    #[test]
+    fn scheduler_payload_selects_the_report_type() {
+        // EventBridge Scheduler passes the target input as it is, next to nothing else
+        let event: CustomEvent = serde_json::from_value(json!({"report_type": "weekly"})).unwrap();
+        assert_eq!(event.report_type.as_deref(), Some("weekly"));
+        let event: CustomEvent = serde_json::from_value(json!({})).unwrap();
+        assert_eq!(event.report_type, None);
+        // a scheduled rule's event, whose fields are all ignored
+        let event: CustomEvent = serde_json::from_value(json!({
+            "version": "0",
+            "detail-type": "Scheduled Event",
+            "source": "aws.events",
+            "detail": {}
+        }))
+        .unwrap();
+        assert_eq!(event.report_type, None);
+        assert_eq!(event.mode, None);
+    }
+
+    /// The report `collect_billing` makes of `source` with `vars` set.
+    async fn collect(
+        source: &FakeMetricsSource,
+        vars: &[(&str, &str)],
+    ) -> Result<Billing, AppError> {
+        collect_with(
+            source,
+            #[cfg(feature = "cost-explorer")]
+            &testing::cost_explorer(),
+            vars,
+        )
+        .await
+    }
+
+    async fn collect_with(
+        source: &FakeMetricsSource,
+        #[cfg(feature = "cost-explorer")] cost_explorer: &CostExplorerFacade,
+        vars: &[(&str, &str)],
+    ) -> Result<Billing, AppError> {
+        let config = testing::config(vars);
+        let preset = ReportType::Daily.preset();
+        let cloudwatch = CloudWatchFacade::new(
+            source,
+            config.namespaces.clone(),
+            time_window(&config, &preset),
+            config
+                .include_currency_dimension
+                .then(|| config.currency.clone()),
+            config.billing_statistics.clone(),
+            RetryPolicy::new(None),
+            Limiter::new(5, 0.0),
+        );
+        collect_billing(
+            &cloudwatch,
+            #[cfg(feature = "cost-explorer")]
+            cost_explorer,
+            &config,
+            &preset,
+            &Tracer::disabled(),
+            &mut InvocationMetrics::new(ReportType::Daily),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn no_datapoints_fail_only_with_require_data() {
+        let source = FakeMetricsSource::default().with_page(vec![vec![("Currency", "USD")]]);
+        let billing = collect(&source, &[]).await.unwrap();
+        assert_eq!(billing.total, Decimal::ZERO);
+
+        match collect(&source, &[("REQUIRE_DATA", "true")]).await {
+            Err(AppError::NoDatapoints { currency }) => assert_eq!(currency, "USD"),
+            Err(err) => panic!("expected NoDatapoints, got {:?}", err),
+            Ok(_) => panic!("expected NoDatapoints"),
+        }
+    }
+
+    #[test]
+    fn region_names_the_sdk_does_not_know_are_accepted() {
+        for region in [
+            "ap-northeast-1",
+            "us-gov-west-1",
+            " eu-central-3 ",
+            "xx-newregion-9",
+        ] {
+            assert_eq!(
+                parse_region(region).unwrap().as_ref(),
+                region.trim(),
+                "{:?}",
+                region
+            );
+        }
+    }
+
+    #[test]
+    fn implausible_region_names_are_rejected() {
+        for region in ["", "us east 1", "US-EAST-1", "useast1", "1-us-east"] {
+            match parse_region(region) {
+                Err(AppError::Config(message)) => {
+                    assert!(message.starts_with("invalid AWS_REGION: "), "{}", message)
+                }
+                other => panic!("{:?}: {:?}", region, other.map(|region| region.to_string())),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn report_runs_without_a_lambda_context() {
+        let server = MockServer::start(vec![MockResponse::new(500, "{}")]);
+        let clients = testing::clients(&server.url).await;
+        let config = testing::config(&[]);
+        let invocation = Invocation {
+            // already passed, so the first query gets no time at all
+            deadline: Some(SystemTime::now() - StdDuration::from_secs(1)),
+            ..Invocation::default()
+        };
+        let err = report(
+            &config,
+            &ReportType::Detailed.preset(),
+            &invocation,
+            &clients,
+            &mut InvocationMetrics::new(ReportType::Detailed),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(err.to_string(), "ListMetrics timed out after 0ns");
+    }
+
+    #[tokio::test]
+    async fn test_webhook_posts_without_querying_cloudwatch() {
+        let server = MockServer::start(vec![MockResponse::new(200, "ok")]);
+        let clients = testing::clients(&server.url).await;
+        let mut config =
+            testing::config(&[("NOTIFY_CHANNELS", "generic-webhook"), ("LANGUAGE", "en")]);
+        // set past the check for https, which the mock server doesn't speak
+        config.webhook_url = Some(Secret::new(format!("{}/hooks/test", server.url)));
+        let output = test_webhook(
+            &config,
+            &ReportType::Detailed.preset(),
+            &Invocation::default(),
+            &clients,
+        )
+        .await
+        .unwrap();
+        assert_eq!(output.delivered_via, ["generic-webhook"]);
+        // the test message is the only request, so CloudWatch was left alone
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].path, "/hooks/test");
+        assert!(requests[0].body.contains("billing notifier test ✅"));
+    }
+
+    #[tokio::test]
+    async fn hide_negative_leaves_credits_out_of_the_breakdown() {
+        let source = FakeMetricsSource::default()
+            .with_page(vec![
+                vec![("Currency", "USD")],
+                vec![("Currency", "USD"), ("ServiceName", "AmazonEC2")],
+                vec![("Currency", "USD"), ("ServiceName", "Credits")],
+            ])
+            .with_datapoints(vec![("Currency", "USD")], vec![Some(8.0)])
+            .with_datapoints(
+                vec![("Currency", "USD"), ("ServiceName", "AmazonEC2")],
+                vec![Some(10.0)],
+            )
+            .with_datapoints(
+                vec![("Currency", "USD"), ("ServiceName", "Credits")],
+                vec![Some(-2.0)],
+            );
+        let names = |billing: &Billing| {
+            billing
+                .services
+                .iter()
+                .map(|service| service.name.clone())
+                .collect::<Vec<_>>()
+        };
+        let billing = collect(&source, &[]).await.unwrap();
+        assert_eq!(names(&billing), ["AmazonEC2", "Credits"]);
+        let billing = collect(&source, &[("HIDE_NEGATIVE", "true")])
+            .await
+            .unwrap();
+        assert_eq!(names(&billing), ["AmazonEC2"]);
+        assert_eq!(billing.total, Decimal::from(8));
+    }
+
+    #[cfg(feature = "cost-explorer")]
+    #[tokio::test]
+    async fn hybrid_takes_the_breakdown_from_cost_explorer() {
+        let server = MockServer::start(vec![MockResponse::new(
+            200,
+            r#"{"ResultsByTime": [{"Groups": [
+                {"Keys": ["Amazon Simple Storage Service"], "Metrics": {
+                    "UnblendedCost": {"Amount": "3.5", "Unit": "USD"},
+                    "UsageQuantity": {"Amount": "100", "Unit": "GB-Mo"}}},
+                {"Keys": ["AWS Lambda"], "Metrics": {
+                    "UnblendedCost": {"Amount": "1", "Unit": "USD"},
+                    "UsageQuantity": {"Amount": "2000", "Unit": "Requests"}}}
+            ]}]}"#,
+        )]);
+        let source = FakeMetricsSource::default()
+            .with_page(vec![vec![("Currency", "USD")]])
+            .with_datapoints(vec![("Currency", "USD")], vec![Some(5.0)]);
+        let billing = collect_with(
+            &source,
+            &testing::cost_explorer_at(&server.url),
+            &[("HYBRID", "true")],
+        )
+        .await
+        .unwrap();
+        assert_eq!(billing.total, Decimal::from(5));
+        let names: Vec<_> = billing
+            .services
+            .iter()
+            .map(|service| service.name.as_str())
+            .collect();
+        assert_eq!(names, ["Amazon Simple Storage Service", "AWS Lambda"]);
+        // usage is only shown with SHOW_USAGE
+        assert!(billing
+            .services
+            .iter()
+            .all(|service| service.usage.is_none()));
+        let request = &server.requests()[0];
+        assert_eq!(
+            request.header("x-amz-target"),
+            Some("AWSInsightsIndexService.GetCostAndUsage")
+        );
+        // CloudWatch was only asked for the total
+        assert_eq!(source.statistics_requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn handler_retry_queries_failed_services_again() {
+        let ec2 = vec![("Currency", "USD"), ("ServiceName", "AmazonEC2")];
+        let source = || {
+            FakeMetricsSource::default()
+                .with_page(vec![
+                    vec![("Currency", "USD")],
+                    ec2.clone(),
+                    vec![("Currency", "USD"), ("ServiceName", "AmazonS3")],
+                ])
+                .with_datapoints(vec![("Currency", "USD")], vec![Some(15.5)])
+                .with_datapoints(ec2.clone(), vec![Some(12.0)])
+                .with_datapoints(
+                    vec![("Currency", "USD"), ("ServiceName", "AmazonS3")],
+                    vec![Some(3.5)],
+                )
+                // outlasting the retries of one call
+                .failing(ec2.clone(), 3)
+        };
+        let err = collect(&source(), &[]).await.err().unwrap();
+        assert_eq!(err.kind(), "CloudWatch");
+
+        let source = source();
+        let billing = collect(&source, &[("HANDLER_RETRY", "true")])
+            .await
+            .unwrap();
+        assert_eq!(billing.services.len(), 2);
+        let services: Vec<_> = source
+            .statistics_requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|request| {
+                request
+                    .dimensions
+                    .iter()
+                    .find(|(name, _)| name == "ServiceName")
+                    .map(|(_, value)| value.clone())
+            })
+            .collect();
+        // S3 only once, while EC2 was retried, and EC2 again after the rest
+        assert_eq!(services.len(), 5);
+        assert_eq!(
+            services.iter().filter(|name| *name == "AmazonS3").count(),
+            1
+        );
+        assert_eq!(services.last().unwrap(), "AmazonEC2");
+    }
+
+    #[tokio::test]
+    async fn handler_retry_runs_a_failed_stage_again() {
+        let source = || {
+            FakeMetricsSource::default()
+                .with_page(vec![vec![("Currency", "USD")]])
+                .with_datapoints(vec![("Currency", "USD")], vec![Some(15.5)])
+                .failing(vec![("Currency", "USD")], 3)
+        };
+        assert!(collect(&source(), &[]).await.is_err());
+        let billing = collect(&source(), &[("HANDLER_RETRY", "true")])
+            .await
+            .unwrap();
+        assert_eq!(billing.total, Decimal::new(155, 1));
+    }
+
+    #[test]
+    fn account_id_comes_from_the_function_arn() {
+        assert_eq!(
+            account_id_of("arn:aws:lambda:us-east-1:123456789012:function:billing"),
+            Some("123456789012")
+        );
+        assert_eq!(
+            account_id_of("arn:aws:lambda:us-east-1::function:billing"),
+            None
+        );
+        assert_eq!(account_id_of(""), None);
+    }
+
+    #[tokio::test]
+    async fn hide_from_breakdown_keeps_services_in_the_total_only() {
+        let source = FakeMetricsSource::default()
+            .with_page(vec![
+                vec![("Currency", "USD")],
+                vec![("Currency", "USD"), ("ServiceName", "AmazonEC2")],
+                vec![("Currency", "USD"), ("ServiceName", "Tax")],
+                vec![("Currency", "USD"), ("ServiceName", "AWSSupport")],
+            ])
+            .with_datapoints(vec![("Currency", "USD")], vec![Some(11.0)])
+            .with_datapoints(
+                vec![("Currency", "USD"), ("ServiceName", "AmazonEC2")],
+                vec![Some(10.0)],
+            )
+            .with_datapoints(
+                vec![("Currency", "USD"), ("ServiceName", "Tax")],
+                vec![Some(1.0)],
+            )
+            .with_datapoints(
+                vec![("Currency", "USD"), ("ServiceName", "AWSSupport")],
+                vec![Some(0.0)],
+            );
+        let billing = collect(&source, &[("HIDE_FROM_BREAKDOWN", "Tax, AWSSupport")])
+            .await
+            .unwrap();
+        let names: Vec<_> = billing
+            .services
+            .iter()
+            .map(|service| service.name.as_str())
+            .collect();
+        assert_eq!(names, ["AmazonEC2"]);
+        assert_eq!(billing.total, Decimal::from(11));
+        // only what costs anything is noted
+        assert_eq!(billing.hidden, ["Tax"]);
+    }
+
+    #[tokio::test]
+    async fn show_sparkline_takes_the_spend_of_each_day() {
+        let day = |day| Some(Utc.with_ymd_and_hms(2024, 6, day, 0, 0, 0).unwrap());
+        let source = FakeMetricsSource::default()
+            .with_page(vec![vec![("Currency", "USD")]])
+            .with_timed_datapoints(
+                vec![("Currency", "USD")],
+                vec![
+                    (day(11), Some(10.0)),
+                    (day(12), Some(15.0)),
+                    (day(13), Some(18.0)),
+                    (day(14), Some(26.0)),
+                ],
+            );
+        let billing = collect(&source, &[]).await.unwrap();
+        assert!(billing.daily_spend.is_empty());
+        let billing = collect(&source, &[("SHOW_SPARKLINE", "true")])
+            .await
+            .unwrap();
+        assert_eq!(
+            billing.daily_spend,
+            [Decimal::from(5), Decimal::from(3), Decimal::from(8)]
+        );
+        assert_eq!(billing.total, Decimal::from(26));
+    }
+
+    #[test]
+    fn mtd_overrides_align_to_calendar_day() {
+        let preset = ReportType::Weekly.preset();
+        let window = |vars: &[(&str, &str)]| time_window(&testing::config(vars), &preset);
+        let vars = [("BILLING_TIMEZONE", "Asia/Tokyo")];
+        assert_eq!(window(&vars), TimeWindow::Rolling(Duration::days(7)));
+        let vars = [vars[0], ("ALIGN_TO_CALENDAR_DAY", "true")];
+        assert_eq!(
+            window(&vars),
+            TimeWindow::CalendarDays {
+                days: 7,
+                tz: Tz::Asia__Tokyo
+            }
+        );
+        let vars = [vars[0], vars[1], ("MTD", "true")];
+        assert_eq!(
+            window(&vars),
+            TimeWindow::MonthToDate {
+                tz: Tz::Asia__Tokyo
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn metrics_without_a_currency_dimension() {
+        let source = FakeMetricsSource::default()
+            .in_namespace("Custom/Billing")
+            .with_page(vec![vec![("ServiceName", "AmazonEC2")]])
+            .with_datapoints(vec![], vec![Some(7.0)])
+            .with_datapoints(vec![("ServiceName", "AmazonEC2")], vec![Some(7.0)]);
+        let vars = [
+            ("BILLING_NAMESPACE", "Custom/Billing"),
+            ("INCLUDE_CURRENCY_DIMENSION", "false"),
+        ];
+        let billing = collect(&source, &vars).await.unwrap();
+        assert_eq!(billing.total, Decimal::from(7));
+        assert_eq!(billing.services[0].name, "AmazonEC2");
+        // no currency to check either
+        let requests = source.list_requests.lock().unwrap();
+        assert!(requests
+            .iter()
+            .all(|request| request.dimension_name != "Currency"));
+        let requests = source.statistics_requests.lock().unwrap();
+        assert!(requests.iter().all(|request| request
+            .dimensions
+            .iter()
+            .all(|(name, _)| name != "Currency")));
+    }
+
+    #[tokio::test]
+    async fn skip_services_when_zero_only_queries_the_total() {
+        let source = || {
+            FakeMetricsSource::default()
+                .with_page(vec![
+                    vec![("Currency", "USD")],
+                    vec![("Currency", "USD"), ("ServiceName", "AmazonEC2")],
+                ])
+                .with_datapoints(vec![("Currency", "USD")], vec![Some(0.0)])
+                .with_datapoints(
+                    vec![("Currency", "USD"), ("ServiceName", "AmazonEC2")],
+                    vec![Some(0.0)],
+                )
+        };
+        let queried = |source: &FakeMetricsSource| source.statistics_requests.lock().unwrap().len();
+        let all = source();
+        let billing = collect(&all, &[]).await.unwrap();
+        assert_eq!(billing.services.len(), 1);
+        assert_eq!(queried(&all), 2);
+
+        let skipping = source();
+        let billing = collect(&skipping, &[("SKIP_SERVICES_WHEN_ZERO", "true")])
+            .await
+            .unwrap();
+        assert!(billing.services.is_empty());
+        assert_eq!(billing.total, Decimal::ZERO);
+        assert_eq!(queried(&skipping), 1);
+    }
+
+    #[test]
+    fn reports_below_notify_min_total_are_held_back() {
+        // generated 2024-06-14 09:00 UTC
+        let mut billing = testing::billing(&[("AmazonEC2", "5")]);
+        billing.total = Decimal::from(5);
+        let held_back = |vars: &[(&str, &str)]| below_min_total(&testing::config(vars), &billing);
+        assert_eq!(held_back(&[]), None);
+        assert_eq!(
+            held_back(&[("NOTIFY_MIN_TOTAL", "10")]).as_deref(),
+            Some("total $5 below $10")
+        );
+        assert_eq!(held_back(&[("NOTIFY_MIN_TOTAL", "5")]), None);
+        let force_day = [("NOTIFY_MIN_TOTAL", "10"), ("NOTIFY_FORCE_DAY", "14")];
+        assert_eq!(held_back(&force_day), None);
+        // the day is the one in BILLING_TIMEZONE, still the 13th in Honolulu
+        let force_day = [
+            ("NOTIFY_MIN_TOTAL", "10"),
+            ("NOTIFY_FORCE_DAY", "13"),
+            ("BILLING_TIMEZONE", "Pacific/Honolulu"),
+        ];
+        assert_eq!(held_back(&force_day), None);
+    }
+
+    #[tokio::test]
+    async fn incomplete_service_list_is_warned_about() {
+        let source = FakeMetricsSource::default()
+            .with_page(vec![
+                vec![("Currency", "USD")],
+                vec![("Currency", "USD"), ("ServiceName", "AmazonEC2")],
+            ])
+            .with_page(vec![vec![("Currency", "USD"), ("ServiceName", "AmazonS3")]])
+            .failing_page(1)
+            .with_datapoints(vec![("Currency", "USD")], vec![Some(12.0)])
+            .with_datapoints(
+                vec![("Currency", "USD"), ("ServiceName", "AmazonEC2")],
+                vec![Some(10.0)],
+            );
+        let billing = collect(&source, &[]).await.unwrap();
+        assert_eq!(billing.services.len(), 1);
+        assert_eq!(billing.services[0].name, "AmazonEC2");
+        assert!(billing
+            .warnings
+            .contains(&billing::incomplete_services_warning(billing.language)));
+        assert_eq!(billing.degraded, ["サービス一覧が不完全"]);
+    }
+}
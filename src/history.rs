@@ -0,0 +1,101 @@
+//! Keeping each report in DynamoDB (`HISTORY_TABLE`), so the spend can be looked back on
+//! after the messages are gone.
+
+use std::collections::HashMap;
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use chrono::SecondsFormat;
+
+use crate::billing::Billing;
+use crate::retry::{self, RetryPolicy};
+use crate::xray::Tracer;
+use crate::AppError;
+
+/// The item a report is stored as, keyed by `generated_at`:
+///
+/// `{"generated_at": S, "currency": S, "total": N, "services": M{name: N}}`
+pub fn history_item(billing: &Billing) -> HashMap<String, AttributeValue> {
+    let services = billing
+        .services
+        .iter()
+        .map(|service| {
+            (
+                service.name.clone(),
+                AttributeValue::N(service.cost.to_string()),
+            )
+        })
+        .collect();
+    HashMap::from([
+        (
+            "generated_at".to_string(),
+            AttributeValue::S(
+                billing
+                    .generated_at
+                    .to_rfc3339_opts(SecondsFormat::Secs, true),
+            ),
+        ),
+        (
+            "currency".to_string(),
+            AttributeValue::S(billing.currency.clone()),
+        ),
+        (
+            "total".to_string(),
+            AttributeValue::N(billing.total.to_string()),
+        ),
+        ("services".to_string(), AttributeValue::M(services)),
+    ])
+}
+
+/// Stores the report in `table`.
+pub async fn record(
+    dynamodb: &DynamoDbClient,
+    table: &str,
+    billing: &Billing,
+    retry: &RetryPolicy,
+    tracer: &Tracer,
+) -> Result<(), AppError> {
+    let item = history_item(billing);
+    let call = retry::with_retry(retry, "PutItem", || async {
+        dynamodb
+            .put_item()
+            .table_name(table)
+            .set_item(Some(item.clone()))
+            .send()
+            .await
+            .map_err(|err| AppError::History {
+                operation: "PutItem",
+                source: err.map_service_error(Into::into),
+            })
+    });
+    tracer.trace("DynamoDB", "aws", call).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn the_item_keeps_the_total_and_every_service() {
+        let report = testing::billing(&[("AmazonEC2", "10.5"), ("AmazonS3", "2")]);
+        let item = history_item(&report);
+        assert_eq!(
+            item["generated_at"],
+            AttributeValue::S("2024-06-14T09:00:00Z".to_string())
+        );
+        assert_eq!(item["currency"], AttributeValue::S("USD".to_string()));
+        assert_eq!(item["total"], AttributeValue::N("12.5".to_string()));
+        assert_eq!(
+            item["services"],
+            AttributeValue::M(HashMap::from([
+                (
+                    "AmazonEC2".to_string(),
+                    AttributeValue::N("10.5".to_string())
+                ),
+                ("AmazonS3".to_string(), AttributeValue::N("2".to_string())),
+            ]))
+        );
+    }
+}
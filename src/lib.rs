@@ -0,0 +1,76 @@
+//! Collects the month-to-date AWS bill and posts it to Slack (or another webhook).
+//!
+//! `main.rs` only wires [`handler::handle`] into the Lambda runtime, so everything here can be
+//! driven without it, as the `billing-report` command (feature `cli`) does.
+
+// `AppError` keeps the SDK errors as they came, which are large; it's only built on the way
+// out of a failed call, so the size doesn't matter
+#![allow(clippy::result_large_err)]
+
+use std::sync::Once;
+
+pub mod account;
+pub mod billing;
+#[cfg(feature = "chart")]
+pub mod chart;
+pub mod cloudwatch;
+pub mod config;
+pub mod console;
+#[cfg(feature = "cost-explorer")]
+pub mod cost_explorer;
+pub mod error;
+pub mod exchange;
+pub mod handler;
+#[cfg(feature = "history-dynamodb")]
+pub mod history;
+pub mod limiter;
+pub mod logging;
+pub mod metrics;
+pub mod notify;
+pub mod retry;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod secret;
+pub mod slack;
+pub mod state;
+#[cfg(feature = "templates")]
+pub mod template;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod xray;
+
+pub use error::AppError;
+
+static INIT: Once = Once::new();
+
+/// Process-wide setup: logging, and TLS certificate locations when built against the system
+/// OpenSSL. Only the first call does anything, so it is safe to call from `main` and from
+/// anything else embedding the crate.
+pub fn init() {
+    INIT.call_once(|| {
+        // SAFETY: this runs first thing in `main`, before any client is built or reads the
+        // environment
+        #[cfg(feature = "native-tls")]
+        unsafe {
+            openssl_probe::init_openssl_env_vars();
+        }
+        logging::init();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn init_is_idempotent_and_keeps_an_installed_subscriber() {
+        // a subscriber the embedding program installed first; it also keeps the JSON records
+        // of the other tests off stderr
+        tracing::subscriber::set_global_default(tracing::subscriber::NoSubscriber::default())
+            .unwrap();
+        init();
+        init();
+        logging::init();
+        assert!(INIT.is_completed());
+    }
+}
@@ -0,0 +1,73 @@
+//! Client-side limits on API calls, so a large account stays under the service's TPS quota
+//! instead of leaning on throttling and retries.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tracing::info;
+
+/// At most `max_concurrency` calls in flight, started at most `max_rps` per second (token
+/// bucket with a one second burst). `max_rps == 0.0` disables the rate limit.
+pub struct Limiter {
+    permits: Semaphore,
+    max_rps: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl Limiter {
+    pub fn new(max_concurrency: usize, max_rps: f64) -> Self {
+        Limiter {
+            permits: Semaphore::new(max_concurrency.max(1)),
+            max_rps,
+            bucket: Mutex::new(Bucket {
+                tokens: max_rps.max(1.0),
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waits for a slot for one call of `operation`; the call may run while the permit is held.
+    pub async fn acquire(&self, operation: &str) -> SemaphorePermit<'_> {
+        let started = Instant::now();
+        // the semaphore is never closed
+        let permit = self.permits.acquire().await.expect("semaphore closed");
+        while let Some(wait) = self.take_token() {
+            tokio::time::sleep(wait).await;
+        }
+        let waited = started.elapsed();
+        if waited >= Duration::from_millis(1) {
+            info!(
+                operation,
+                waited_ms = waited.as_millis() as u64,
+                "waited for the API call limiter"
+            );
+        }
+        permit
+    }
+
+    /// Takes a token, or returns how long until one is available.
+    fn take_token(&self) -> Option<Duration> {
+        if self.max_rps <= 0.0 {
+            return None;
+        }
+        let mut bucket = self.bucket.lock().unwrap_or_else(|err| err.into_inner());
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.max_rps).min(self.max_rps.max(1.0));
+        bucket.refilled_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.max_rps,
+            ))
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! Operational metrics of the notifier itself, written as a CloudWatch Embedded Metric Format
+//! log line so they cost no API calls.
+
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use crate::config::ReportType;
+
+const NAMESPACE: &str = "BillingNotification";
+
+/// Counters for one invocation, dimensioned by report mode.
+pub struct InvocationMetrics {
+    report_type: ReportType,
+    started: Instant,
+    pub services_queried: usize,
+    pub services_failed: usize,
+    pub notification_success: bool,
+}
+
+impl InvocationMetrics {
+    pub fn new(report_type: ReportType) -> Self {
+        InvocationMetrics {
+            report_type,
+            started: Instant::now(),
+            services_queried: 0,
+            services_failed: 0,
+            notification_success: false,
+        }
+    }
+
+    /// The EMF document: the `_aws` metadata declaring the metrics, and their values as
+    /// top-level members next to the `ReportMode` dimension.
+    pub fn to_emf(&self, timestamp_ms: u64) -> Value {
+        json!({
+            "_aws": {
+                "Timestamp": timestamp_ms,
+                "CloudWatchMetrics": [{
+                    "Namespace": NAMESPACE,
+                    "Dimensions": [["ReportMode"]],
+                    "Metrics": [
+                        {"Name": "DurationMs", "Unit": "Milliseconds"},
+                        {"Name": "ServicesQueried", "Unit": "Count"},
+                        {"Name": "ServicesFailed", "Unit": "Count"},
+                        {"Name": "NotificationSuccess", "Unit": "Count"},
+                    ],
+                }],
+            },
+            "ReportMode": self.report_type.as_str(),
+            "DurationMs": self.started.elapsed().as_millis() as u64,
+            "ServicesQueried": self.services_queried,
+            "ServicesFailed": self.services_failed,
+            "NotificationSuccess": u8::from(self.notification_success),
+        })
+    }
+
+    /// Writes the EMF line to stdout, where the Lambda log agent picks it up. It bypasses the
+    /// tracing subscriber, whose JSON envelope would hide the `_aws` member.
+    pub fn emit(&self) {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default();
+        println!("{}", self.to_emf(timestamp_ms));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emf_declares_every_metric_it_carries() {
+        let mut metrics = InvocationMetrics::new(ReportType::Weekly);
+        metrics.services_queried = 12;
+        metrics.services_failed = 1;
+        metrics.notification_success = true;
+        let emf = metrics.to_emf(1_718_355_600_000);
+
+        assert_eq!(emf["_aws"]["Timestamp"], 1_718_355_600_000u64);
+        let declared = &emf["_aws"]["CloudWatchMetrics"][0];
+        assert_eq!(declared["Namespace"], NAMESPACE);
+        for dimension in declared["Dimensions"][0].as_array().unwrap() {
+            assert!(emf[dimension.as_str().unwrap()].is_string());
+        }
+        for metric in declared["Metrics"].as_array().unwrap() {
+            assert!(emf[metric["Name"].as_str().unwrap()].is_u64(), "{}", metric);
+        }
+        assert_eq!(emf["ReportMode"], "weekly");
+        assert_eq!(emf["ServicesQueried"], 12);
+        assert_eq!(emf["ServicesFailed"], 1);
+        assert_eq!(emf["NotificationSuccess"], 1);
+    }
+}
@@ -0,0 +1,249 @@
+//! Retrying AWS calls that failed transiently, e.g. `GetMetricStatistics` throttled while
+//! querying dozens of services.
+
+use std::future::Future;
+use std::time::{Duration, SystemTime};
+
+use tracing::warn;
+
+use crate::AppError;
+
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// attempts including the first one
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// no retry is started that would sleep past this, normally the invocation deadline
+    pub deadline: Option<SystemTime>,
+    /// longest a single attempt may take; cut short so it ends before `deadline`
+    pub attempt_timeout: Duration,
+}
+
+/// Time kept before the deadline for reporting the error, so a timed out call still ends in
+/// a structured error rather than Lambda killing the invocation.
+const DEADLINE_MARGIN: Duration = Duration::from_millis(500);
+
+impl RetryPolicy {
+    /// 3 attempts of up to 10s, backing off from 200ms up to 2s.
+    pub fn new(deadline: Option<SystemTime>) -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(2),
+            deadline,
+            attempt_timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Timeout for an attempt starting now.
+    pub(crate) fn timeout(&self) -> Duration {
+        cap_to_deadline(self.attempt_timeout, self.deadline)
+    }
+
+    /// Whether an attempt after waiting `delay` would still have time before the deadline.
+    pub(crate) fn leaves_time_for(&self, delay: Duration) -> bool {
+        self.deadline
+            .is_none_or(|deadline| SystemTime::now() + delay + DEADLINE_MARGIN < deadline)
+    }
+
+    /// Delay after the `attempt`-th (1-based) failure: doubling from `base_delay`, capped at
+    /// `max_delay`, with the upper half randomized so concurrent callers don't retry in lockstep.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        let half = exponential / 2;
+        half + Duration::from_nanos(fastrand::u64(..=half.as_nanos() as u64))
+    }
+}
+
+/// `timeout`, cut short so a call starting now ends before `deadline`.
+pub fn cap_to_deadline(timeout: Duration, deadline: Option<SystemTime>) -> Duration {
+    match deadline {
+        Some(deadline) => deadline
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+            .saturating_sub(DEADLINE_MARGIN)
+            .min(timeout),
+        None => timeout,
+    }
+}
+
+/// Runs `call` until it succeeds, fails with an error that isn't [`AppError::is_retryable`],
+/// runs out of attempts, or retrying would overrun the deadline. Each attempt is bounded by
+/// `policy.attempt_timeout`, failing with [`AppError::Timeout`].
+pub async fn with_retry<T, F, Fut>(
+    policy: &RetryPolicy,
+    operation: &str,
+    mut call: F,
+) -> Result<T, AppError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, AppError>>,
+{
+    let mut attempt = 1;
+    loop {
+        let timeout = policy.timeout();
+        let result = match tokio::time::timeout(timeout, call()).await {
+            Ok(result) => result,
+            Err(_) => Err(AppError::Timeout {
+                operation: operation.to_string(),
+                timeout,
+            }),
+        };
+        match result {
+            Err(err) if attempt < policy.max_attempts && err.is_retryable() => {
+                let backoff = policy.backoff(attempt);
+                if !policy.leaves_time_for(backoff) {
+                    warn!(
+                        operation,
+                        attempt,
+                        error_kind = err.kind(),
+                        "{} failed, no time left to retry: {}",
+                        operation,
+                        err
+                    );
+                    return Err(err);
+                }
+                warn!(
+                    operation,
+                    attempt,
+                    max_attempts = policy.max_attempts,
+                    backoff_ms = backoff.as_millis() as u64,
+                    error_kind = err.kind(),
+                    "{} failed, retrying: {}",
+                    operation,
+                    err
+                );
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use aws_sdk_cloudwatch::error::SdkError;
+
+    use super::*;
+
+    fn fast() -> RetryPolicy {
+        RetryPolicy {
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+            ..RetryPolicy::new(None)
+        }
+    }
+
+    fn transient() -> AppError {
+        AppError::CloudWatch {
+            operation: "ListMetrics",
+            source: SdkError::timeout_error("timed out"),
+        }
+    }
+
+    /// Runs `with_retry` over `results` in order, returning the outcome and the attempts made.
+    async fn run(
+        policy: &RetryPolicy,
+        results: Vec<Result<u32, AppError>>,
+    ) -> (Result<u32, AppError>, usize) {
+        let results = Mutex::new(results.into_iter());
+        let attempts = Mutex::new(0);
+        let result = with_retry(policy, "ListMetrics", || async {
+            *attempts.lock().unwrap() += 1;
+            results.lock().unwrap().next().unwrap()
+        })
+        .await;
+        let attempts = *attempts.lock().unwrap();
+        (result, attempts)
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_max_with_jitter() {
+        let policy = RetryPolicy::new(None);
+        for _ in 0..100 {
+            let first = policy.backoff(1);
+            assert!(first >= Duration::from_millis(100) && first <= Duration::from_millis(200));
+            let third = policy.backoff(3);
+            assert!(third >= Duration::from_millis(400) && third <= Duration::from_millis(800));
+            let capped = policy.backoff(30);
+            assert!(capped >= Duration::from_secs(1) && capped <= Duration::from_secs(2));
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_a_transient_failure() {
+        let (result, attempts) = run(&fast(), vec![Err(transient()), Ok(7)]).await;
+        assert_eq!(result.unwrap(), 7);
+        assert_eq!(attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let results = (0..5).map(|_| Err(transient())).collect();
+        let (result, attempts) = run(&fast(), results).await;
+        assert!(result.unwrap_err().is_retryable());
+        assert_eq!(attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_a_permanent_failure() {
+        let (result, attempts) =
+            run(&fast(), vec![Err(AppError::Config("bad".into())), Ok(7)]).await;
+        assert!(matches!(result, Err(AppError::Config(_))));
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_past_the_deadline() {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_secs(10),
+            max_delay: Duration::from_secs(10),
+            deadline: Some(SystemTime::now() + Duration::from_secs(3)),
+            ..RetryPolicy::new(None)
+        };
+        let (result, attempts) = run(&policy, vec![Err(transient()), Ok(7)]).await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn a_slow_attempt_times_out_and_is_retried() {
+        let policy = RetryPolicy {
+            attempt_timeout: Duration::from_millis(20),
+            ..fast()
+        };
+        let attempts = Mutex::new(0);
+        let result = with_retry(&policy, "GetParameter", || async {
+            *attempts.lock().unwrap() += 1;
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok::<_, AppError>(())
+        })
+        .await;
+        match result {
+            Err(AppError::Timeout { operation, timeout }) => {
+                assert_eq!(operation, "GetParameter");
+                assert_eq!(timeout, Duration::from_millis(20));
+            }
+            result => panic!("expected a timeout, got {:?}", result),
+        }
+        assert_eq!(*attempts.lock().unwrap(), 3);
+    }
+
+    #[test]
+    fn attempts_end_before_the_deadline() {
+        let policy = RetryPolicy::new(Some(SystemTime::now() + Duration::from_secs(3)));
+        let timeout = policy.timeout();
+        assert!(timeout <= Duration::from_secs(3) - DEADLINE_MARGIN);
+        assert!(timeout > Duration::from_secs(2));
+        let past = RetryPolicy::new(Some(SystemTime::now() - Duration::from_secs(1)));
+        assert_eq!(past.timeout(), Duration::ZERO);
+        assert_eq!(RetryPolicy::new(None).timeout(), Duration::from_secs(10));
+    }
+}
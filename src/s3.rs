@@ -0,0 +1,243 @@
+//! A copy of each report in S3, for `S3_REPORT_BUCKET`.
+
+use std::borrow::Cow;
+#[cfg(feature = "chart")]
+use std::time::Duration as StdDuration;
+
+#[cfg(feature = "chart")]
+use aws_sdk_s3::presigning::PresigningConfig;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::Client as S3Client;
+use chrono::NaiveDate;
+
+use crate::billing::Billing;
+use crate::notify::GenericWebhookBody;
+use crate::retry::{self, RetryPolicy};
+use crate::xray::Tracer;
+use crate::AppError;
+
+/// Key of the report of `date`, e.g. `billing/2019/01/31.json` for the prefix `billing`.
+pub fn object_key(prefix: &str, date: NaiveDate, extension: &str) -> String {
+    let separator = if prefix.is_empty() || prefix.ends_with('/') {
+        ""
+    } else {
+        "/"
+    };
+    format!(
+        "{}{}{}.{}",
+        prefix,
+        separator,
+        date.format("%Y/%m/%d"),
+        extension
+    )
+}
+
+/// The breakdown as CSV: a `service,cost,currency` header and a line per service.
+pub fn csv_body(billing: &Billing) -> String {
+    let mut csv = String::from("service,cost,currency\n");
+    for service in &billing.services {
+        csv.push_str(&format!(
+            "{},{},{}\n",
+            csv_field(&service.name),
+            service.cost,
+            csv_field(&billing.currency)
+        ));
+    }
+    csv
+}
+
+/// Quotes `field` when it holds a comma, a quote or a line break.
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+/// Puts the report of `date` in `bucket` as the JSON the generic webhook gets, and with `csv`
+/// its breakdown as CSV too, returning the keys written. A report already there for the same
+/// day is replaced, so a rerun leaves the latest one.
+#[allow(clippy::too_many_arguments)]
+pub async fn put_report(
+    s3: &S3Client,
+    retry: &RetryPolicy,
+    tracer: &Tracer,
+    bucket: &str,
+    prefix: &str,
+    csv: bool,
+    billing: &Billing,
+    date: NaiveDate,
+) -> Result<Vec<String>, AppError> {
+    let json = serde_json::to_vec(&GenericWebhookBody::new(billing))
+        .expect("the report serializes to JSON");
+    let mut objects = vec![(object_key(prefix, date, "json"), json, "application/json")];
+    if csv {
+        objects.push((
+            object_key(prefix, date, "csv"),
+            csv_body(billing).into_bytes(),
+            "text/csv; charset=utf-8",
+        ));
+    }
+
+    let mut keys = Vec::with_capacity(objects.len());
+    for (key, body, content_type) in objects {
+        let call = retry::with_retry(retry, "PutObject", || async {
+            s3.put_object()
+                .bucket(bucket)
+                .key(&key)
+                .content_type(content_type)
+                .body(ByteStream::from(body.clone()))
+                .send()
+                .await
+                .map_err(|err| AppError::S3 {
+                    operation: "PutObject",
+                    key: key.clone(),
+                    source: err.map_service_error(Into::into),
+                })
+        });
+        tracer.trace("S3", "aws", call).await?;
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+/// How long the chart URL works. Slack fetches the image once when the message is posted, so
+/// this only needs to outlast that; a URL signed with the function's temporary credentials
+/// stops working with them anyway.
+#[cfg(feature = "chart")]
+const CHART_URL_TTL: StdDuration = StdDuration::from_secs(60 * 60);
+
+/// Puts the chart of `date` next to its report and returns a presigned URL to it, for a
+/// message to show it without the bucket being public.
+#[cfg(feature = "chart")]
+pub async fn put_chart(
+    s3: &S3Client,
+    retry: &RetryPolicy,
+    tracer: &Tracer,
+    bucket: &str,
+    prefix: &str,
+    png: Vec<u8>,
+    date: NaiveDate,
+) -> Result<String, AppError> {
+    let key = object_key(prefix, date, "png");
+    let call = retry::with_retry(retry, "PutObject", || async {
+        s3.put_object()
+            .bucket(bucket)
+            .key(&key)
+            .content_type("image/png")
+            .body(ByteStream::from(png.clone()))
+            .send()
+            .await
+            .map_err(|err| AppError::S3 {
+                operation: "PutObject",
+                key: key.clone(),
+                source: err.map_service_error(Into::into),
+            })
+    });
+    tracer.trace("S3", "aws", call).await?;
+    let presigning =
+        PresigningConfig::expires_in(CHART_URL_TTL).expect("an hour is a valid expiry");
+    let presigned = s3
+        .get_object()
+        .bucket(bucket)
+        .key(&key)
+        .presigned(presigning)
+        .await
+        .map_err(|err| AppError::S3 {
+            operation: "GetObject",
+            key: key.clone(),
+            source: err.map_service_error(Into::into),
+        })?;
+    Ok(presigned.uri().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, MockResponse, MockServer};
+
+    #[test]
+    fn keys_are_dated_under_the_prefix() {
+        let date = NaiveDate::from_ymd_opt(2019, 1, 31).unwrap();
+        assert_eq!(object_key("", date, "json"), "2019/01/31.json");
+        assert_eq!(object_key("billing", date, "csv"), "billing/2019/01/31.csv");
+        assert_eq!(
+            object_key("billing/", date, "json"),
+            "billing/2019/01/31.json"
+        );
+    }
+
+    #[test]
+    fn csv_quotes_what_needs_it() {
+        let billing = testing::billing(&[("AmazonEC2", "10.5"), ("Support, \"Business\"", "1")]);
+        assert_eq!(
+            csv_body(&billing),
+            "service,cost,currency\nAmazonEC2,10.5,USD\n\"Support, \"\"Business\"\"\",1,USD\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn report_is_put_as_json_and_csv() {
+        let server =
+            MockServer::start(vec![MockResponse::new(200, ""), MockResponse::new(200, "")]);
+        let billing = testing::billing(&[("AmazonEC2", "10.5")]);
+        let keys = put_report(
+            &testing::s3_client(&server.url),
+            &RetryPolicy::new(None),
+            &Tracer::disabled(),
+            "reports",
+            "billing",
+            true,
+            &billing,
+            NaiveDate::from_ymd_opt(2024, 6, 14).unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(keys, ["billing/2024/06/14.json", "billing/2024/06/14.csv"]);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].method, "PUT");
+        assert!(requests[0]
+            .path
+            .starts_with("/reports/billing/2024/06/14.json"));
+        assert_eq!(requests[0].header("content-type"), Some("application/json"));
+        let json: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(json["total"], 10.5);
+        assert_eq!(
+            requests[1].header("content-type"),
+            Some("text/csv; charset=utf-8")
+        );
+        assert_eq!(requests[1].body, csv_body(&billing));
+    }
+
+    #[cfg(feature = "chart")]
+    #[tokio::test]
+    async fn chart_is_put_next_to_the_report_and_presigned() {
+        let server = MockServer::start(vec![MockResponse::new(200, "")]);
+        let url = put_chart(
+            &testing::s3_client(&server.url),
+            &RetryPolicy::new(None),
+            &Tracer::disabled(),
+            "reports",
+            "billing",
+            b"png".to_vec(),
+            NaiveDate::from_ymd_opt(2024, 6, 14).unwrap(),
+        )
+        .await
+        .unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0]
+            .path
+            .starts_with("/reports/billing/2024/06/14.png"));
+        assert_eq!(requests[0].header("content-type"), Some("image/png"));
+        assert!(
+            url.starts_with(&format!("{}/reports/billing/2024/06/14.png?", server.url)),
+            "{}",
+            url
+        );
+        assert!(url.contains("X-Amz-Expires=3600"), "{}", url);
+    }
+}
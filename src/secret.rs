@@ -0,0 +1,50 @@
+//! A wrapper for credentials, such as the webhook URL, that keeps them out of logs and errors.
+
+use std::fmt;
+
+const REDACTED: &str = "***REDACTED***";
+
+/// Formats as `***REDACTED***` with both `{}` and `{:?}`, so it can't leak through a log line
+/// or an error message by accident. The value is only reachable through [`Secret::expose`],
+/// which should be called where it is actually used, e.g. the HTTP call.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Secret(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl<T> fmt::Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn never_formats_its_value() {
+        let secret = Secret::new("https://hooks.slack.com/services/T0/B0/xyz".to_string());
+        assert_eq!(secret.to_string(), REDACTED);
+        assert_eq!(format!("{:?}", secret), REDACTED);
+        assert_eq!(format!("{:?}", Some(&secret)), "Some(***REDACTED***)");
+        assert_eq!(
+            secret.expose(),
+            "https://hooks.slack.com/services/T0/B0/xyz"
+        );
+    }
+}
@@ -0,0 +1,432 @@
+//! A small Slack client for incoming webhooks and the few Web API methods the bot uses.
+//! Messages are plain `serde_json` values, POSTed with the shared HTTP client.
+
+use std::fmt;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::logging;
+use crate::notify::NotifyError;
+use crate::secret::Secret;
+
+/// Escapes the characters Slack reads as markup, so data such as service names shows up
+/// verbatim. Text meant as markup, like mentions, must not go through this.
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Drops control characters, which make Slack reject the whole payload.
+pub fn sanitize(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control() || *c == '\n')
+        .collect()
+}
+
+#[derive(Debug)]
+pub enum PostError {
+    /// Slack received the message and refused it, e.g. 400 `invalid_payload`
+    Rejected {
+        status: u16,
+        body: String,
+        /// the `Retry-After` of a 429
+        retry_after: Option<Duration>,
+    },
+    /// the request didn't get an answer
+    Transport(String),
+}
+
+impl PostError {
+    /// Whether Slack refused the message itself rather than the webhook (e.g. 404
+    /// `no_service` for a revoked one), so a simpler message may go through.
+    pub fn is_invalid_payload(&self) -> bool {
+        match self {
+            PostError::Rejected { status, body, .. } => {
+                *status == 400 && body.starts_with("invalid_")
+            }
+            PostError::Transport(_) => false,
+        }
+    }
+
+    /// How long Slack asked to wait before trying again, when it rate limited the request.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            PostError::Rejected { retry_after, .. } => *retry_after,
+            PostError::Transport(_) => None,
+        }
+    }
+
+    /// Whether posting the same message again may work: the request got no answer, or Slack
+    /// was overloaded or failing.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            PostError::Rejected { status, .. } => *status == 429 || *status >= 500,
+            PostError::Transport(_) => true,
+        }
+    }
+}
+
+/// What the error bodies of an incoming webhook mean, for the ones that don't speak for
+/// themselves.
+fn describe(body: &str) -> Option<&'static str> {
+    match body {
+        "no_service" => Some("the webhook was disabled or removed"),
+        "no_team" => Some("the Slack workspace is gone"),
+        "invalid_token" => Some("the webhook URL is wrong"),
+        "channel_not_found" => Some("the webhook's channel was deleted"),
+        "channel_is_archived" => Some("the webhook's channel is archived"),
+        "action_prohibited" => Some("a workspace admin restricted posting to the channel"),
+        "posting_to_general_channel_denied" => Some("only admins may post to the channel"),
+        "not_in_channel" => Some("the bot isn't a member of the channel"),
+        "time_in_past" | "time_too_far" => Some("post_at is out of the range Slack schedules"),
+        _ => None,
+    }
+}
+
+impl fmt::Display for PostError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PostError::Rejected { status, body, .. } => match describe(body) {
+                Some(description) => {
+                    write!(f, "Slack responded {}: {} ({})", status, body, description)
+                }
+                None => write!(f, "Slack responded {}: {}", status, body),
+            },
+            PostError::Transport(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for PostError {}
+
+impl From<PostError> for NotifyError {
+    fn from(err: PostError) -> Self {
+        NotifyError::new(err.to_string())
+    }
+}
+
+/// `err` without the URL it failed on, and the secret (the webhook URL or the bot token)
+/// redacted should the message quote it anyway.
+fn transport_error(err: reqwest::Error, secret: &Secret<String>) -> PostError {
+    let message = err.without_url().to_string();
+    PostError::Transport(logging::redact(&message, secret.expose()))
+}
+
+/// The `Retry-After` of `res` in seconds, which Slack sends with 429.
+fn retry_after(res: &reqwest::Response) -> Option<Duration> {
+    res.headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// A message Slack accepted.
+pub struct Posted {
+    pub bytes_sent: usize,
+    pub status: u16,
+    /// the response body, `ok`
+    pub body: String,
+}
+
+/// A Web API call Slack accepted.
+pub struct Called {
+    pub bytes_sent: usize,
+    pub status: u16,
+    /// the JSON answer, with `"ok": true`
+    pub answer: Value,
+}
+
+/// Where the Web API methods are, unless the caller points elsewhere, e.g. to a local server in
+/// tests.
+pub const API_URL: &str = "https://slack.com/api";
+
+/// Calls the Web API `method`, e.g. `chat.postMessage`, at `api_url` with `payload` and returns
+/// the answer.
+///
+/// The API answers most failures with 200 and `"ok": false`; those are errors quoting the
+/// `error` code, like `channel_not_found`, along with the HTTP status. Anything not JSON, e.g.
+/// from a proxy on the way, is quoted as it is but for the token.
+pub async fn call(
+    http: &reqwest::Client,
+    api_url: &str,
+    token: &Secret<String>,
+    method: &str,
+    payload: &Value,
+) -> Result<Called, PostError> {
+    let body = serde_json::to_vec(payload).map_err(|err| PostError::Transport(err.to_string()))?;
+    let bytes_sent = body.len();
+    let res = http
+        .post(format!("{}/{}", api_url.trim_end_matches('/'), method))
+        .bearer_auth(token.expose())
+        .header(
+            reqwest::header::CONTENT_TYPE,
+            "application/json; charset=utf-8",
+        )
+        .body(body)
+        .send()
+        .await
+        .map_err(|err| transport_error(err, token))?;
+    let status = res.status().as_u16();
+    let retry_after = retry_after(&res);
+    let text = res
+        .text()
+        .await
+        .map_err(|err| transport_error(err, token))?;
+    match serde_json::from_str::<Value>(&text) {
+        Ok(answer) if answer["ok"] == true => Ok(Called {
+            bytes_sent,
+            status,
+            answer,
+        }),
+        Ok(answer) => Err(PostError::Rejected {
+            status,
+            body: answer["error"]
+                .as_str()
+                .unwrap_or("unknown_error")
+                .to_string(),
+            retry_after,
+        }),
+        Err(_) => Err(PostError::Rejected {
+            status,
+            body: logging::redact(text.trim(), token.expose()),
+            retry_after,
+        }),
+    }
+}
+
+/// POSTs `payload` and returns what Slack answered.
+///
+/// Slack answers a delivered message with 200 and the body `ok`; anything else, e.g. 400
+/// `invalid_payload` or 404 `no_service` for a revoked webhook, is an error quoting the body.
+pub async fn post(
+    http: &reqwest::Client,
+    webhook_url: &Secret<String>,
+    payload: &Value,
+) -> Result<Posted, PostError> {
+    let body = serde_json::to_vec(payload).map_err(|err| PostError::Transport(err.to_string()))?;
+    let bytes_sent = body.len();
+    let res = http
+        .post(webhook_url.expose())
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await
+        .map_err(|err| transport_error(err, webhook_url))?;
+    let status = res.status();
+    let retry_after = retry_after(&res);
+    let text = res
+        .text()
+        .await
+        .map_err(|err| transport_error(err, webhook_url))?;
+    if status.is_success() && text.trim() == "ok" {
+        Ok(Posted {
+            bytes_sent,
+            status: status.as_u16(),
+            body: text.trim().to_string(),
+        })
+    } else {
+        Err(PostError::Rejected {
+            status: status.as_u16(),
+            body: text.trim().to_string(),
+            retry_after,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::{MockResponse, MockServer};
+
+    #[tokio::test]
+    async fn webhook_error_in_a_200_is_rejected() {
+        let server = MockServer::start(vec![MockResponse::new(200, "invalid_payload")]);
+        let webhook_url = Secret::new(format!("{}/services/T0/B0/secret", server.url));
+        let err = post(
+            &reqwest::Client::new(),
+            &webhook_url,
+            &json!({"text": "hi"}),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(
+            matches!(err, PostError::Rejected { status: 200, ref body, .. } if body == "invalid_payload")
+        );
+        assert!(!err.is_retryable());
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/services/T0/B0/secret");
+        assert_eq!(requests[0].body, r#"{"text":"hi"}"#);
+    }
+
+    #[tokio::test]
+    async fn api_error_in_a_200_is_rejected() {
+        let server = MockServer::start(vec![MockResponse::new(
+            200,
+            r#"{"ok": false, "error": "channel_not_found"}"#,
+        )]);
+        let token = Secret::new("xoxb-secret".to_string());
+        let payload = json!({"channel": "C123", "text": "hi"});
+        let err = call(
+            &reqwest::Client::new(),
+            &server.url,
+            &token,
+            "chat.postMessage",
+            &payload,
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(
+            matches!(err, PostError::Rejected { status: 200, ref body, .. } if body == "channel_not_found")
+        );
+        assert!(!err.is_retryable());
+        assert!(err
+            .to_string()
+            .contains("the webhook's channel was deleted"));
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/chat.postMessage");
+        assert_eq!(
+            requests[0].header("authorization"),
+            Some("Bearer xoxb-secret")
+        );
+    }
+
+    #[tokio::test]
+    async fn the_bot_token_is_kept_out_of_errors() {
+        // a proxy echoing the request it failed on
+        let server = MockServer::start(vec![MockResponse::new(
+            502,
+            "bad gateway: Authorization: Bearer xoxb-secret",
+        )]);
+        let token = Secret::new("xoxb-secret".to_string());
+        let http = reqwest::Client::new();
+        let payload = json!({"channel": "C123", "text": "hi"});
+        let err = call(&http, &server.url, &token, "chat.postMessage", &payload)
+            .await
+            .err()
+            .unwrap();
+        assert!(err.is_retryable());
+        assert_eq!(
+            err.to_string(),
+            "Slack responded 502: bad gateway: Authorization: Bearer ***"
+        );
+        // nothing listens once the server ran out of responses
+        let err = call(&http, &server.url, &token, "chat.postMessage", &payload)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, PostError::Transport(_)));
+        assert!(!err.to_string().contains("xoxb-secret"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_carries_retry_after() {
+        let server = MockServer::start(vec![
+            MockResponse::new(429, "rate_limited").with_header("Retry-After", "3")
+        ]);
+        let err = post(
+            &reqwest::Client::new(),
+            &Secret::new(server.url.clone()),
+            &json!({"text": "hi"}),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(err.is_retryable());
+        assert_eq!(err.retry_after(), Some(Duration::from_secs(3)));
+    }
+
+    #[tokio::test]
+    async fn server_error_is_retryable() {
+        let server = MockServer::start(vec![MockResponse::new(503, "")]);
+        let err = post(
+            &reqwest::Client::new(),
+            &Secret::new(server.url.clone()),
+            &json!({"text": "hi"}),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(err.is_retryable());
+        assert_eq!(err.retry_after(), None);
+    }
+
+    #[tokio::test]
+    async fn connection_failure_is_retryable() {
+        let server = MockServer::start(vec![]);
+        // nothing listens once the server ran out of responses
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let webhook_url = Secret::new(format!("{}/services/T0/B0/secret", server.url));
+        let err = post(
+            &reqwest::Client::new(),
+            &webhook_url,
+            &json!({"text": "hi"}),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(matches!(err, PostError::Transport(_)));
+        assert!(err.is_retryable());
+    }
+
+    #[test]
+    fn escape_leaves_no_markup() {
+        assert_eq!(
+            escape("R&D <team> costs > $5"),
+            "R&amp;D &lt;team&gt; costs &gt; $5"
+        );
+        assert_eq!(escape("Amazon S3"), "Amazon S3");
+    }
+
+    #[tokio::test]
+    async fn post_sends_the_payload_as_json() {
+        let server = MockServer::start(vec![MockResponse::new(200, "ok\n")]);
+        let webhook_url = Secret::new(format!("{}/services/T0/B0/secret", server.url));
+        let payload = json!({"text": "今月の請求額は $12.34 です"});
+        let posted = post(&reqwest::Client::new(), &webhook_url, &payload)
+            .await
+            .unwrap();
+        assert_eq!(posted.status, 200);
+        assert_eq!(posted.body, "ok");
+        let requests = server.requests();
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].path, "/services/T0/B0/secret");
+        assert_eq!(requests[0].header("content-type"), Some("application/json"));
+        assert_eq!(posted.bytes_sent, requests[0].body.len());
+        assert_eq!(
+            serde_json::from_str::<Value>(&requests[0].body).unwrap(),
+            payload
+        );
+    }
+
+    #[test]
+    fn sanitize_drops_control_characters_but_newlines() {
+        assert_eq!(
+            sanitize("Amazon\u{7}S3\tcosts\n$5\u{1b}"),
+            "AmazonS3costs\n$5"
+        );
+    }
+
+    #[test]
+    fn only_a_refused_message_is_an_invalid_payload() {
+        let rejected = |status, body: &str| PostError::Rejected {
+            status,
+            body: body.to_string(),
+            retry_after: None,
+        };
+        assert!(rejected(400, "invalid_attachments").is_invalid_payload());
+        assert!(rejected(400, "invalid_payload").is_invalid_payload());
+        assert!(!rejected(404, "no_service").is_invalid_payload());
+        assert!(!rejected(403, "invalid_token").is_invalid_payload());
+        assert!(!PostError::Transport("connection reset".to_string()).is_invalid_payload());
+    }
+}
@@ -0,0 +1,273 @@
+//! Values kept from one invocation to the next, in SSM Parameter Store next to the webhook URL.
+
+use aws_sdk_ssm::types::ParameterType;
+use aws_sdk_ssm::Client as SsmClient;
+use rust_decimal::Decimal;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::retry::{self, RetryPolicy};
+use crate::xray::Tracer;
+use crate::AppError;
+
+/// Total of the last report that was sent, for `NOTIFY_ONLY_ON_CHANGE`.
+const PREVIOUS_TOTAL_PARAMETER: &str = "/billing-notification/previous-total";
+
+/// Services of the last report that was sent, most expensive first, for `SHOW_RANK_CHANGES`.
+const PREVIOUS_RANKS_PARAMETER: &str = "/billing-notification/previous-ranks";
+
+/// Fingerprint of the last report that was sent, for `SKIP_UNCHANGED`.
+const PREVIOUS_FINGERPRINT_PARAMETER: &str = "/billing-notification/previous-fingerprint";
+
+/// The last report that was sent, as [`crate::billing::fingerprint`] and when.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SentReport {
+    pub fingerprint: String,
+    /// Unix time
+    pub sent_at: i64,
+}
+
+/// The total last stored by [`put_previous_total`], or `None` on the first run.
+pub async fn get_previous_total(
+    ssm: &SsmClient,
+    retry: &RetryPolicy,
+    tracer: &Tracer,
+) -> Result<Option<Decimal>, AppError> {
+    let value = get_value(ssm, retry, tracer, PREVIOUS_TOTAL_PARAMETER).await?;
+    // a value that doesn't parse is treated like none: the next report goes out and fixes it
+    Ok(value.and_then(|value| value.parse::<Decimal>().ok()))
+}
+
+pub async fn put_previous_total(
+    ssm: &SsmClient,
+    retry: &RetryPolicy,
+    tracer: &Tracer,
+    total: Decimal,
+) -> Result<(), AppError> {
+    put_value(
+        ssm,
+        retry,
+        tracer,
+        PREVIOUS_TOTAL_PARAMETER,
+        total.to_string(),
+    )
+    .await
+}
+
+/// The service names last stored by [`put_previous_ranks`], or `None` on the first run.
+pub async fn get_previous_ranks(
+    ssm: &SsmClient,
+    retry: &RetryPolicy,
+    tracer: &Tracer,
+) -> Result<Option<Vec<String>>, AppError> {
+    let value = get_value(ssm, retry, tracer, PREVIOUS_RANKS_PARAMETER).await?;
+    // like the total, a value that doesn't parse is treated like none
+    Ok(value.and_then(|value| serde_json::from_str(&value).ok()))
+}
+
+/// Stores `names`, most expensive first, as a JSON array.
+pub async fn put_previous_ranks(
+    ssm: &SsmClient,
+    retry: &RetryPolicy,
+    tracer: &Tracer,
+    names: &[&str],
+) -> Result<(), AppError> {
+    let value = serde_json::to_string(names).expect("names serialize to JSON");
+    put_value(ssm, retry, tracer, PREVIOUS_RANKS_PARAMETER, value).await
+}
+
+/// The report last stored by [`put_previous_report`], or `None` on the first run.
+pub async fn get_previous_report(
+    ssm: &SsmClient,
+    retry: &RetryPolicy,
+    tracer: &Tracer,
+) -> Result<Option<SentReport>, AppError> {
+    let value = get_value(ssm, retry, tracer, PREVIOUS_FINGERPRINT_PARAMETER).await?;
+    // like the total, a value that doesn't parse is treated like none
+    Ok(value.and_then(|value| serde_json::from_str(&value).ok()))
+}
+
+pub async fn put_previous_report(
+    ssm: &SsmClient,
+    retry: &RetryPolicy,
+    tracer: &Tracer,
+    report: &SentReport,
+) -> Result<(), AppError> {
+    let value = serde_json::to_string(report).expect("the report serializes to JSON");
+    put_value(ssm, retry, tracer, PREVIOUS_FINGERPRINT_PARAMETER, value).await
+}
+
+/// The value of `parameter`, or `None` when it doesn't exist yet.
+pub(crate) async fn get_value(
+    ssm: &SsmClient,
+    retry: &RetryPolicy,
+    tracer: &Tracer,
+    parameter: &str,
+) -> Result<Option<String>, AppError> {
+    let call = retry::with_retry(retry, "GetParameter", || async {
+        ssm.get_parameter()
+            .name(parameter)
+            .with_decryption(true)
+            .send()
+            .await
+            .map(Some)
+            .or_else(|err| {
+                if err
+                    .as_service_error()
+                    .is_some_and(|err| err.is_parameter_not_found())
+                {
+                    return Ok(None);
+                }
+                Err(AppError::Ssm {
+                    operation: "GetParameter",
+                    parameter: parameter.to_string(),
+                    source: err.map_service_error(Into::into),
+                })
+            })
+    });
+    let output = match tracer.trace("SSM", "aws", call).await? {
+        Some(output) => output,
+        None => return Ok(None),
+    };
+    Ok(Some(
+        output
+            .parameter()
+            .and_then(|p| p.value())
+            .unwrap_or_default()
+            .to_string(),
+    ))
+}
+
+async fn put_value(
+    ssm: &SsmClient,
+    retry: &RetryPolicy,
+    tracer: &Tracer,
+    parameter: &str,
+    value: String,
+) -> Result<(), AppError> {
+    let call = retry::with_retry(retry, "PutParameter", || async {
+        ssm.put_parameter()
+            .name(parameter)
+            .value(&value)
+            .r#type(ParameterType::String)
+            .overwrite(true)
+            .send()
+            .await
+            .map_err(|err| AppError::Ssm {
+                operation: "PutParameter",
+                parameter: parameter.to_string(),
+                source: err.map_service_error(Into::into),
+            })
+    });
+    tracer.trace("SSM", "aws", call).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::testing::{self, MockResponse, MockServer};
+
+    fn parameter(name: &str, value: &str) -> MockResponse {
+        MockResponse::new(
+            200,
+            &json!({"Parameter": {"Name": name, "Type": "String", "Value": value}}).to_string(),
+        )
+    }
+
+    fn not_found() -> MockResponse {
+        MockResponse::new(400, r#"{"__type":"ParameterNotFound"}"#)
+    }
+
+    #[tokio::test]
+    async fn previous_total_round_trip() {
+        let server = MockServer::start(vec![
+            not_found(),
+            MockResponse::new(200, r#"{"Version":2,"Tier":"Standard"}"#),
+            parameter(PREVIOUS_TOTAL_PARAMETER, "123.45"),
+            parameter(PREVIOUS_TOTAL_PARAMETER, "garbage"),
+        ]);
+        let ssm = testing::ssm_client(&server.url);
+        let (retry, tracer) = (RetryPolicy::new(None), Tracer::disabled());
+
+        assert_eq!(
+            get_previous_total(&ssm, &retry, &tracer).await.unwrap(),
+            None
+        );
+        put_previous_total(&ssm, &retry, &tracer, Decimal::new(12345, 2))
+            .await
+            .unwrap();
+        assert_eq!(
+            get_previous_total(&ssm, &retry, &tracer).await.unwrap(),
+            Some(Decimal::new(12345, 2))
+        );
+        assert_eq!(
+            get_previous_total(&ssm, &retry, &tracer).await.unwrap(),
+            None
+        );
+
+        let put: Value = serde_json::from_str(&server.requests()[1].body).unwrap();
+        assert_eq!(put["Name"], PREVIOUS_TOTAL_PARAMETER);
+        assert_eq!(put["Value"], "123.45");
+        assert_eq!(put["Overwrite"], true);
+    }
+
+    #[tokio::test]
+    async fn previous_ranks_round_trip() {
+        let server = MockServer::start(vec![
+            MockResponse::new(200, r#"{"Version":1,"Tier":"Standard"}"#),
+            parameter(PREVIOUS_RANKS_PARAMETER, r#"["AmazonEC2","AmazonS3"]"#),
+            not_found(),
+        ]);
+        let ssm = testing::ssm_client(&server.url);
+        let (retry, tracer) = (RetryPolicy::new(None), Tracer::disabled());
+
+        put_previous_ranks(&ssm, &retry, &tracer, &["AmazonEC2", "AmazonS3"])
+            .await
+            .unwrap();
+        assert_eq!(
+            get_previous_ranks(&ssm, &retry, &tracer).await.unwrap(),
+            Some(vec!["AmazonEC2".to_string(), "AmazonS3".to_string()])
+        );
+        assert_eq!(
+            get_previous_ranks(&ssm, &retry, &tracer).await.unwrap(),
+            None
+        );
+        let put: Value = serde_json::from_str(&server.requests()[0].body).unwrap();
+        assert_eq!(put["Name"], PREVIOUS_RANKS_PARAMETER);
+        assert_eq!(put["Value"], r#"["AmazonEC2","AmazonS3"]"#);
+    }
+
+    #[tokio::test]
+    async fn previous_report_round_trip() {
+        let stored = r#"{"fingerprint":"1150:9f86d081884c7d65","sent_at":1718323200}"#;
+        let server = MockServer::start(vec![
+            MockResponse::new(200, r#"{"Version":1,"Tier":"Standard"}"#),
+            parameter(PREVIOUS_FINGERPRINT_PARAMETER, stored),
+            parameter(PREVIOUS_FINGERPRINT_PARAMETER, "1150"),
+        ]);
+        let ssm = testing::ssm_client(&server.url);
+        let (retry, tracer) = (RetryPolicy::new(None), Tracer::disabled());
+        let report = SentReport {
+            fingerprint: "1150:9f86d081884c7d65".to_string(),
+            sent_at: 1718323200,
+        };
+
+        put_previous_report(&ssm, &retry, &tracer, &report)
+            .await
+            .unwrap();
+        let put: Value = serde_json::from_str(&server.requests()[0].body).unwrap();
+        assert_eq!(put["Value"], stored);
+        let previous = get_previous_report(&ssm, &retry, &tracer)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(previous.fingerprint, report.fingerprint);
+        assert_eq!(previous.sent_at, report.sent_at);
+        assert!(get_previous_report(&ssm, &retry, &tracer)
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
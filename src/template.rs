@@ -0,0 +1,195 @@
+//! Slack messages worded by the user, for `MESSAGE_TEMPLATE_PARAMETER` and
+//! `ATTACHMENT_TEMPLATE_PARAMETER`.
+//!
+//! The templates are [Tera](https://keats.github.io/tera/docs/) kept in SSM Parameter Store, so
+//! the wording can change without a deploy. `templates/` has the built-in wording as examples.
+
+use std::error::Error;
+
+use aws_sdk_ssm::Client as SsmClient;
+use rust_decimal::prelude::ToPrimitive;
+use serde_derive::Serialize;
+use tera::{Context, Tera};
+
+use crate::billing::{Billing, Severity};
+use crate::config::Config;
+use crate::notify;
+use crate::retry::RetryPolicy;
+use crate::state;
+use crate::xray::Tracer;
+use crate::AppError;
+
+const MESSAGE: &str = "message";
+const ATTACHMENT: &str = "attachment";
+
+/// The parsed templates, at least one of them.
+pub struct Templates {
+    tera: Tera,
+    message: bool,
+    attachment: bool,
+}
+
+/// What a template is rendered with.
+///
+/// ```json
+/// {
+///   "total": "$12.34",
+///   "total_value": 12.34,
+///   "currency": "USD",
+///   "services": [
+///     {"name": "AmazonEC2", "cost": "$10.5", "cost_value": 10.5, "share": "85.1%",
+///      "change": "▲ $1.2", "details": "$10.5 (85.1%, ▲ $1.2)"}
+///   ],
+///   "period_start": "2019-01-01",
+///   "period_end": "2019-01-14",
+///   "as_of": "2019-01-14T09:00:00Z",
+///   "severity": "warning",
+///   "account_id": "123456789012",
+///   "account_alias": "mycompany-prod",
+///   "language": "ja"
+/// }
+/// ```
+///
+/// `share`, `change`, `as_of`, `severity` (`normal`, `warning` or `critical`, with a threshold
+/// set), `account_id` and `account_alias` are null where the report doesn't have them.
+#[derive(Serialize)]
+struct TemplateContext<'a> {
+    total: String,
+    total_value: f64,
+    currency: &'a str,
+    services: Vec<TemplateService<'a>>,
+    period_start: String,
+    period_end: String,
+    as_of: Option<String>,
+    severity: Option<&'static str>,
+    account_id: Option<&'a str>,
+    account_alias: Option<&'a str>,
+    language: &'static str,
+}
+
+#[derive(Serialize)]
+struct TemplateService<'a> {
+    name: &'a str,
+    cost: String,
+    cost_value: f64,
+    share: Option<String>,
+    change: Option<String>,
+    /// the cost as the built-in message shows it, with the share, change and the like
+    details: String,
+}
+
+impl<'a> TemplateContext<'a> {
+    fn new(billing: &'a Billing, severity: Option<Severity>) -> Self {
+        let language = billing.language;
+        let services_total = notify::services_total(billing);
+        TemplateContext {
+            total: notify::format_money(billing.total, &billing.currency, language),
+            total_value: billing.total.to_f64().unwrap_or_default(),
+            currency: &billing.currency,
+            services: billing
+                .services
+                .iter()
+                .map(|service| TemplateService {
+                    name: &service.name,
+                    cost: notify::format_money(service.cost, &billing.currency, language),
+                    cost_value: service.cost.to_f64().unwrap_or_default(),
+                    share: notify::format_share(service.cost, services_total),
+                    change: billing
+                        .changes
+                        .get(&service.name)
+                        .map(|change| notify::format_change(change, &billing.currency, language)),
+                    details: notify::format_service_cost(billing, service, services_total),
+                })
+                .collect(),
+            period_start: billing.period.start.format("%Y-%m-%d").to_string(),
+            period_end: billing.period.end.format("%Y-%m-%d").to_string(),
+            as_of: billing.as_of.map(|as_of| as_of.to_rfc3339()),
+            severity: severity.map(|severity| match severity {
+                Severity::Normal => "normal",
+                Severity::Warning => "warning",
+                Severity::Critical => "critical",
+            }),
+            account_id: billing.account_id.as_deref(),
+            account_alias: billing.account_alias.as_deref(),
+            language: language.pick("ja", "en"),
+        }
+    }
+}
+
+/// Reads and parses the templates `config` names, `None` when it names none. A template that
+/// doesn't parse is a configuration error giving where in it the problem is.
+pub async fn get_templates(
+    ssm: &SsmClient,
+    retry: &RetryPolicy,
+    tracer: &Tracer,
+    config: &Config,
+) -> Result<Option<Templates>, AppError> {
+    let mut templates = Templates {
+        tera: Tera::default(),
+        message: false,
+        attachment: false,
+    };
+    for (name, parameter) in [
+        (MESSAGE, &config.message_template_parameter),
+        (ATTACHMENT, &config.attachment_template_parameter),
+    ] {
+        let parameter = match parameter {
+            Some(parameter) => parameter,
+            None => continue,
+        };
+        let source = state::get_value(ssm, retry, tracer, parameter)
+            .await?
+            .ok_or_else(|| AppError::Config(format!("{} doesn't exist", parameter)))?;
+        templates
+            .tera
+            .add_raw_template(name, &source)
+            .map_err(|err| {
+                AppError::Config(format!(
+                    "invalid template in {}: {}",
+                    parameter,
+                    describe(&err)
+                ))
+            })?;
+        match name {
+            MESSAGE => templates.message = true,
+            _ => templates.attachment = true,
+        }
+    }
+    Ok((templates.message || templates.attachment).then_some(templates))
+}
+
+impl Templates {
+    /// The message text and the attachment text, each `None` without its template.
+    pub fn render(
+        &self,
+        billing: &Billing,
+        severity: Option<Severity>,
+    ) -> Result<(Option<String>, Option<String>), AppError> {
+        let context = Context::from_serialize(TemplateContext::new(billing, severity))
+            .expect("the context serializes to an object");
+        let render = |name: &str| {
+            self.tera.render(name, &context).map_err(|err| {
+                AppError::Config(format!(
+                    "rendering the {} template failed: {}",
+                    name,
+                    describe(&err)
+                ))
+            })
+        };
+        let message = self.message.then(|| render(MESSAGE)).transpose()?;
+        let attachment = self.attachment.then(|| render(ATTACHMENT)).transpose()?;
+        Ok((message, attachment))
+    }
+}
+
+/// `err` with its causes, where Tera puts the line and column of a parse error.
+fn describe(err: &tera::Error) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
@@ -0,0 +1,536 @@
+//! Fakes for exercising the crate without AWS or Slack: an in-memory `BillingMetricsSource`
+//! for `CloudWatchFacade`, a local HTTP server for the Slack, webhook, SSM and S3 clients, and
+//! sample reports.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use aws_sdk_cloudwatch::error::SdkError;
+use chrono::{DateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+
+use crate::account::AccountIdentity;
+use crate::billing::{Billing, Period, ServiceBilling};
+use crate::cloudwatch::{
+    BillingMetricsSource, Datapoint, ListMetricsPage, ListMetricsRequest, MetricStatisticsRequest,
+    BILLING_NAMESPACE,
+};
+use crate::config::{Config, Language, MessageFormat, ReportFormat, TotalRounding};
+#[cfg(feature = "cost-explorer")]
+use crate::cost_explorer::CostExplorerFacade;
+use crate::handler::Clients;
+use crate::notify::SlackOptions;
+#[cfg(feature = "cost-explorer")]
+use crate::retry::RetryPolicy;
+use crate::AppError;
+
+/// The dimensions of a metric, as name and value pairs.
+type Dimensions = Vec<(String, String)>;
+
+/// Serves `ListMetrics` pages in order and `GetMetricStatistics` datapoints keyed by the
+/// requested namespace and dimensions. Unknown dimensions yield no datapoints, like CloudWatch
+/// does.
+///
+/// `with_page` and `with_datapoints` fill `AWS/Billing` unless `in_namespace` picked another.
+#[derive(Default)]
+pub struct FakeMetricsSource {
+    /// metric dimensions per `ListMetrics` page, per namespace
+    pub pages: HashMap<String, Vec<Vec<Dimensions>>>,
+    pub datapoints: HashMap<(String, Dimensions), Vec<Datapoint>>,
+    namespace: Option<String>,
+    /// every request received, for asserting on what the facade asked for
+    pub list_requests: Mutex<Vec<ListMetricsRequest>>,
+    pub statistics_requests: Mutex<Vec<MetricStatisticsRequest>>,
+    /// how long each `GetMetricStatistics` call takes
+    latency: Duration,
+    in_flight: AtomicUsize,
+    /// most `GetMetricStatistics` calls that were in flight at once
+    pub max_in_flight: AtomicUsize,
+    /// `GetMetricStatistics` calls still to time out, per namespace and dimensions
+    failures: Mutex<HashMap<(String, Dimensions), usize>>,
+    /// `ListMetrics` pages that always time out, by namespace and index
+    failing_pages: Vec<(String, usize)>,
+}
+
+impl FakeMetricsSource {
+    /// Makes the following `with_page` and `with_datapoints` fill `namespace`.
+    pub fn in_namespace(mut self, namespace: &str) -> Self {
+        self.namespace = Some(namespace.to_string());
+        self
+    }
+
+    fn current_namespace(&self) -> String {
+        self.namespace
+            .clone()
+            .unwrap_or_else(|| BILLING_NAMESPACE.to_string())
+    }
+
+    pub fn with_page(mut self, metrics: Vec<Vec<(&str, &str)>>) -> Self {
+        let namespace = self.current_namespace();
+        self.pages.entry(namespace).or_default().push(
+            metrics
+                .into_iter()
+                .map(|dimensions| {
+                    dimensions
+                        .into_iter()
+                        .map(|(name, value)| (name.to_string(), value.to_string()))
+                        .collect()
+                })
+                .collect(),
+        );
+        self
+    }
+
+    pub fn with_datapoints(self, dimensions: Vec<(&str, &str)>, maxima: Vec<Option<f64>>) -> Self {
+        self.with_timed_datapoints(
+            dimensions,
+            maxima.into_iter().map(|maximum| (None, maximum)).collect(),
+        )
+    }
+
+    /// Like `with_datapoints`, with the time each datapoint was recorded.
+    pub fn with_timed_datapoints(
+        mut self,
+        dimensions: Vec<(&str, &str)>,
+        datapoints: Vec<(Option<DateTime<Utc>>, Option<f64>)>,
+    ) -> Self {
+        let namespace = self.current_namespace();
+        self.datapoints.insert(
+            (
+                namespace,
+                dimensions
+                    .into_iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+            ),
+            datapoints
+                .into_iter()
+                .map(|(timestamp, maximum)| Datapoint {
+                    timestamp,
+                    maximum,
+                    average: None,
+                    minimum: None,
+                })
+                .collect(),
+        );
+        self
+    }
+
+    /// Makes each `GetMetricStatistics` call take `latency`, so concurrent calls overlap.
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Makes the next `times` `GetMetricStatistics` calls for `dimensions` time out, which is
+    /// retryable.
+    pub fn failing(self, dimensions: Vec<(&str, &str)>, times: usize) -> Self {
+        let key = (
+            self.current_namespace(),
+            dimensions
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        );
+        self.failures.lock().unwrap().insert(key, times);
+        self
+    }
+
+    /// Makes the `ListMetrics` page at `index`, counting from 0, time out however often it is
+    /// asked for, like throttling that outlasts the retries.
+    pub fn failing_page(mut self, index: usize) -> Self {
+        let namespace = self.current_namespace();
+        self.failing_pages.push((namespace, index));
+        self
+    }
+}
+
+impl BillingMetricsSource for FakeMetricsSource {
+    async fn list_metrics(&self, request: ListMetricsRequest) -> Result<ListMetricsPage, AppError> {
+        let index = match request.next_token {
+            Some(ref token) => token.parse::<usize>().map_err(|_| AppError::CloudWatch {
+                operation: "ListMetrics",
+                source: SdkError::construction_failure(format!("invalid next token: {}", token)),
+            })?,
+            None => 0,
+        };
+        let pages = self
+            .pages
+            .get(&request.namespace)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let failing = self
+            .failing_pages
+            .contains(&(request.namespace.clone(), index));
+        self.list_requests.lock().unwrap().push(request);
+        if failing {
+            return Err(AppError::CloudWatch {
+                operation: "ListMetrics",
+                source: SdkError::timeout_error("timed out"),
+            });
+        }
+        let metrics = pages.get(index).cloned().unwrap_or_default();
+        let next_token = if index + 1 < pages.len() {
+            Some((index + 1).to_string())
+        } else {
+            None
+        };
+        Ok(ListMetricsPage {
+            metrics,
+            next_token,
+        })
+    }
+
+    async fn get_metric_statistics(
+        &self,
+        request: MetricStatisticsRequest,
+    ) -> Result<Vec<Datapoint>, AppError> {
+        let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+        if !self.latency.is_zero() {
+            tokio::time::sleep(self.latency).await;
+        }
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        let key = (request.namespace.clone(), request.dimensions.clone());
+        if let Some(failures) = self.failures.lock().unwrap().get_mut(&key) {
+            if *failures > 0 {
+                *failures -= 1;
+                self.statistics_requests.lock().unwrap().push(request);
+                return Err(AppError::CloudWatch {
+                    operation: "GetMetricStatistics",
+                    source: SdkError::timeout_error("timed out"),
+                });
+            }
+        }
+        let datapoints = self
+            .datapoints
+            .get(&(request.namespace.clone(), request.dimensions.clone()))
+            .cloned()
+            .unwrap_or_default();
+        self.statistics_requests.lock().unwrap().push(request);
+        Ok(datapoints)
+    }
+}
+
+/// A report of `services`, each a name and a cost, totalling their sum as of 2024-06-14 09:00
+/// UTC. Everything else is left out, for the test to fill in what it's about.
+pub fn billing(services: &[(&str, &str)]) -> Billing {
+    let services: Vec<ServiceBilling> = services
+        .iter()
+        .map(|(name, cost)| ServiceBilling {
+            name: name.to_string(),
+            cost: cost.parse().unwrap(),
+            usage: None,
+        })
+        .collect();
+    let as_of = Utc.with_ymd_and_hms(2024, 6, 14, 9, 0, 0).unwrap();
+    Billing {
+        generated_at: as_of,
+        as_of: Some(as_of),
+        total: services.iter().map(|service| service.cost).sum(),
+        currency: "USD".to_string(),
+        services,
+        warnings: vec![],
+        degraded: vec![],
+        digest: None,
+        changes: BTreeMap::new(),
+        rank_changes: BTreeMap::new(),
+        increases: vec![],
+        forecasts: BTreeMap::new(),
+        budget: None,
+        request_id: None,
+        period: Period::month_to_date(as_of),
+        account_id: None,
+        account_alias: None,
+        timezone: Tz::UTC,
+        language: Language::Ja,
+        accounts: vec![],
+        daily_spend: vec![],
+        chart_url: None,
+        message_text: None,
+        attachment_text: None,
+        hidden: vec![],
+        conversion: None,
+        statistics: vec![],
+    }
+}
+
+/// The Slack options of a deployment that sets nothing, but without the timestamp footer.
+pub fn slack_options() -> SlackOptions {
+    SlackOptions {
+        format: ReportFormat::Full,
+        service_threshold: None,
+        show_timestamp: false,
+        paginate: true,
+        max_bytes: 40_000,
+        message_format: MessageFormat::Fields,
+        console_links: false,
+        attachment_title: "サービス別内訳".to_string(),
+        alert_threshold: None,
+        warning_threshold: None,
+        alert_mention: None,
+        spend_buckets: None,
+        rounding: TotalRounding::default(),
+        region: None,
+        free_tier_hints: BTreeMap::new(),
+        account_min_share: None,
+        account_names: BTreeMap::new(),
+    }
+}
+
+/// One canned answer of a [`MockServer`].
+pub struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    /// how long the server takes before answering
+    pub delay: Duration,
+}
+
+impl MockResponse {
+    pub fn new(status: u16, body: &str) -> Self {
+        MockResponse {
+            status,
+            headers: vec![],
+            body: body.to_string(),
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+/// A request a [`MockServer`] received.
+#[derive(Clone, Debug)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub path: String,
+    /// with lowercase names
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl RecordedRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// An HTTP server on localhost answering each request with the next of its responses, one
+/// request per connection. Once they run out it stops listening, so any further request fails
+/// to connect.
+pub struct MockServer {
+    /// `http://127.0.0.1:<port>`
+    pub url: String,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+}
+
+impl MockServer {
+    pub fn start(responses: Vec<MockResponse>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let received = Arc::clone(&requests);
+        thread::spawn(move || {
+            for response in responses {
+                let stream = match listener.accept() {
+                    Ok((stream, _)) => stream,
+                    Err(_) => return,
+                };
+                // a client that hung up before sending the whole request isn't recorded
+                let _ = serve(&stream, &response, &received);
+            }
+        });
+        MockServer { url, requests }
+    }
+
+    /// Every request answered so far, in order.
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// Reads a request off `stream`, with a body of its `Content-Length`, and records it in
+/// `received` before answering `response`, so a client that got its answer finds its request
+/// recorded.
+fn serve(
+    mut stream: &TcpStream,
+    response: &MockResponse,
+    received: &Mutex<Vec<RecordedRequest>>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut request_line = line.split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+    let mut headers = vec![];
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+    let length = headers
+        .iter()
+        .find(|(name, _)| name == "content-length")
+        .and_then(|(_, value)| value.parse::<usize>().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    received.lock().unwrap().push(RecordedRequest {
+        method,
+        path,
+        headers,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    });
+    thread::sleep(response.delay);
+
+    let reason = reqwest::StatusCode::from_u16(response.status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or("");
+    let mut answer = format!(
+        "HTTP/1.1 {} {}\r\ncontent-length: {}\r\nconnection: close\r\n",
+        response.status,
+        reason,
+        response.body.len()
+    );
+    for (name, value) in &response.headers {
+        answer.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    answer.push_str("\r\n");
+    answer.push_str(&response.body);
+    stream.write_all(answer.as_bytes())
+}
+
+/// The configuration with `vars` set and nothing else, which must be valid.
+pub fn config(vars: &[(&str, &str)]) -> Config {
+    let vars: HashMap<&str, &str> = vars.iter().copied().collect();
+    Config::from_lookup(|name| vars.get(name).map(|value| value.to_string())).unwrap()
+}
+
+/// A Cost Explorer facade for code that takes one but isn't expected to call it; a call fails
+/// for lack of credentials.
+#[cfg(feature = "cost-explorer")]
+pub fn cost_explorer() -> CostExplorerFacade {
+    use aws_sdk_costexplorer::config::{BehaviorVersion, Region};
+
+    let config = aws_sdk_costexplorer::Config::builder()
+        .behavior_version(BehaviorVersion::latest())
+        .region(Region::new("us-east-1"))
+        .build();
+    CostExplorerFacade::new(
+        aws_sdk_costexplorer::Client::from_conf(config),
+        RetryPolicy::new(None),
+        &[],
+    )
+}
+
+/// A Cost Explorer facade sending to `endpoint_url` like [`ssm_client`].
+#[cfg(feature = "cost-explorer")]
+pub fn cost_explorer_at(endpoint_url: &str) -> CostExplorerFacade {
+    use aws_sdk_costexplorer::config::retry::RetryConfig;
+    use aws_sdk_costexplorer::config::{BehaviorVersion, Credentials, Region};
+
+    let config = aws_sdk_costexplorer::Config::builder()
+        .behavior_version(BehaviorVersion::latest())
+        .region(Region::new("us-east-1"))
+        .credentials_provider(Credentials::new("AKIDTEST", "secret", None, None, "test"))
+        .endpoint_url(endpoint_url)
+        .retry_config(RetryConfig::disabled())
+        .build();
+    CostExplorerFacade::new(
+        aws_sdk_costexplorer::Client::from_conf(config),
+        RetryPolicy::new(None),
+        &[],
+    )
+}
+
+/// An SSM client sending to `endpoint_url`, e.g. a [`MockServer`], with dummy credentials and
+/// the SDK's own retries off so every attempt reaches the server.
+pub fn ssm_client(endpoint_url: &str) -> aws_sdk_ssm::Client {
+    use aws_sdk_ssm::config::retry::RetryConfig;
+    use aws_sdk_ssm::config::{BehaviorVersion, Credentials, Region};
+
+    let config = aws_sdk_ssm::Config::builder()
+        .behavior_version(BehaviorVersion::latest())
+        .region(Region::new("us-east-1"))
+        .credentials_provider(Credentials::new("AKIDTEST", "secret", None, None, "test"))
+        .endpoint_url(endpoint_url)
+        .retry_config(RetryConfig::disabled())
+        .build();
+    aws_sdk_ssm::Client::from_conf(config)
+}
+
+/// An S3 client like [`ssm_client`], with path-style addressing so the bucket is in the path
+/// and without checksums so bodies arrive as they were put.
+#[cfg(feature = "s3")]
+pub fn s3_client(endpoint_url: &str) -> aws_sdk_s3::Client {
+    use aws_sdk_s3::config::retry::RetryConfig;
+    use aws_sdk_s3::config::{BehaviorVersion, Credentials, Region, RequestChecksumCalculation};
+
+    let config = aws_sdk_s3::Config::builder()
+        .behavior_version(BehaviorVersion::latest())
+        .region(Region::new("us-east-1"))
+        .credentials_provider(Credentials::new("AKIDTEST", "secret", None, None, "test"))
+        .endpoint_url(endpoint_url)
+        .force_path_style(true)
+        .request_checksum_calculation(RequestChecksumCalculation::WhenRequired)
+        .retry_config(RetryConfig::disabled())
+        .build();
+    aws_sdk_s3::Client::from_conf(config)
+}
+
+/// Every client of [`Clients`] sending to `endpoint_url` like [`ssm_client`], for driving a
+/// whole report without AWS.
+pub async fn clients(endpoint_url: &str) -> Clients {
+    use aws_config::retry::RetryConfig;
+    use aws_config::{BehaviorVersion, Region};
+    use aws_sdk_ssm::config::Credentials;
+
+    let sdk_config = aws_config::defaults(BehaviorVersion::latest())
+        .region(Region::new("us-east-1"))
+        .credentials_provider(Credentials::new("AKIDTEST", "secret", None, None, "test"))
+        .endpoint_url(endpoint_url)
+        .retry_config(RetryConfig::disabled())
+        .load()
+        .await;
+    Clients {
+        cloudwatch: aws_sdk_cloudwatch::Client::new(&sdk_config),
+        #[cfg(feature = "cost-explorer")]
+        cost_explorer: aws_sdk_costexplorer::Client::new(&sdk_config),
+        ssm: aws_sdk_ssm::Client::new(&sdk_config),
+        #[cfg(feature = "sns")]
+        sns: aws_sdk_sns::Client::new(&sdk_config),
+        #[cfg(feature = "ses")]
+        ses: aws_sdk_sesv2::Client::new(&sdk_config),
+        #[cfg(feature = "history-dynamodb")]
+        dynamodb: aws_sdk_dynamodb::Client::new(&sdk_config),
+        #[cfg(feature = "s3")]
+        s3: aws_sdk_s3::Client::new(&sdk_config),
+        http: reqwest::Client::new(),
+        account: AccountIdentity::default(),
+    }
+}
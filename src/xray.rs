@@ -0,0 +1,166 @@
+//! Thin AWS X-Ray integration: subsegments for the external calls, sent to the X-Ray daemon
+//! over UDP, so a slow invocation shows which call it was waiting on. Everything is a no-op
+//! unless the invocation is traced and sampled, so local runs are unaffected.
+
+use std::env;
+use std::future::Future;
+use std::net::UdpSocket;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Map, Value};
+use tracing::debug;
+
+const DEFAULT_DAEMON_ADDRESS: &str = "127.0.0.1:2000";
+
+/// The `Root=...;Parent=...;Sampled=1` header Lambda passes in `_X_AMZN_TRACE_ID`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TraceHeader {
+    pub root: String,
+    pub parent: String,
+    pub sampled: bool,
+}
+
+impl TraceHeader {
+    pub fn parse(header: &str) -> Option<Self> {
+        let (mut root, mut parent, mut sampled) = (None, None, false);
+        for part in header.split(';') {
+            match part.trim().split_once('=') {
+                Some(("Root", value)) => root = Some(value.to_string()),
+                Some(("Parent", value)) => parent = Some(value.to_string()),
+                Some(("Sampled", value)) => sampled = value == "1",
+                _ => {}
+            }
+        }
+        Some(TraceHeader {
+            root: root?,
+            parent: parent?,
+            sampled,
+        })
+    }
+}
+
+pub struct Tracer {
+    inner: Option<Inner>,
+}
+
+struct Inner {
+    socket: UdpSocket,
+    header: TraceHeader,
+}
+
+impl Tracer {
+    pub fn disabled() -> Self {
+        Tracer { inner: None }
+    }
+
+    /// Traces into the invocation's segment when `header` (or else `_X_AMZN_TRACE_ID`) says
+    /// it is sampled. The daemon address comes from `AWS_XRAY_DAEMON_ADDRESS`.
+    pub fn for_invocation(header: Option<&str>) -> Self {
+        let header = match header
+            .map(str::to_string)
+            .or_else(|| env::var("_X_AMZN_TRACE_ID").ok())
+        {
+            Some(header) => header,
+            None => return Tracer::disabled(),
+        };
+        let header = match TraceHeader::parse(&header) {
+            Some(header) if header.sampled => header,
+            _ => return Tracer::disabled(),
+        };
+        let address = env::var("AWS_XRAY_DAEMON_ADDRESS").unwrap_or_default();
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .and_then(|socket| socket.connect(daemon_udp_address(&address)).map(|_| socket));
+        match socket {
+            Ok(socket) => Tracer {
+                inner: Some(Inner { socket, header }),
+            },
+            Err(err) => {
+                debug!("X-Ray disabled, cannot reach the daemon: {}", err);
+                Tracer::disabled()
+            }
+        }
+    }
+
+    /// Starts a subsegment for a call to `name`; `namespace` is `aws` for AWS API calls and
+    /// `remote` for anything else.
+    pub fn subsegment(&self, name: &str, namespace: &'static str) -> Subsegment<'_> {
+        Subsegment {
+            tracer: self,
+            name: name.to_string(),
+            namespace,
+            start_time: now(),
+            annotations: Map::new(),
+        }
+    }
+
+    /// Runs `call` in a subsegment that is marked as a fault if it fails.
+    pub async fn trace<T, E, F>(&self, name: &str, namespace: &'static str, call: F) -> Result<T, E>
+    where
+        F: Future<Output = Result<T, E>>,
+    {
+        let subsegment = self.subsegment(name, namespace);
+        let result = call.await;
+        subsegment.end(result.is_err());
+        result
+    }
+}
+
+pub struct Subsegment<'a> {
+    tracer: &'a Tracer,
+    name: String,
+    namespace: &'static str,
+    start_time: f64,
+    annotations: Map<String, Value>,
+}
+
+impl<'a> Subsegment<'a> {
+    /// Annotations are indexed, so traces can be filtered on them.
+    pub fn annotate<V: Into<Value>>(&mut self, key: &str, value: V) {
+        if self.tracer.inner.is_some() {
+            self.annotations.insert(key.to_string(), value.into());
+        }
+    }
+
+    /// Sends the subsegment, marked as a fault if the call failed.
+    pub fn end(self, fault: bool) {
+        let inner = match self.tracer.inner {
+            Some(ref inner) => inner,
+            None => return,
+        };
+        let document = json!({
+            "name": self.name,
+            "id": format!("{:016x}", fastrand::u64(..)),
+            "trace_id": inner.header.root,
+            "parent_id": inner.header.parent,
+            "type": "subsegment",
+            "namespace": self.namespace,
+            "start_time": self.start_time,
+            "end_time": now(),
+            "fault": fault,
+            "annotations": self.annotations,
+        });
+        let datagram = format!("{{\"format\":\"json\",\"version\":1}}\n{}", document);
+        // tracing is best effort and must never fail the invocation
+        if let Err(err) = inner.socket.send(datagram.as_bytes()) {
+            debug!("failed to send X-Ray subsegment: {}", err);
+        }
+    }
+}
+
+/// `AWS_XRAY_DAEMON_ADDRESS` is either `host:port` or `tcp:host:port udp:host:port`.
+fn daemon_udp_address(address: &str) -> &str {
+    if address.is_empty() {
+        return DEFAULT_DAEMON_ADDRESS;
+    }
+    address
+        .split_whitespace()
+        .find_map(|part| part.strip_prefix("udp:"))
+        .unwrap_or(address)
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs_f64())
+        .unwrap_or_default()
+}
@@ -0,0 +1,168 @@
+//! A report from CloudWatch metrics to Slack messages, through the library as the handler
+//! drives it, with the metrics served by `FakeMetricsSource`.
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use aws_billing_notification::billing::Billing;
+use aws_billing_notification::cloudwatch::{CloudWatchFacade, TimeWindow};
+use aws_billing_notification::config::{Config, ReportType};
+#[cfg(feature = "cost-explorer")]
+use aws_billing_notification::cost_explorer::CostExplorerFacade;
+use aws_billing_notification::handler;
+use aws_billing_notification::limiter::Limiter;
+use aws_billing_notification::metrics::InvocationMetrics;
+use aws_billing_notification::notify::{build_slack_payloads, SlackLimits, SlackOptions};
+use aws_billing_notification::retry::RetryPolicy;
+use aws_billing_notification::testing::FakeMetricsSource;
+use aws_billing_notification::xray::Tracer;
+use rust_decimal::Decimal;
+
+fn source() -> FakeMetricsSource {
+    FakeMetricsSource::default()
+        .with_page(vec![
+            vec![("Currency", "USD")],
+            vec![("Currency", "USD"), ("ServiceName", "AmazonS3")],
+        ])
+        .with_page(vec![vec![
+            ("Currency", "USD"),
+            ("ServiceName", "AmazonEC2"),
+        ]])
+        .with_datapoints(vec![("Currency", "USD")], vec![Some(10.0), Some(15.5)])
+        .with_datapoints(
+            vec![("Currency", "USD"), ("ServiceName", "AmazonEC2")],
+            vec![Some(12.0)],
+        )
+        .with_datapoints(
+            vec![("Currency", "USD"), ("ServiceName", "AmazonS3")],
+            vec![Some(3.5)],
+        )
+}
+
+/// The configuration of a deployment that sets `vars` only.
+fn config(vars: &[(&str, &str)]) -> Config {
+    Config::from_lookup(|name| {
+        vars.iter()
+            .find(|(var, _)| *var == name)
+            .map(|(_, value)| value.to_string())
+    })
+    .unwrap()
+}
+
+/// A Cost Explorer facade for the default `GROUP_BY=service`, which never calls it; a call
+/// fails for lack of credentials.
+#[cfg(feature = "cost-explorer")]
+fn cost_explorer() -> CostExplorerFacade {
+    use aws_sdk_costexplorer::config::{BehaviorVersion, Region};
+
+    let config = aws_sdk_costexplorer::Config::builder()
+        .behavior_version(BehaviorVersion::latest())
+        .region(Region::new("us-east-1"))
+        .build();
+    CostExplorerFacade::new(
+        aws_sdk_costexplorer::Client::from_conf(config),
+        RetryPolicy::new(None),
+        &[],
+    )
+}
+
+/// The report the handler would collect from `source` for `config`.
+async fn collect(source: &FakeMetricsSource, config: &Config) -> Billing {
+    let preset = ReportType::Daily.preset();
+    let cloudwatch = CloudWatchFacade::new(
+        source,
+        config.namespaces.clone(),
+        TimeWindow::Rolling(preset.lookback),
+        config
+            .include_currency_dimension
+            .then(|| config.currency.clone()),
+        config.billing_statistics.clone(),
+        RetryPolicy::new(None),
+        Limiter::new(config.cloudwatch_max_concurrency, 0.0),
+    );
+    handler::collect_billing(
+        &cloudwatch,
+        #[cfg(feature = "cost-explorer")]
+        &cost_explorer(),
+        config,
+        &preset,
+        &Tracer::disabled(),
+        &mut InvocationMetrics::new(ReportType::Daily),
+    )
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn collects_the_report_and_builds_the_slack_message() {
+    let config = config(&[]);
+    let preset = ReportType::Daily.preset();
+    let billing = collect(&source(), &config).await;
+
+    assert_eq!(billing.total, Decimal::new(155, 1));
+    let services: Vec<_> = billing
+        .services
+        .iter()
+        .map(|service| (service.name.as_str(), service.cost))
+        .collect();
+    assert_eq!(
+        services,
+        [
+            ("AmazonEC2", Decimal::from(12)),
+            ("AmazonS3", Decimal::new(35, 1))
+        ]
+    );
+    assert!(billing.warnings.is_empty(), "{:?}", billing.warnings);
+
+    let options = SlackOptions {
+        show_timestamp: false,
+        ..SlackOptions::from_config(&config, &preset)
+    };
+    let payloads = build_slack_payloads(&billing, &options, SlackLimits::webhook(&options));
+    assert_eq!(payloads.len(), 1);
+    let payload = &payloads[0];
+    let text = payload["text"].as_str().unwrap();
+    assert_eq!(text.lines().next(), Some("今月の請求額は $15.5 です"));
+    let fields = payload["attachments"][0]["fields"].as_array().unwrap();
+    let titles: Vec<_> = fields
+        .iter()
+        .map(|field| field["title"].as_str().unwrap())
+        .collect();
+    assert_eq!(titles, ["AmazonEC2", "AmazonS3"]);
+    assert_eq!(fields[0]["value"], "$12 (77.4%)");
+}
+
+#[tokio::test]
+async fn a_currency_without_metrics_is_warned_about() {
+    let config = config(&[("BILLING_CURRENCY", "JPY")]);
+    let billing = collect(&source(), &config).await;
+    assert_eq!(billing.total, Decimal::ZERO);
+    assert_eq!(billing.warnings.len(), 1, "{:?}", billing.warnings);
+    assert!(billing.warnings[0].contains("USD"));
+}
+
+#[tokio::test]
+async fn services_are_queried_concurrently_up_to_the_limit() {
+    let names: Vec<String> = (0..10).map(|i| format!("Service{}", i)).collect();
+    let mut page = vec![vec![("Currency", "USD")]];
+    page.extend(
+        names
+            .iter()
+            .map(|name| vec![("Currency", "USD"), ("ServiceName", name.as_str())]),
+    );
+    let mut source = FakeMetricsSource::default()
+        .with_page(page)
+        .with_latency(Duration::from_millis(20));
+    for (i, name) in names.iter().enumerate() {
+        source = source.with_datapoints(
+            vec![("Currency", "USD"), ("ServiceName", name.as_str())],
+            vec![Some(i as f64 + 1.0)],
+        );
+    }
+    let billing = collect(&source, &config(&[("CLOUDWATCH_MAX_CONCURRENCY", "3")])).await;
+    assert_eq!(source.max_in_flight.load(Ordering::SeqCst), 3);
+    // by cost, whichever call finished first
+    let services: Vec<&str> = billing.services.iter().map(|s| s.name.as_str()).collect();
+    let expected: Vec<&str> = names.iter().rev().map(String::as_str).collect();
+    assert_eq!(services, expected);
+}
@@ -0,0 +1,102 @@
+//! Snapshots of the Slack messages built for sample reports, in `tests/snapshots`. A change to
+//! the wording or layout shows up as a diff of those; once reviewed, `UPDATE_SNAPSHOTS=1 cargo
+//! test` writes the new ones.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use aws_billing_notification::billing::{self, AccountBilling, ServiceBilling};
+use aws_billing_notification::config::Language;
+use aws_billing_notification::notify::{build_slack_payloads, SlackLimits};
+use aws_billing_notification::testing;
+use rust_decimal::Decimal;
+use serde_json::Value;
+
+fn assert_snapshot(name: &str, payloads: &[Value]) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/snapshots")
+        .join(format!("{}.json", name));
+    let actual = serde_json::to_string_pretty(payloads).unwrap() + "\n";
+    if env::var("UPDATE_SNAPSHOTS").is_ok_and(|v| v == "1") {
+        fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path)
+        .unwrap_or_else(|err| panic!("{}: {}; UPDATE_SNAPSHOTS=1 writes it", path.display(), err));
+    assert!(
+        actual == expected,
+        "{} is out of date; review the difference and rerun with UPDATE_SNAPSHOTS=1:\n{}",
+        path.display(),
+        actual
+    );
+}
+
+fn service(name: &str, cost: &str) -> ServiceBilling {
+    ServiceBilling {
+        name: name.to_string(),
+        cost: cost.parse().unwrap(),
+        usage: None,
+    }
+}
+
+#[test]
+fn japanese() {
+    let mut report = testing::billing(&[
+        ("AmazonEC2", "1234.5"),
+        ("AmazonS3", "56.78"),
+        ("AWSLambda", "0.12"),
+    ]);
+    report.warnings = vec!["⚠ データが古い可能性 (30時間前)".to_string()];
+    let options = testing::slack_options();
+    let payloads = build_slack_payloads(&report, &options, SlackLimits::webhook(&options));
+    assert_snapshot("ja", &payloads);
+}
+
+#[test]
+fn english() {
+    let mut report = testing::billing(&[
+        ("AmazonEC2", "1234.5"),
+        ("AmazonS3", "56.78"),
+        ("AWSLambda", "0.12"),
+    ]);
+    report.language = Language::En;
+    let mut options = testing::slack_options();
+    options.attachment_title = "Breakdown by service".to_string();
+    options.service_threshold = Some(Decimal::from(1000));
+    let payloads = build_slack_payloads(&report, &options, SlackLimits::webhook(&options));
+    assert_snapshot("en", &payloads);
+}
+
+#[test]
+fn paginated() {
+    let names: Vec<String> = (1..=60).map(|n| format!("Service{:02}", n)).collect();
+    let costs: Vec<String> = (1..=60).map(|n| format!("{}.5", 61 - n)).collect();
+    let services: Vec<(&str, &str)> = names
+        .iter()
+        .zip(&costs)
+        .map(|(name, cost)| (name.as_str(), cost.as_str()))
+        .collect();
+    let report = testing::billing(&services);
+    let options = testing::slack_options();
+    let payloads = build_slack_payloads(&report, &options, SlackLimits::webhook(&options));
+    assert_eq!(payloads.len(), 2);
+    assert_snapshot("paginated", &payloads);
+}
+
+#[test]
+fn grouped_by_account() {
+    let (accounts, services): (Vec<AccountBilling>, _) = billing::group_by_account(vec![
+        ("111111111111".to_string(), service("AmazonEC2", "800")),
+        ("111111111111".to_string(), service("AmazonS3", "20")),
+        ("222222222222".to_string(), service("AmazonEC2", "100")),
+        ("222222222222".to_string(), service("AWSLambda", "3.25")),
+    ]);
+    let mut report = testing::billing(&[]);
+    report.total = services.iter().map(|service| service.cost).sum();
+    report.services = services;
+    report.accounts = accounts;
+    let options = testing::slack_options();
+    let payloads = build_slack_payloads(&report, &options, SlackLimits::webhook(&options));
+    assert_snapshot("grouped_by_account", &payloads);
+}
@@ -0,0 +1,32 @@
+//! `WEBHOOK_URL` standing in for the SSM parameter.
+
+use aws_billing_notification::notify;
+use aws_billing_notification::retry::RetryPolicy;
+use aws_billing_notification::testing::{self, MockServer};
+use aws_billing_notification::xray::Tracer;
+
+#[tokio::test]
+async fn webhook_url_variable_skips_ssm() {
+    let server = MockServer::start(vec![]);
+    let ssm = testing::ssm_client(&server.url);
+    let retry = RetryPolicy::new(None);
+    let config = testing::config(&[(
+        "WEBHOOK_URL",
+        "https://hooks.slack.com/services/T0/B0/from-env",
+    )]);
+
+    let url = notify::get_webhook_url(
+        &ssm,
+        &retry,
+        &Tracer::disabled(),
+        config.webhook_url.as_ref(),
+        config.slack_webhook_host(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        url.expose(),
+        "https://hooks.slack.com/services/T0/B0/from-env"
+    );
+    assert!(server.requests().is_empty());
+}